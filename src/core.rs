@@ -1,13 +1,26 @@
-use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use regex::{Captures, Regex};
 
+use crate::edn;
+use crate::format;
 use crate::env::Env;
-use crate::reader::read_form;
+use crate::eval::{eval, handle_function};
+use crate::json::{self, JsonOptions};
+use crate::limits;
+use crate::ports::{self, Port};
+use crate::reader::{self, read_form};
+use crate::sandbox;
+use crate::sequences;
 use crate::types::*;
 
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fs::read_to_string;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 macro_rules! assert_min_args {
     ($args:expr, $min:literal) => {{
@@ -20,6 +33,8 @@ macro_rules! assert_min_args {
     }};
 }
 
+pub(crate) use assert_min_args;
+
 pub fn list(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     Ok(MValue::list(args))
 }
@@ -58,14 +73,14 @@ pub fn vector_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 
 pub fn sequential_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 1);
-    let x = args[0].is_list() || args[0].is_vector();
+    let x = args[0].is_list() || args[0].is_vector() || args[0].is_lazy_seq();
 
     Ok(MValue::bool(x))
 }
 
 pub fn map_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 1);
-    let x = args[0].is_hashmap();
+    let x = args[0].is_hashmap() || args[0].is_sorted_map();
 
     Ok(MValue::bool(x))
 }
@@ -107,6 +122,17 @@ pub fn keyword_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 
 pub fn empty_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 1);
+
+    if args[0].is_lazy_seq() {
+        return Ok(MValue::bool(args[0].realize()?.is_none()));
+    }
+
+    match *args[0].0 {
+        MalVal::HashMap(ref hm, _) => return Ok(MValue::bool(hm.is_empty())),
+        MalVal::SortedMap(ref sorted, _) => return Ok(MValue::bool(sorted.is_empty())),
+        _ => {},
+    }
+
     let list = args[0].cast_to_list()?;
 
     Ok(MValue::bool(list.is_empty()))
@@ -115,20 +141,53 @@ pub fn empty_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 pub fn count(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 1);
 
-    let x = args[0].cast_to_list().map(|v| v.len()).unwrap_or(0);
+    let x = match *args[0].0 {
+        MalVal::HashMap(ref hm, _) => hm.len(),
+        MalVal::SortedMap(ref sorted, _) => sorted.len(),
+        // Realizing may fail, or be interrupted, and that is no count of 0.
+        MalVal::LazySeq(_) => args[0].cast_to_list()?.len(),
+        _ => args[0].cast_to_list().map(|v| v.len()).unwrap_or(0),
+    };
     let x = i64::try_from(x)?;
 
     Ok(MValue::integer(x))
 }
 
+fn has_float(args: &[MValue]) -> bool {
+    args.iter().any(|v| matches!(*v.0, MalVal::Float(_)))
+}
+
 pub fn add(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    let x = args.iter().flat_map(MValue::cast_to_int).sum();
+    if has_float(&args) {
+        let x = args.iter().map(MValue::cast_to_float).sum::<Result<f64>>()?;
+        return Ok(MValue::float(x));
+    }
+
+    let x = args.iter().map(MValue::cast_to_int).sum::<Result<i64>>()?;
 
     Ok(MValue::integer(x))
 }
 
 pub fn sub(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    assert_min_args!(&args, 2);
+    assert_min_args!(&args, 1);
+
+    if args.len() == 1 {
+        return match *args[0].0 {
+            MalVal::Float(x) => Ok(MValue::float(-x)),
+            _ => args[0].cast_to_int()?.checked_neg().map(MValue::integer)
+                .ok_or_else(|| Error::EvalError("Integer overflow".to_string())),
+        };
+    }
+
+    if has_float(&args) {
+        let mut x = args[0].cast_to_float()?;
+
+        for y in args[1..].iter() {
+            x -= y.cast_to_float()?;
+        }
+
+        return Ok(MValue::float(x));
+    }
 
     let mut x = args[0].cast_to_int()?;
 
@@ -140,7 +199,12 @@ pub fn sub(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 }
 
 pub fn mul(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    let x = args.iter().flat_map(MValue::cast_to_int).product();
+    if has_float(&args) {
+        let x = args.iter().map(MValue::cast_to_float).product::<Result<f64>>()?;
+        return Ok(MValue::float(x));
+    }
+
+    let x = args.iter().map(MValue::cast_to_int).product::<Result<i64>>()?;
 
     Ok(MValue::integer(x))
 }
@@ -148,10 +212,21 @@ pub fn mul(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 pub fn div(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 2);
 
+    if has_float(&args) {
+        let mut x = args[0].cast_to_float()?;
+
+        for y in args[1..].iter() {
+            x /= y.cast_to_float()?;
+        }
+
+        return Ok(MValue::float(x));
+    }
+
     let mut x = args[0].cast_to_int()?;
 
     for y in args[1..].iter() {
-        x /= y.cast_to_int()?;
+        x = x.checked_div(y.cast_to_int()?)
+            .ok_or_else(|| Error::EvalError("Division by zero".to_string()))?;
     }
 
     Ok(MValue::integer(x))
@@ -159,57 +234,68 @@ pub fn div(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 
 pub fn eq(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 2);
-    Ok(MValue::bool(args[0] == args[1]))
+    Ok(MValue::bool(args.windows(2).all(|pair| pair[0] == pair[1])))
 }
 
-pub fn lt(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    assert_min_args!(&args, 2);
+fn compare_numbers(args: &[MValue]) -> Result<Option<Ordering>> {
+    if has_float(&args[..2]) {
+        let x = args[0].cast_to_float()?;
+        let y = args[1].cast_to_float()?;
+
+        return Ok(x.partial_cmp(&y));
+    }
 
     let x = args[0].cast_to_int()?;
     let y = args[1].cast_to_int()?;
 
-    Ok(MValue::bool(x < y))
+    Ok(Some(x.cmp(&y)))
 }
 
-pub fn gt(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    assert_min_args!(&args, 2);
+/// Whether each number is ordered against the next as `accepts` allows.
+fn in_order(args: &[MValue], accepts: fn(Ordering) -> bool) -> Result<MValue> {
+    assert_min_args!(args, 2);
 
-    let x = args[0].cast_to_int()?;
-    let y = args[1].cast_to_int()?;
+    for pair in args.windows(2) {
+        if !compare_numbers(pair)?.is_some_and(accepts) {
+            return Ok(MValue::bool(false));
+        }
+    }
 
-    Ok(MValue::bool(x > y))
+    Ok(MValue::bool(true))
 }
 
-pub fn lte(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    assert_min_args!(&args, 2);
+pub fn lt(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    in_order(&args, Ordering::is_lt)
+}
 
-    let x = args[0].cast_to_int()?;
-    let y = args[1].cast_to_int()?;
+pub fn gt(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    in_order(&args, Ordering::is_gt)
+}
 
-    Ok(MValue::bool(x <= y))
+pub fn lte(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    in_order(&args, Ordering::is_le)
 }
 
 pub fn gte(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    assert_min_args!(&args, 2);
-
-    let x = args[0].cast_to_int()?;
-    let y = args[1].cast_to_int()?;
+    in_order(&args, Ordering::is_ge)
+}
 
-    Ok(MValue::bool(x >= y))
+fn print_limits(env: &Option<Env>) -> PrintLimits {
+    env.as_ref().map(PrintLimits::from_env).unwrap_or_default()
 }
 
-pub fn print_str(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    let x = args.iter().map(|x| x.pr_str(true)).collect::<Vec<String>>();
+pub fn print_str(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let x = args.iter().map(|x| x.pr_str_limited(true, print_limits(&env))).collect::<Vec<String>>();
 
     let r = x.join(" ");
 
     Ok(MValue::string(r))
 }
 
-pub fn string(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+pub fn string(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
     let x = args
         .iter()
-        .map(|x| x.pr_str(false))
+        .map(|x| x.pr_str_limited(false, print_limits(&env)))
         .collect::<Vec<String>>();
 
     let r = x.join("");
@@ -217,24 +303,41 @@ pub fn string(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     Ok(MValue::string(r))
 }
 
-pub fn prn(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    let x = args.iter().map(|x| x.pr_str(true)).collect::<Vec<String>>();
+pub fn prn(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let x = args.iter().map(|x| x.pr_str_limited(true, print_limits(&env))).collect::<Vec<String>>();
 
     let r = x.join(" ");
 
-    println!("{}", r);
+    ports::print(&env, &format!("{}\n", r))?;
     Ok(MValue::nil())
 }
 
-pub fn println(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+pub fn println(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
     let x = args
         .iter()
-        .map(|x| x.pr_str(false))
+        .map(|x| x.pr_str_limited(false, print_limits(&env)))
         .collect::<Vec<String>>();
 
     let r = x.join(" ");
 
-    println!("{}", r);
+    ports::print(&env, &format!("{}\n", r))?;
+    Ok(MValue::nil())
+}
+
+fn format_args(args: &[MValue]) -> Result<String> {
+    assert_min_args!(args, 1);
+
+    format::format(&args[0].cast_to_string()?, &args[1..])
+}
+
+pub fn format(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    Ok(MValue::string(format_args(&args)?))
+}
+
+pub fn printf(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    ports::print(&env, &format_args(&args)?)?;
+    ports::current(&env, "*out*")?.cast_to_port()?.flush()?;
+
     Ok(MValue::nil())
 }
 
@@ -247,14 +350,23 @@ pub fn read_str(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     parser.parse(string.as_bytes()).map_err(From::from)
 }
 
-pub fn slurp(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+/// `(read-all s)` is a list of every form in `s`.
+pub fn read_forms(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 1);
 
-    let filename = args[0].cast_to_string()?;
+    let string = args[0].cast_to_string()?;
+    let forms = reader::read_all().parse(string.as_bytes())?;
+    Ok(MValue::list(forms))
+}
+
+pub fn slurp(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
 
-    read_to_string(filename)
+    let filename = sandbox::resolve_path(&env, &args[0].cast_to_string()?)?;
+
+    read_to_string(&filename)
         .map(MValue::string)
-        .map_err(From::from)
+        .map_err(|e| Error::io(filename, e))
 }
 
 pub fn atom(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
@@ -280,6 +392,10 @@ pub fn reset(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 pub fn cons(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 2);
 
+    if args[1].is_lazy_seq() {
+        return Ok(MValue::lazy_cons(args[0].clone(), args[1].clone()));
+    }
+
     let mut v = args[1].cast_to_list()?;
     v.insert(0, args[0].clone());
 
@@ -287,6 +403,10 @@ pub fn cons(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 }
 
 pub fn concat(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    if args.iter().any(MValue::is_lazy_seq) {
+        return sequences::lazy_concat(args);
+    }
+
     let mut v = Vec::new();
 
     for arg in args {
@@ -302,6 +422,21 @@ pub fn nth(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     let i = args[1].cast_to_int()?;
     let k = usize::try_from(i)?;
 
+    if args[0].is_lazy_seq() {
+        let mut step = args[0].realize()?;
+
+        for _ in 0..k {
+            step = match step {
+                Some((_, rest)) => rest.realize()?,
+                None => break,
+            };
+        }
+
+        return step
+            .map(|(value, _)| value)
+            .ok_or_else(|| Error::EvalError("Out of bounds".to_string()));
+    }
+
     args[0]
         .cast_to_list()?
         .get(k)
@@ -309,14 +444,21 @@ pub fn nth(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
         .ok_or_else(|| Error::EvalError("Out of bounds".to_string()))
 }
 
+fn is_collection(value: &MValue) -> bool {
+    value.is_list() || value.is_vector() || value.is_hashmap() || value.is_set()
+        || value.is_sorted_map() || value.is_sorted_set()
+}
+
 pub fn first(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 1);
 
     let value = &args[0];
-    if !value.is_list() && !value.is_vector() {
+    if value.is_lazy_seq() {
+        Ok(value.realize()?.map_or_else(MValue::nil, |(first, _)| first))
+    } else if !is_collection(value) {
         Ok(MValue::nil())
     } else {
-        let list = value.cast_to_list()?;
+        let list = sequences::to_seq(value)?;
         if list.is_empty() {
             return Ok(MValue::nil());
         }
@@ -331,10 +473,12 @@ pub fn rest(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     let value = &args[0];
     let l = vec![];
 
-    if !value.is_list() && !value.is_vector() {
+    if value.is_lazy_seq() {
+        Ok(value.realize()?.map_or_else(|| MValue::list(l), |(_, rest)| rest))
+    } else if !is_collection(value) {
         Ok(MValue::list(l))
     } else {
-        let list = value.cast_to_list()?;
+        let list = sequences::to_seq(value)?;
 
         if list.is_empty() {
             return Ok(MValue::list(l));
@@ -354,6 +498,19 @@ pub fn assoc(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 2);
 
     let hm = &args[0];
+
+    if let MalVal::SortedMap(ref sorted, ref meta) = *hm.0 {
+        let mut sorted = sorted.clone();
+
+        for pair in args[1..].chunks(2) {
+            let value = pair.get(1).cloned().ok_or_else(|| Error::EvalError(
+                "assoc takes key and value pairs".to_string()))?;
+            sorted.insert(pair[0].clone(), value)?;
+        }
+
+        return MValue::sorted_map(sorted).with_meta(meta.clone());
+    }
+
     let mut args = args[1..].to_vec();
     hm.hassoc(&mut args)
 }
@@ -361,10 +518,20 @@ pub fn assoc(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 pub fn dissoc(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 2);
 
+    if let MalVal::SortedMap(ref sorted, ref meta) = *args[0].0 {
+        let mut sorted = sorted.clone();
+
+        for key in args[1..].iter() {
+            sorted.remove(key)?;
+        }
+
+        return MValue::sorted_map(sorted).with_meta(meta.clone());
+    }
+
     let mut hm = args[0].cast_to_hashmap()?;
 
     for key in args[1..].to_vec() {
-        hm.remove(&(key.cast_to_string()?, key.enum_key()));
+        hm.shift_remove(&key.hash_key()?);
     }
 
     Ok(MValue::from_hashmap(hm))
@@ -373,16 +540,20 @@ pub fn dissoc(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 pub fn get(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 2);
 
-    if !args[0].is_hashmap() {
-        return Ok(MValue::nil());
+    if let MalVal::SortedMap(ref sorted, _) | MalVal::SortedSet(ref sorted, _) = *args[0].0 {
+        return Ok(sorted.get(&args[1])?.unwrap_or_else(MValue::nil));
     }
 
-    let map = args[0].cast_to_hashmap()?;
-    let key = args[1].cast_to_string()?;
-    let key_type = args[1].enum_key();
+    let map = if args[0].is_hashmap() {
+        args[0].cast_to_hashmap()?
+    } else if args[0].is_set() {
+        args[0].cast_to_set()?
+    } else {
+        return Ok(MValue::nil());
+    };
 
     let r = map
-        .get(&(key, key_type))
+        .get(&args[1].hash_key()?)
         .cloned()
         .unwrap_or_else(MValue::nil);
     Ok(r)
@@ -391,45 +562,85 @@ pub fn get(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 pub fn contains_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 2);
 
-    let map = args[0].cast_to_hashmap()?;
-    let key = args[1].cast_to_string()?;
-    let key_type = args[1].enum_key();
+    if let MalVal::SortedMap(ref sorted, _) | MalVal::SortedSet(ref sorted, _) = *args[0].0 {
+        return Ok(MValue::bool(sorted.get(&args[1])?.is_some()));
+    }
 
-    let r = map.get(&(key, key_type)).is_some();
+    let map = if args[0].is_set() {
+        args[0].cast_to_set()?
+    } else {
+        args[0].cast_to_hashmap()?
+    };
+
+    let r = map.contains_key(&args[1].hash_key()?);
     Ok(MValue::bool(r))
 }
 
-pub fn keys(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+pub fn hash_set(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    MValue::set(args)
+}
+
+pub fn set(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 1);
 
-    let map = args[0].cast_to_hashmap()?;
-    let keys = map.keys().map(MValue::reconstruct).collect::<Result<_>>()?;
+    if args[0].is_nil() {
+        return MValue::set(vec![]);
+    }
 
-    Ok(MValue::list(keys))
+    MValue::set(args[0].cast_to_list()?)
 }
 
-pub fn values(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+pub fn set_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 1);
+    Ok(MValue::bool(args[0].is_set() || args[0].is_sorted_set()))
+}
+
+pub fn disj(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    if let MalVal::SortedSet(ref sorted, ref meta) = *args[0].0 {
+        let mut sorted = sorted.clone();
+
+        for value in args[1..].iter() {
+            sorted.remove(value)?;
+        }
+
+        return MValue::sorted_set(sorted).with_meta(meta.clone());
+    }
+
+    let mut set = args[0].cast_to_set()?;
+
+    for value in args[1..].iter() {
+        set.shift_remove(&value.hash_key()?);
+    }
+
+    Ok(MValue::from_set(set))
+}
+
+pub fn keys(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if let MalVal::SortedMap(ref sorted, _) = *args[0].0 {
+        return Ok(MValue::list(sorted.keys()));
+    }
 
     let map = args[0].cast_to_hashmap()?;
-    let keys = map.values().cloned().collect::<Vec<_>>();
+    let keys = map.keys().map(MValue::reconstruct).collect::<Result<_>>()?;
 
     Ok(MValue::list(keys))
 }
 
-pub fn readline(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+pub fn values(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
     assert_min_args!(&args, 1);
 
-    let prompt = args[0].cast_to_string()?;
-    let mut ed = Editor::<()>::new();
+    if let MalVal::SortedMap(ref sorted, _) = *args[0].0 {
+        return Ok(MValue::list(sorted.values()));
+    }
 
-    let usr_str = ed.readline(&prompt);
+    let map = args[0].cast_to_hashmap()?;
+    let keys = map.values().cloned().collect::<Vec<_>>();
 
-    match usr_str {
-        Ok(usr_str) => Ok(MValue::string(usr_str)),
-        Err(ReadlineError::Eof) => Ok(MValue::nil()),
-        Err(err) => Err(Error::IoError(format!("Failed readling line: {:?}", err))),
-    }
+    Ok(MValue::list(keys))
 }
 
 pub fn time_ms(_args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
@@ -478,7 +689,14 @@ pub fn seq(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
         return Ok(args[0].clone());
     }
 
-    let l = args[0].cast_to_list()?;
+    if args[0].is_lazy_seq() {
+        return Ok(match args[0].realize()? {
+            Some(_) => args[0].clone(),
+            None => MValue::nil(),
+        });
+    }
+
+    let l = sequences::to_seq(&args[0])?;
 
     if l.is_empty() {
         return Ok(MValue::nil());
@@ -488,9 +706,20 @@ pub fn seq(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
 }
 
 pub fn conj(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    assert_min_args!(&args, 2);
+    match args.len() {
+        0 => return Ok(MValue::vector(vec![])),
+        1 => return Ok(args[0].clone()),
+        _ => {},
+    }
+
     let v = &args[0];
 
+    if v.is_nil() || v.is_lazy_seq() {
+        let mut head = args[1..].to_vec();
+        head.reverse();
+        return concat(vec![MValue::list(head), v.clone()], None);
+    }
+
     if v.is_list() {
         let mut v = v.cast_to_list()?;
         let mut head = args[1..].to_vec();
@@ -506,5 +735,572 @@ pub fn conj(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
         return Ok(MValue::vector(v));
     }
 
+    if v.is_set() {
+        let mut set = v.cast_to_set()?;
+
+        for value in args[1..].iter() {
+            set.insert(value.hash_key()?, value.clone());
+        }
+
+        return Ok(MValue::from_set(set));
+    }
+
+    if let MalVal::SortedSet(ref sorted, ref meta) = *v.0 {
+        let mut sorted = sorted.clone();
+
+        for value in args[1..].iter() {
+            sorted.insert(value.clone(), value.clone())?;
+        }
+
+        return MValue::sorted_set(sorted).with_meta(meta.clone());
+    }
+
+    if v.is_hashmap() || v.is_sorted_map() {
+        let mut entries = vec![];
+
+        for entry in args[1..].iter() {
+            entries.append(&mut entry.cast_to_list()?);
+        }
+
+        if entries.len() % 2 != 0 {
+            return Err(Error::EvalError("conj onto a map takes [key value] pairs".to_string()));
+        }
+
+        entries.insert(0, v.clone());
+        return assoc(entries, None);
+    }
+
+    Ok(MValue::nil())
+}
+
+fn option_enabled(options: Option<&MValue>, name: &str) -> Result<bool> {
+    match options {
+        Some(options) if !options.is_nil() => {
+            let options = options.cast_to_hashmap()?;
+            let value = options.get(&(name.to_string(), "Keyword".to_string()));
+
+            Ok(value.map(|v| !v.is_nil() && *v.0 != MalVal::Bool(false)).unwrap_or(false))
+        },
+        _ => Ok(false),
+    }
+}
+
+fn json_options(options: Option<&MValue>) -> Result<JsonOptions> {
+    Ok(JsonOptions {
+        keywordize_keys: option_enabled(options, "keywordize-keys")?,
+        strict: option_enabled(options, "strict")?,
+        pretty: option_enabled(options, "pretty")?,
+    })
+}
+
+pub fn json_parse(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let input = args[0].cast_to_string()?;
+    let options = json_options(args.get(1))?;
+
+    json::parse(&input, &options)
+}
+
+pub fn json_stringify(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let options = json_options(args.get(1))?;
+
+    json::stringify(&args[0], &options).map(MValue::string)
+}
+
+pub fn meval(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    eval(args[0].clone(), &env.unwrap())
+}
+
+pub fn swap(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let mut args = args.clone();
+    let atom = args.remove(0);
+    let f = args.remove(0);
+
+    args.insert(0, atom.atom_deref()?);
+
+    let v = handle_function(&f, args)?;
+    Ok(atom.atom_reset(v)?)
+}
+
+pub fn apply(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let mut args = args.clone();
+    let f = args.remove(0);
+
+    let mut last_arguments = args.pop()
+        .ok_or_else(|| Error::EvalError("Not enough arguments".to_string()))?
+        .cast_to_list()?;
+
+    args.append(&mut last_arguments);
+
+    handle_function(&f, args)
+}
+
+fn composed(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let fns = env.as_ref()
+        .and_then(|env| env.get("fns"))
+        .ok_or_else(|| Error::NoSymbolFound("fns".to_string()))?
+        .cast_to_list()?;
+
+    let mut fns = fns.iter().rev();
+    let mut value = match fns.next() {
+        Some(f) => handle_function(f, args)?,
+        None => return identity(args, None),
+    };
+
+    for f in fns {
+        value = handle_function(f, vec![value])?;
+    }
+
+    Ok(value)
+}
+
+/// `(comp f g h)` is a function that calls `h`, then `g`, then `f`.
+pub fn comp(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let env = Env::new(None);
+    env.set("fns", MValue::list(args));
+
+    Ok(MValue::function(composed, Some(env)))
+}
+
+pub fn identity(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(args[0].clone())
+}
+
+fn data_readers(env: &Option<Env>) -> MValue {
+    env.as_ref()
+        .and_then(|env| env.get("*data-readers*"))
+        .unwrap_or_else(edn::default_readers)
+}
+
+pub fn read_edn(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let input = args[0].cast_to_string()?;
+    edn::read(&input, &data_readers(&env))
+}
+
+pub fn write_edn(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    edn::write(&args[0]).map(MValue::string)
+}
+
+pub fn register_tag(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    if !args[0].is_symbol() {
+        return Err(Error::EvalError(format!("{} is not a symbol", args[0])));
+    }
+
+    let env = env.ok_or_else(|| Error::EvalError("register-tag needs an environment".to_string()))?;
+    let readers = data_readers(&Some(env.clone())).hassoc(&mut args[..2].to_vec())?;
+
+    env.set("*data-readers*", readers);
+    Ok(args[0].clone())
+}
+
+/// Converts a match to its Lisp value: the matched string when the pattern
+/// has no groups, a vector of the match followed by each group otherwise, or
+/// a map from group names (and 0 for the whole match) when groups are named.
+pub(crate) fn match_result(regex: &Regex, caps: &Captures) -> MValue {
+    let group = |m: Option<regex::Match>| {
+        m.map(|m| MValue::string(m.as_str())).unwrap_or_else(MValue::nil)
+    };
+
+    if regex.capture_names().flatten().next().is_some() {
+        let mut values = vec![MValue::integer(0), group(caps.get(0))];
+
+        for name in regex.capture_names().flatten() {
+            values.push(MValue::keyword(name));
+            values.push(group(caps.name(name)));
+        }
+
+        return MValue::hashmap(&mut values);
+    }
+
+    if caps.len() == 1 {
+        return group(caps.get(0));
+    }
+
+    MValue::vector(caps.iter().map(group).collect())
+}
+
+pub fn re_pattern(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if args[0].is_regex() {
+        return Ok(args[0].clone());
+    }
+
+    MValue::regex(&args[0].cast_to_string()?)
+}
+
+pub fn regex_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::bool(args[0].is_regex()))
+}
+
+pub fn re_find(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let regex = args[0].cast_to_regex()?;
+    let s = args[1].cast_to_string()?;
+
+    let r = regex.captures(&s)
+        .map(|caps| match_result(&regex, &caps))
+        .unwrap_or_else(MValue::nil);
+    Ok(r)
+}
+
+pub fn re_matches(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let regex = args[0].cast_to_regex()?;
+    let anchored = MValue::regex(&format!("^(?:{})$", regex.as_str()))?.cast_to_regex()?;
+    let s = args[1].cast_to_string()?;
+
+    let r = anchored.captures(&s)
+        .map(|caps| match_result(&regex, &caps))
+        .unwrap_or_else(MValue::nil);
+    Ok(r)
+}
+
+pub fn re_seq(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let regex = args[0].cast_to_regex()?;
+    let s = args[1].cast_to_string()?;
+
+    let matches = regex.captures_iter(&s)
+        .map(|caps| match_result(&regex, &caps))
+        .collect::<Vec<_>>();
+
+    if matches.is_empty() {
+        return Ok(MValue::nil());
+    }
+
+    Ok(MValue::list(matches))
+}
+
+pub fn re_groups(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let regex = args[0].cast_to_regex()?;
+    let s = args[1].cast_to_string()?;
+
+    let r = regex.captures(&s)
+        .map(|caps| {
+            let groups = caps.iter()
+                .map(|m| m.map(|m| MValue::string(m.as_str())).unwrap_or_else(MValue::nil))
+                .collect();
+            MValue::vector(groups)
+        })
+        .unwrap_or_else(MValue::nil);
+    Ok(r)
+}
+
+/// The command and the options after it, in `(sh "ls" "-l" :dir "/tmp")`.
+fn command(name: &str, args: &[MValue]) -> Result<(Command, HashMap<String, MValue>)> {
+    assert_min_args!(args, 1);
+
+    let split = args.iter().position(MValue::is_keyword).unwrap_or(args.len());
+    let words = args[..split].iter().map(MValue::cast_to_string).collect::<Result<Vec<_>>>()?;
+
+    let mut options = HashMap::new();
+    for pair in args[split..].chunks(2) {
+        let value = pair.get(1).cloned().unwrap_or_else(MValue::nil);
+        options.insert(pair[0].cast_to_string()?, value);
+    }
+
+    let (program, words) = words.split_first()
+        .ok_or_else(|| Error::EvalError(format!("{} needs a program to run", name)))?;
+
+    let mut command = Command::new(program);
+    command.args(words);
+
+    if let Some(dir) = options.get("dir") {
+        command.current_dir(dir.cast_to_string()?);
+    }
+
+    if let Some(vars) = options.get("env") {
+        for ((name, _), value) in vars.cast_to_hashmap()? {
+            command.env(name, value.pr_str(false));
+        }
+    }
+
+    Ok((command, options))
+}
+
+fn spawn(command: &mut Command) -> Result<Child> {
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::io(command.get_program(), e))
+}
+
+fn timeout_option(value: Option<&MValue>) -> Result<Option<Duration>> {
+    match value {
+        Some(ms) if !ms.is_nil() => Ok(Some(Duration::from_millis(u64::try_from(ms.cast_to_int()?)?))),
+        _ => Ok(None),
+    }
+}
+
+/// Waits for the child to exit, or until the timeout passes, which gives
+/// `None`. Interrupting the evaluation kills the child.
+fn wait(child: &mut Child, timeout: Option<Duration>) -> Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    let mut pause = Duration::from_millis(1);
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if let Err(e) = limits::poll() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            return Ok(None);
+        }
+
+        thread::sleep(pause);
+        pause = (pause * 2).min(Duration::from_millis(50));
+    }
+}
+
+/// The exit code, or for a process killed by a signal 128 plus the
+/// signal, as shells report it.
+fn exit_code(status: ExitStatus) -> MValue {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return MValue::integer(128 + i64::from(signal));
+        }
+    }
+
+    MValue::integer(status.code().map_or(-1, i64::from))
+}
+
+fn read_all<R: Read + Send + 'static>(mut r: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        let _ = r.read_to_end(&mut bytes);
+        bytes
+    })
+}
+
+/// `(sh "cmd" "arg" ... :in "input" :dir "path" :env {"NAME" "value"}
+/// :timeout ms)` runs a program to completion and gives
+/// `{:exit code :out "..." :err "..."}`. Running past the timeout kills it.
+pub fn sh(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let (mut command, options) = command("sh", &args)?;
+    let timeout = timeout_option(options.get("timeout"))?;
+    let mut child = spawn(&mut command)?;
+
+    let input = match options.get("in") {
+        Some(input) if !input.is_nil() => input.pr_str(false),
+        _ => String::new(),
+    };
+
+    // Writing and reading happen on their own threads so that a program
+    // filling one pipe while we write to another can't deadlock.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let out = read_all(child.stdout.take().unwrap());
+    let err = read_all(child.stderr.take().unwrap());
+
+    let status = match wait(&mut child, timeout)? {
+        Some(status) => status,
+        None => {
+            let _ = child.kill();
+            let _ = child.wait();
+
+            // The threads are detached rather than joined: they finish once
+            // the pipes close, which a process the program started in the
+            // background may hold open for much longer than the timeout.
+            drop((writer, out, err));
+            return Err(Error::Interrupted(format!("{} timed out after {:?}",
+                command.get_program().to_string_lossy(), timeout.unwrap_or_default())));
+        },
+    };
+
+    // A program that exits without reading its input breaks the pipe,
+    // which isn't an error of ours.
+    let _ = writer.join();
+    let out = out.join().unwrap_or_default();
+    let err = err.join().unwrap_or_default();
+
+    Ok(MValue::hashmap(&mut vec![
+        MValue::keyword("exit"), exit_code(status),
+        MValue::keyword("out"), MValue::string(String::from_utf8_lossy(&out)),
+        MValue::keyword("err"), MValue::string(String::from_utf8_lossy(&err)),
+    ]))
+}
+
+thread_local! {
+    static PROCESSES: RefCell<HashMap<i64, Child>> = RefCell::new(HashMap::new());
+}
+
+fn process_id(process: &MValue) -> Result<i64> {
+    process.cast_to_hashmap()?
+        .get(&("pid".to_string(), "Keyword".to_string()))
+        .ok_or_else(|| Error::EvalError(format!("{} is not a process", process)))?
+        .cast_to_int()
+}
+
+fn with_process<T, F: FnOnce(&mut Child) -> Result<T>>(process: &MValue, f: F) -> Result<T> {
+    let pid = process_id(process)?;
+
+    PROCESSES.with(|processes| match processes.borrow_mut().get_mut(&pid) {
+        Some(child) => f(child),
+        None => Err(Error::EvalError(format!("There is no process {}", pid))),
+    })
+}
+
+/// `(process "cmd" "arg" ... :dir "path" :env {"NAME" "value"})` starts a
+/// program and gives `{:pid n :in port :out port :err port}` to talk to it
+/// while it runs.
+pub fn process(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let (mut command, _) = command("process", &args)?;
+    let mut child = spawn(&mut command)?;
+    let pid = i64::from(child.id());
+
+    let name = |stream: &str| format!("{} {}", pid, stream);
+    let process = MValue::hashmap(&mut vec![
+        MValue::keyword("pid"), MValue::integer(pid),
+        MValue::keyword("in"), Port::writer(name("stdin"), child.stdin.take().unwrap()),
+        MValue::keyword("out"), Port::reader(name("stdout"), child.stdout.take().unwrap()),
+        MValue::keyword("err"), Port::reader(name("stderr"), child.stderr.take().unwrap()),
+    ]);
+
+    PROCESSES.with(|processes| processes.borrow_mut().insert(pid, child));
+    Ok(process)
+}
+
+/// `(process-wait p)` waits for the process to exit and gives its exit
+/// code. `(process-wait p ms)` gives `nil` if it is still running after
+/// that long. Once it has exited the process is forgotten, and waiting for
+/// it again is an error.
+pub fn process_wait(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let timeout = timeout_option(args.get(1))?;
+    let status = with_process(&args[0], |child| wait(child, timeout))?;
+
+    match status {
+        Some(status) => {
+            let pid = process_id(&args[0])?;
+            PROCESSES.with(|processes| processes.borrow_mut().remove(&pid));
+            Ok(exit_code(status))
+        },
+        None => Ok(MValue::nil()),
+    }
+}
+
+/// `(process-kill p)` kills the process if it is still running.
+pub fn process_kill(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    with_process(&args[0], |child| match child.try_wait()? {
+        Some(_) => Ok(()),
+        None => child.kill().map_err(From::from),
+    })?;
+
+    Ok(MValue::nil())
+}
+
+/// `(getenv "NAME")` is the value of an environment variable, or `nil`.
+pub fn getenv(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let value = std::env::var(args[0].cast_to_string()?).ok();
+    Ok(value.map_or_else(MValue::nil, MValue::string))
+}
+
+/// `(setenv "NAME" value)` sets an environment variable for this process
+/// and the programs it runs, or removes it when `value` is `nil`.
+pub fn setenv(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let name = args[0].cast_to_string()?;
+
+    if name.is_empty() || name.contains('=') || name.contains('\0') {
+        return Err(Error::EvalError(format!("Invalid environment variable name: {:?}", name)));
+    }
+
+    if args[1].is_nil() {
+        std::env::remove_var(name);
+    } else {
+        std::env::set_var(name, args[1].pr_str(false));
+    }
+
     Ok(MValue::nil())
 }
+
+/// `(env-vars)` is a map of every environment variable to its value.
+pub fn env_vars(_args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let mut entries = std::env::vars_os()
+        .flat_map(|(name, value)| vec![
+            MValue::string(name.to_string_lossy()),
+            MValue::string(value.to_string_lossy()),
+        ])
+        .collect();
+
+    Ok(MValue::hashmap(&mut entries))
+}
+
+/// `(exit)` or `(exit status)` ends the program, with status 0 by default.
+pub fn exit(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let status = match args.first() {
+        Some(status) => i32::try_from(status.cast_to_int()?)?,
+        None => 0,
+    };
+
+    Err(Error::Exit(status))
+}
+
+#[test]
+fn test_processes() {
+    use crate::sandbox::{environment, Sandbox};
+
+    let env = environment(&Sandbox::unrestricted()).unwrap();
+    let run = |code: &str| eval(read_form().parse(code.as_bytes()).unwrap(), &env);
+
+    assert_eq!(run("(sh \"sh\" \"-c\" \"tr a-z A-Z; echo $X >&2; exit 3\" :in \"hi\" :env {\"X\" 1})").unwrap(),
+        run("{:exit 3 :out \"HI\" :err \"1\\n\"}").unwrap());
+    assert!(run("(sh \"sleep\" \"5\" :timeout 50)").is_err());
+    assert!(run("(sh \"sh\" \"-c\" \"sleep 5 & sleep 5\" :timeout 50)").is_err());
+    assert!(matches!(run("(sh \"no-such-program\")"), Err(Error::IoError(_))));
+    assert_eq!(run("(sh :dir \"/tmp\")").unwrap_err().to_string(), "Eval error: sh needs a program to run");
+    assert_eq!(run("(process :dir \"/tmp\")").unwrap_err().to_string(), "Eval error: process needs a program to run");
+
+    run("(def! p (process \"cat\"))").unwrap();
+    run("(do (write \"line\\n\" (get p :in)) (close (get p :in)))").unwrap();
+    assert_eq!(run("(read-line (get p :out))").unwrap(), MValue::string("line"));
+    assert_eq!(run("(process-wait p)").unwrap(), MValue::integer(0));
+
+    run("(def! p (process \"sleep\" \"5\"))").unwrap();
+    assert_eq!(run("(process-wait p 10)").unwrap(), MValue::nil());
+    run("(process-kill p)").unwrap();
+    assert_eq!(run("(process-wait p)").unwrap(), MValue::integer(128 + 9));
+    assert!(run("(process-wait p)").is_err());
+    assert!(PROCESSES.with(|processes| processes.borrow().is_empty()));
+
+    run("(setenv \"MAL_TEST_VAR\" 42)").unwrap();
+    assert_eq!(run("(getenv \"MAL_TEST_VAR\")").unwrap(), MValue::string("42"));
+    assert_eq!(run("(get (env-vars) \"MAL_TEST_VAR\")").unwrap(), MValue::string("42"));
+    run("(setenv \"MAL_TEST_VAR\" nil)").unwrap();
+    assert_eq!(run("(getenv \"MAL_TEST_VAR\")").unwrap(), MValue::nil());
+
+    assert!(matches!(run("(try* (exit 3) (catch* e 0))"), Err(Error::Exit(3))));
+}
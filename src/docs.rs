@@ -0,0 +1,184 @@
+//! Documentation kept as metadata: definitions record their `:name`,
+//! `:arglists`, `:doc` and where they were read from, and builtins are
+//! registered with theirs.
+
+use crate::core::assert_min_args;
+use crate::env::Env;
+use crate::ports;
+use crate::reader::{form_end, read_form};
+use crate::sandbox::resolve_path;
+use crate::types::*;
+
+use regex::Regex;
+
+use std::convert::TryFrom;
+
+/// `value` with its name, arglists such as `"[coll] [n coll]"` and
+/// docstring as metadata.
+pub fn document(value: MValue, name: &str, arglists: &str, doc: &str) -> MValue {
+    let arglists = format!("({})", arglists);
+    let arglists = read_form().parse(arglists.as_bytes()).unwrap_or_else(|_| MValue::list(vec![]));
+
+    let meta = MValue::hashmap(&mut vec![
+        MValue::keyword("name"), MValue::symbol(name),
+        MValue::keyword("arglists"), MValue::list(arglists.cast_to_list().unwrap_or_default()),
+        MValue::keyword("doc"), MValue::string(doc),
+    ]);
+
+    value.with_meta(meta).unwrap_or(value)
+}
+
+fn meta_entry(value: &MValue, key: &str) -> Option<MValue> {
+    let meta = value.meta().ok()?.cast_to_hashmap().ok()?;
+    meta.get(&(key.to_string(), "Keyword".to_string())).cloned()
+}
+
+fn describe(value: &MValue) -> String {
+    let name = meta_entry(value, "name").map_or_else(|| value.pr_str(true), |name| name.pr_str(false));
+    let mut s = format!("-------------------------\n{}\n", name);
+
+    if let Some(arglists) = meta_entry(value, "arglists") {
+        s.push_str(&format!("{}\n", arglists.pr_str(true)));
+    }
+
+    if value.is_macro() {
+        s.push_str("Macro\n");
+    }
+
+    match meta_entry(value, "doc") {
+        Some(doc) => s.push_str(&format!("  {}\n", doc.pr_str(false))),
+        None => s.push_str("  No documentation\n"),
+    }
+
+    s
+}
+
+/// `(doc f)` prints the name, arglists and docstring of `f`.
+pub fn doc(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    ports::print(&env, &describe(&args[0]))?;
+    Ok(MValue::nil())
+}
+
+/// `(find-doc pattern)` prints the documentation of everything bound whose
+/// name or docstring matches the regex or string.
+pub fn find_doc(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let pattern = match *args[0].0 {
+        MalVal::Regex(ref regex) => regex.clone(),
+        _ => Regex::new(&regex::escape(&args[0].cast_to_string()?))
+            .map_err(|e| Error::EvalError(format!("Invalid regex: {}", e)))?,
+    };
+
+    let env = env.ok_or_else(|| Error::EvalError("find-doc needs an environment".to_string()))?;
+    let mut names = env.visible_names();
+    names.sort();
+    names.dedup();
+
+    for name in names {
+        let value = match env.get(&name) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let doc = meta_entry(&value, "doc").map(|doc| doc.pr_str(false)).unwrap_or_default();
+
+        if pattern.is_match(&name) || pattern.is_match(&doc) {
+            ports::print(&Some(env.clone()), &describe(&value))?;
+        }
+    }
+
+    Ok(MValue::nil())
+}
+
+/// `(arglists f)` is the list of parameter vectors `f` takes, or `nil`.
+pub fn arglists(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(meta_entry(&args[0], "arglists").unwrap_or_else(MValue::nil))
+}
+
+/// The text of the form at `line` and `column` of `path`, if it can still
+/// be read there. The file may have been changed or removed since, or be
+/// out of the sandbox's reach.
+fn source_text(path: &str, line: i64, column: i64, env: &Option<Env>) -> Option<String> {
+    let path = resolve_path(env, path).ok()?;
+    let text = std::fs::read_to_string(path).ok()?;
+
+    let line = usize::try_from(line - 1).ok()?;
+    let column = usize::try_from(column - 1).ok()?;
+
+    let start = text.split_inclusive('\n').take(line).map(str::len).sum::<usize>() + column;
+
+    if !text.is_char_boundary(start) || start >= text.len() {
+        return None;
+    }
+
+    form_end(text.as_bytes(), start).and_then(|end| text.get(start..end)).map(str::to_string)
+}
+
+/// `(source f)` prints the definition of `f` as it was written in its
+/// file, or as it reads back when it was entered some other way.
+pub fn source(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let value = &args[0];
+    let name = meta_entry(value, "name").unwrap_or_else(MValue::nil);
+
+    let position = (meta_entry(value, "file"), meta_entry(value, "line"), meta_entry(value, "column"));
+    let text = match position {
+        (Some(file), Some(line), Some(column)) =>
+            source_text(&file.cast_to_string()?, line.cast_to_int()?, column.cast_to_int()?, &env),
+        _ => None,
+    };
+
+    let text = match (text, &*value.0) {
+        (Some(text), _) => text,
+        (None, MalVal::Lambda(ref closure, _)) => {
+            let def = if value.is_macro() { "defmacro!" } else { "def!" };
+            MValue::list(vec![MValue::symbol(def), name, closure.source()]).pr_str(true)
+        },
+        (None, MalVal::Fun(_, _, _)) => format!("{} is a builtin", name.pr_str(false)),
+        (None, _) => format!("No source for {}", value.pr_str(true)),
+    };
+
+    ports::print(&env, &format!("{}\n", text))?;
+    Ok(MValue::nil())
+}
+
+#[test]
+fn test_docs() {
+    use crate::eval::eval;
+    use crate::sandbox::{environment, Sandbox};
+
+    let env = environment(&Sandbox::unrestricted()).unwrap();
+    let run = |code: &str| eval(read_form().parse(code.as_bytes()).unwrap(), &env);
+
+    let path = std::env::temp_dir().join(format!("mal-docs-{}.mal", std::process::id()));
+    std::fs::write(&path, "; helpers\n\n(defn twice\n  \"Doubles x.\"\n  [x] (* 2 x)) (def! y 1)\n").unwrap();
+    run(&format!("(load-file {:?})", path.display().to_string())).unwrap();
+
+    assert_eq!(run("(twice 4)").unwrap(), MValue::integer(8));
+    assert_eq!(run("(arglists twice)").unwrap(), run("'([x])").unwrap());
+    assert_eq!(run("(with-out-str (doc twice))").unwrap(),
+        MValue::string("-------------------------\ntwice\n([x])\n  Doubles x.\n"));
+    assert_eq!(run("(with-out-str (source twice))").unwrap(),
+        MValue::string("(defn twice\n  \"Doubles x.\"\n  [x] (* 2 x))\n"));
+
+    // Once the file is cut short or gone, the definition is printed instead.
+    std::fs::write(&path, "; helpers\n").unwrap();
+    let printed = MValue::string("(def! twice (fn* (x) (do (* 2 x))))\n");
+    assert_eq!(run("(with-out-str (source twice))").unwrap(), printed);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(run("(with-out-str (source twice))").unwrap(), printed);
+
+    assert_eq!(run("(arglists map)").unwrap(), run("'([f] [f coll & colls])").unwrap());
+    assert_eq!(run("(- 5)").unwrap(), MValue::integer(-5));
+    assert_eq!(run("(- 1.5)").unwrap(), MValue::float(-1.5));
+    assert_eq!(run("(arglists /)").unwrap(), run("'([x y & more])").unwrap());
+    assert!(run("(/ 5)").is_err());
+    assert_eq!(run("[(= 1 1 2) (< 1 2 3) (< 1 3 2) (>= 3 3 1)]").unwrap().pr_str(true), "[false true false true]");
+    assert!(run("(str/includes? (with-out-str (find-doc #\"^str/join$\")) \"str/join\")").unwrap().is_truthy());
+
+}
@@ -0,0 +1,270 @@
+//! Line editing for the REPL: completing names, highlighting code and
+//! telling when an entry is complete.
+//!
+//! A line is split into tokens much as the reader would, though leniently,
+//! since what is being typed is usually not a whole form yet.
+
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::Helper;
+
+use crate::env::Env;
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
+
+const SPECIAL_FORMS: &[&str] = &[
+    "def!", "defmacro!", "let*", "fn*", "if", "do", "quote", "quasiquote", "unquote",
+    "splice-unquote", "macroexpand", "try*", "catch*", "lazy-seq", "var", "binding", "with-open",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Open,
+    Close,
+    Str { terminated: bool },
+    Comment,
+    Keyword,
+    Number,
+    Word,
+    Space,
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()[]{}\",;".contains(c)
+}
+
+/// Takes characters while `f` accepts them, moving `end` past them.
+fn take_while<F: FnMut(char) -> bool>(chars: &mut Peekable<CharIndices>, end: &mut usize, mut f: F) {
+    while let Some(&(i, c)) = chars.peek() {
+        if !f(c) {
+            break;
+        }
+        *end = i + c.len_utf8();
+        chars.next();
+    }
+}
+
+fn tokens(line: &str) -> Vec<(Kind, Range<usize>)> {
+    let mut tokens = vec![];
+    let mut chars = line.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+
+        let kind = match c {
+            '(' | '[' | '{' => Kind::Open,
+            ')' | ']' | '}' => Kind::Close,
+            ';' => {
+                take_while(&mut chars, &mut end, |c| c != '\n');
+                Kind::Comment
+            },
+            '"' => {
+                let mut escaped = false;
+                let mut terminated = false;
+                take_while(&mut chars, &mut end, |c| {
+                    // Stops after the closing quote has been taken.
+                    !terminated && {
+                        terminated = !escaped && c == '"';
+                        escaped = !escaped && c == '\\';
+                        true
+                    }
+                });
+                Kind::Str { terminated }
+            },
+            _ if c.is_whitespace() || c == ',' => {
+                take_while(&mut chars, &mut end, |c| c.is_whitespace() || c == ',');
+                Kind::Space
+            },
+            _ => {
+                take_while(&mut chars, &mut end, |c| !is_delimiter(c));
+                word_kind(&line[start..end])
+            },
+        };
+
+        tokens.push((kind, start..end));
+    }
+
+    tokens
+}
+
+fn word_kind(word: &str) -> Kind {
+    let digits = word.strip_prefix('-').unwrap_or(word);
+
+    if word.starts_with(':') {
+        Kind::Keyword
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        Kind::Number
+    } else {
+        Kind::Word
+    }
+}
+
+/// Whether `input` has closed every bracket and string it opened, so that
+/// the REPL can evaluate it rather than read another line.
+pub fn is_complete(input: &str) -> bool {
+    let mut depth = 0;
+
+    for (kind, _) in tokens(input) {
+        match kind {
+            Kind::Open => depth += 1,
+            Kind::Close => depth -= 1,
+            Kind::Str { terminated: false } => return false,
+            _ => {},
+        }
+    }
+
+    depth <= 0
+}
+
+/// Completes names bound in the environment, special forms and keywords
+/// that were entered before, and highlights what is typed.
+pub struct ReplHelper {
+    env: Env,
+    keywords: RefCell<BTreeSet<String>>,
+}
+
+impl ReplHelper {
+    pub fn new(env: &Env) -> Self {
+        ReplHelper { env: env.clone(), keywords: RefCell::new(BTreeSet::new()) }
+    }
+
+    /// Keeps the keywords in an entry to complete them later.
+    pub fn remember(&self, input: &str) {
+        let mut keywords = self.keywords.borrow_mut();
+
+        for (kind, range) in tokens(input) {
+            if kind == Kind::Keyword && range.len() > 1 {
+                keywords.insert(input[range].to_string());
+            }
+        }
+    }
+
+    fn candidates(&self, prefix: &str) -> Vec<String> {
+        let names = if prefix.starts_with(':') {
+            self.keywords.borrow().iter().cloned().collect()
+        } else {
+            let mut names = self.env.visible_names();
+            names.extend(SPECIAL_FORMS.iter().map(|s| s.to_string()));
+            names
+        };
+
+        let names = names.into_iter()
+            .filter(|name| name.starts_with(prefix))
+            .collect::<BTreeSet<String>>();
+
+        names.into_iter().collect()
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize) -> rustyline::Result<(usize, Vec<String>)> {
+        let token = tokens(&line[..pos]).pop();
+
+        let start = match token {
+            Some((Kind::Word, range)) | Some((Kind::Keyword, range)) => {
+                // Reader macros such as ' and @ aren't part of the name.
+                let word = &line[range.clone()];
+                range.start + word.len() - word.trim_start_matches(|c| "'`~@^#".contains(c)).len()
+            },
+            _ => return Ok((pos, vec![])),
+        };
+
+        match &line[start..pos] {
+            "" => Ok((pos, vec![])),
+            prefix => Ok((start, self.candidates(prefix))),
+        }
+    }
+}
+
+impl Hinter for ReplHelper {
+    fn hint(&self, _line: &str, _pos: usize) -> Option<String> {
+        None
+    }
+}
+
+/// The bracket that matches the one just before the cursor, or under it.
+fn matching_bracket(tokens: &[(Kind, Range<usize>)], pos: usize) -> Option<usize> {
+    let at = |p: usize| tokens.iter().position(|(kind, range)| {
+        range.start == p && (*kind == Kind::Open || *kind == Kind::Close)
+    });
+
+    let i = pos.checked_sub(1).and_then(at).or_else(|| at(pos))?;
+    let mut depth = 0;
+
+    let mut step = |j: usize| {
+        match tokens[j].0 {
+            Kind::Open => depth += 1,
+            Kind::Close => depth -= 1,
+            _ => return None,
+        }
+        Some(depth == 0).filter(|&matched| matched).map(|_| tokens[j].1.start)
+    };
+
+    match tokens[i].0 {
+        Kind::Open => (i..tokens.len()).find_map(&mut step),
+        _ => (0..=i).rev().find_map(&mut step),
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        let tokens = tokens(line);
+        let matching = matching_bracket(&tokens, pos);
+        let mut highlighted = String::with_capacity(line.len() * 2);
+
+        for (kind, range) in tokens {
+            let colour = match kind {
+                _ if Some(range.start) == matching => "1;34",
+                Kind::Str { .. } => "32",
+                Kind::Keyword => "35",
+                Kind::Number => "36",
+                Kind::Comment => "90",
+                _ => "",
+            };
+
+            match colour {
+                "" => highlighted.push_str(&line[range]),
+                _ => highlighted.push_str(&format!("\x1b[{}m{}\x1b[0m", colour, &line[range])),
+            }
+        }
+
+        Cow::Owned(highlighted)
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        // Colours depend on the whole line, so every change redraws it.
+        true
+    }
+}
+
+impl Helper for ReplHelper {}
+
+#[test]
+fn test_editor() {
+    assert!(is_complete("(+ 1 [2 {:a \"(\"}])"));
+    assert!(!is_complete("(let* (a 1)"));
+    assert!(!is_complete("(str \"a\\\" b"));
+    assert!(is_complete("(+ 1 2) ; (unclosed"));
+
+    let env = Env::new(None);
+    env.set("str/join", crate::types::MValue::nil());
+    env.set("str/split", crate::types::MValue::nil());
+    let helper = ReplHelper::new(&Env::new(Some(env)));
+    helper.remember("{:name 1 :size 2}");
+
+    let complete = |line: &str| helper.complete(line, line.len()).unwrap();
+    assert_eq!(complete("(str/"), (1, vec!["str/join".to_string(), "str/split".to_string()]));
+    assert_eq!(complete("'(let"), (2, vec!["let*".to_string()]));
+    assert_eq!(complete("(get m :s"), (7, vec![":size".to_string()]));
+    assert_eq!(complete("(str \"str/"), (10, vec![]));
+
+    assert_eq!(helper.highlight("(f [1])", 7), "\x1b[1;34m(\x1b[0mf [\x1b[36m1\x1b[0m])");
+}
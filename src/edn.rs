@@ -0,0 +1,570 @@
+use indexmap::IndexMap;
+
+use crate::env::Env;
+use crate::eval::handle_function;
+use crate::types::*;
+
+/// Reads the first EDN value in `input`, returning nil when there is none.
+///
+/// Tagged literals are resolved through `readers`, a hash-map from tag
+/// symbols to the functions that receive the tagged value.
+///
+/// Map keys and set elements must be scalars: nil, booleans, numbers,
+/// strings, keywords or symbols, which is what hash-maps and sets can hold.
+/// Collections in those places are a parse error rather than being read
+/// some other way.
+pub fn read(input: &str, readers: &MValue) -> Result<MValue> {
+    let mut parser = EdnParser {
+        input: input.chars().collect(),
+        pos: 0,
+        readers: readers.cast_to_hashmap()?,
+    };
+
+    parser.skip_ignored()?;
+
+    if parser.peek().is_none() {
+        return Ok(MValue::nil());
+    }
+
+    parser.parse_value()
+}
+
+/// Renders `value` as EDN, failing for values that have no EDN
+/// representation such as functions and atoms.
+pub fn write(value: &MValue) -> Result<String> {
+    let mut out = String::new();
+    write_value(value, &mut out)?;
+    Ok(out)
+}
+
+/// The tagged literal handlers every environment starts with: `#inst` and
+/// `#uuid`, which validate their string and keep it tagged, so that it
+/// writes back as it was read.
+pub fn default_readers() -> MValue {
+    MValue::hashmap(&mut vec![
+        MValue::symbol("inst"), MValue::function(read_inst, None),
+        MValue::symbol("uuid"), MValue::function(read_uuid, None),
+    ])
+}
+
+fn read_inst(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let s = tagged_string(&args, "inst")?;
+    let b = s.as_bytes();
+    let digits = |range: std::ops::Range<usize>| b[range].iter().all(u8::is_ascii_digit);
+
+    let mut valid = b.len() >= 4 && digits(0..4);
+    let mut i = 4;
+
+    for &(separator, len) in &[(b'-', 2), (b'-', 2), (b'T', 2), (b':', 2), (b':', 2)] {
+        if i == b.len() || !valid || b[i] != separator {
+            break;
+        }
+
+        valid = b.len() >= i + 1 + len && digits(i + 1..i + 1 + len);
+        i += 1 + len;
+    }
+
+    if valid && i < b.len() && b[i] == b'.' {
+        let start = i + 1;
+        i = start + b[start..].iter().take_while(|c| c.is_ascii_digit()).count();
+        valid = i > start;
+    }
+
+    valid = valid && match &s[i..] {
+        "" | "Z" => true,
+        offset => offset.len() == 6
+            && (offset.starts_with('+') || offset.starts_with('-'))
+            && digits(i + 1..i + 3) && b[i + 3] == b':' && digits(i + 4..i + 6),
+    };
+
+    if !valid {
+        return Err(Error::ParseError(format!("Invalid #inst timestamp \"{}\"", s)));
+    }
+
+    Ok(MValue::tagged("inst", MValue::string(s)))
+}
+
+fn read_uuid(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let s = tagged_string(&args, "uuid")?;
+
+    let groups = s.split('-').map(str::len).collect::<Vec<_>>();
+    let valid = groups == [8, 4, 4, 4, 12]
+        && s.chars().all(|c| c == '-' || c.is_ascii_hexdigit());
+
+    if !valid {
+        return Err(Error::ParseError(format!("Invalid #uuid \"{}\"", s)));
+    }
+
+    Ok(MValue::tagged("uuid", MValue::string(s)))
+}
+
+fn tagged_string(args: &[MValue], tag: &str) -> Result<String> {
+    match args.first() {
+        Some(v) if v.is_string() => v.cast_to_string(),
+        _ => Err(Error::ParseError(format!("#{} expects a string", tag))),
+    }
+}
+
+struct EdnParser {
+    input: Vec<char>,
+    pos: usize,
+    readers: IndexMap<(String, String), MValue>,
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_alphanumeric() || ".*+!-_?$%&=<>/:#'".contains(c)
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()[]{}\",;".contains(c)
+}
+
+impl EdnParser {
+    fn error(&self, message: &str) -> Error {
+        let consumed = &self.input[..self.pos.min(self.input.len())];
+        let line = consumed.iter().filter(|&&c| c == '\n').count() + 1;
+        let column = consumed.iter().rev().take_while(|&&c| c != '\n').count() + 1;
+
+        Error::ParseError(format!("EDN {} at line {}, column {}", message, line, column))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input.get(self.pos).cloned()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.input.get(self.pos + offset).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn skip_ignored(&mut self) -> Result<()> {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() || c == ',' => self.pos += 1,
+                Some(';') => {
+                    while let Some(c) = self.next() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                },
+                Some('#') if self.peek_at(1) == Some('_') => {
+                    self.pos += 2;
+                    self.skip_ignored()?;
+                    self.parse_value()?;
+                },
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<MValue> {
+        match self.peek() {
+            Some('(') => self.parse_sequence(')').map(MValue::list),
+            Some('[') => self.parse_sequence(']').map(MValue::vector),
+            Some('{') => self.parse_map(),
+            Some('"') => self.parse_string(),
+            Some('\\') => self.parse_char(),
+            Some('#') => self.parse_dispatch(),
+            Some(':') => self.parse_keyword(),
+            Some(c) if c.is_ascii_digit() => self.parse_number(),
+            Some('+') | Some('-') if self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) =>
+                self.parse_number(),
+            Some(c) if is_symbol_char(c) => self.parse_symbol(),
+            Some(c) => Err(self.error(&format!("unexpected character '{}'", c))),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn parse_sequence(&mut self, end: char) -> Result<Vec<MValue>> {
+        let mut values = Vec::new();
+        self.pos += 1;
+
+        loop {
+            self.skip_ignored()?;
+
+            match self.peek() {
+                Some(c) if c == end => {
+                    self.pos += 1;
+                    return Ok(values);
+                },
+                Some(_) => values.push(self.parse_value()?),
+                None => return Err(self.error(&format!("expected '{}' but input ended", end))),
+            }
+        }
+    }
+
+    fn parse_map(&mut self) -> Result<MValue> {
+        let start = self.pos;
+        let values = self.parse_sequence('}')?;
+
+        if values.len() % 2 != 0 {
+            self.pos = start;
+            return Err(self.error("map literal must contain an even number of forms"));
+        }
+
+        let mut map = IndexMap::new();
+
+        for pair in values.chunks(2) {
+            let key = self.scalar_key(&pair[0], start, "map keys")?;
+            if map.insert(key, pair[1].clone()).is_some() {
+                self.pos = start;
+                return Err(self.error(&format!("duplicate map key {}", pair[0].pr_str(true))));
+            }
+        }
+
+        Ok(MValue::from_hashmap(map))
+    }
+
+    fn parse_set(&mut self) -> Result<MValue> {
+        let start = self.pos;
+        let values = self.parse_sequence('}')?;
+        let mut set = IndexMap::new();
+
+        for value in values {
+            let key = self.scalar_key(&value, start, "set elements")?;
+            if set.insert(key, value.clone()).is_some() {
+                self.pos = start;
+                return Err(self.error(&format!("duplicate set element {}", value.pr_str(true))));
+            }
+        }
+
+        Ok(MValue::from_set(set))
+    }
+
+    fn scalar_key(&mut self, value: &MValue, start: usize, what: &str) -> Result<(String, String)> {
+        value.hash_key().map_err(|_| {
+            self.pos = start;
+            self.error(&format!(
+                "{} must be nil, booleans, numbers, strings, keywords or symbols, not {}",
+                what, value.pr_str(true)))
+        })
+    }
+
+    fn parse_dispatch(&mut self) -> Result<MValue> {
+        self.pos += 1;
+
+        match self.peek() {
+            Some('{') => self.parse_set(),
+            Some('#') => {
+                self.pos += 1;
+                match self.parse_token().as_str() {
+                    "Inf" => Ok(MValue::float(f64::INFINITY)),
+                    "-Inf" => Ok(MValue::float(f64::NEG_INFINITY)),
+                    "NaN" => Ok(MValue::float(f64::NAN)),
+                    token => Err(self.error(&format!("unknown symbolic value ##{}", token))),
+                }
+            },
+            Some(c) if c.is_alphabetic() => self.parse_tagged(),
+            _ => Err(self.error("invalid dispatch character after '#'")),
+        }
+    }
+
+    fn parse_tagged(&mut self) -> Result<MValue> {
+        let start = self.pos;
+        let tag = self.parse_token();
+
+        self.skip_ignored()?;
+        let value = self.parse_value()?;
+
+        match self.readers.get(&(tag.clone(), "Symbol".to_string())) {
+            Some(handler) => handle_function(handler, vec![value]),
+            None => {
+                self.pos = start;
+                Err(self.error(&format!("no reader function for tag {}", tag)))
+            },
+        }
+    }
+
+    fn parse_token(&mut self) -> String {
+        let start = self.pos;
+
+        while self.peek().is_some_and(is_symbol_char) {
+            self.pos += 1;
+        }
+
+        self.input[start..self.pos].iter().collect()
+    }
+
+    fn parse_symbol(&mut self) -> Result<MValue> {
+        let start = self.pos;
+        let token = self.parse_token();
+
+        match token.as_str() {
+            "nil" => return Ok(MValue::nil()),
+            "true" => return Ok(MValue::bool(true)),
+            "false" => return Ok(MValue::bool(false)),
+            _ => {},
+        }
+
+        if !valid_symbol(&token) {
+            self.pos = start;
+            return Err(self.error(&format!("invalid symbol {}", token)));
+        }
+
+        Ok(MValue::symbol(token))
+    }
+
+    fn parse_keyword(&mut self) -> Result<MValue> {
+        let start = self.pos;
+        self.pos += 1;
+        let token = self.parse_token();
+
+        if token.starts_with(':') || !valid_symbol(&token) {
+            self.pos = start;
+            return Err(self.error(&format!("invalid keyword :{}", token)));
+        }
+
+        Ok(MValue::keyword(token))
+    }
+
+    fn parse_number(&mut self) -> Result<MValue> {
+        let start = self.pos;
+        let mut token = self.parse_token();
+
+        let is_float = token.ends_with('M')
+            || token.contains(&['.', 'e', 'E'][..]);
+
+        if token.ends_with('M') || token.ends_with('N') {
+            token.pop();
+        }
+
+        let token = token.trim_start_matches('+');
+
+        let value = if is_float {
+            token.parse().ok().map(MValue::float)
+        } else {
+            token.parse().ok().map(MValue::integer)
+        };
+
+        value.ok_or_else(|| {
+            self.pos = start;
+            self.error(&format!("invalid number {}", token))
+        })
+    }
+
+    fn parse_string(&mut self) -> Result<MValue> {
+        let mut s = String::new();
+        self.pos += 1;
+
+        loop {
+            match self.next() {
+                Some('"') => return Ok(MValue::string(s)),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('n') => '\n',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        Some('u') => self.parse_unicode()?,
+                        _ => {
+                            self.pos -= 1;
+                            return Err(self.error("invalid escape sequence"));
+                        },
+                    };
+                    s.push(c);
+                },
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn parse_unicode(&mut self) -> Result<char> {
+        let start = self.pos;
+        let hex = self.input.iter().skip(self.pos).take(4).collect::<String>();
+        self.pos += 4;
+
+        u32::from_str_radix(&hex, 16).ok()
+            .filter(|_| hex.len() == 4)
+            .and_then(std::char::from_u32)
+            .ok_or_else(|| {
+                self.pos = start;
+                self.error("invalid unicode escape")
+            })
+    }
+
+    /// Characters have no type of their own, so `\a` reads as the string "a".
+    fn parse_char(&mut self) -> Result<MValue> {
+        let start = self.pos;
+        self.pos += 1;
+
+        let first = self.next().ok_or_else(|| self.error("expected character after '\\'"))?;
+        let mut name = first.to_string();
+
+        while let Some(c) = self.peek().filter(|&c| !is_delimiter(c)) {
+            name.push(c);
+            self.pos += 1;
+        }
+
+        let c = match name.as_str() {
+            "newline" => '\n',
+            "return" => '\r',
+            "space" => ' ',
+            "tab" => '\t',
+            n if n.chars().count() == 1 => first,
+            n if n.starts_with('u') && n.len() == 5 => {
+                self.pos = start + 2;
+                self.parse_unicode()?
+            },
+            n => {
+                self.pos = start;
+                return Err(self.error(&format!("invalid character \\{}", n)));
+            },
+        };
+
+        Ok(MValue::string(c))
+    }
+}
+
+fn valid_symbol(token: &str) -> bool {
+    if token == "/" {
+        return true;
+    }
+
+    let mut parts = token.splitn(2, '/');
+    let valid_part = |part: &str| {
+        let mut chars = part.chars();
+        match chars.next() {
+            None => false,
+            Some(c) if c.is_ascii_digit() || c == ':' || c == '#' || c == '\'' => false,
+            Some(c) if c == '+' || c == '-' || c == '.' =>
+                !chars.next().is_some_and(|c| c.is_ascii_digit()),
+            Some(_) => true,
+        }
+    };
+
+    let first = parts.next().is_some_and(valid_part);
+
+    match parts.next() {
+        Some(name) => first && valid_part(name) && !name.contains('/'),
+        None => first,
+    }
+}
+
+fn write_value(value: &MValue, out: &mut String) -> Result<()> {
+    match *value.0 {
+        MalVal::Nil => out.push_str("nil"),
+        MalVal::Bool(b) => out.push_str(&b.to_string()),
+        MalVal::Int(n) => out.push_str(&n.to_string()),
+        MalVal::Float(n) => out.push_str(&format_float(n)),
+        MalVal::Sym(ref s) => out.push_str(s),
+        MalVal::Keyword(ref s) => {
+            out.push(':');
+            out.push_str(s);
+        },
+        MalVal::Str(ref s) => {
+            out.push('"');
+            for c in s.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    '\t' => out.push_str("\\t"),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        },
+        MalVal::List(ref l, _) => write_sequence(l.iter(), "(", ")", out)?,
+        MalVal::Vector(ref l, _) => write_sequence(l.iter(), "[", "]", out)?,
+        MalVal::Set(ref s, _) => write_sequence(s.values(), "#{", "}", out)?,
+        MalVal::HashMap(ref hm, _) => {
+            let entries = hm.iter()
+                .map(|(k, v)| MValue::reconstruct(k).map(|k| vec![k, v.clone()]))
+                .collect::<Result<Vec<_>>>()?
+                .concat();
+            write_sequence(entries.iter(), "{", "}", out)?;
+        },
+        MalVal::Tagged(ref tag, ref v) => {
+            out.push('#');
+            out.push_str(tag);
+            out.push(' ');
+            write_value(v, out)?;
+        },
+        MalVal::SortedSet(ref s, _) => write_sequence(s.keys().iter(), "#{", "}", out)?,
+        MalVal::SortedMap(ref s, _) => {
+            let entries = s.iter()
+                .flat_map(|(k, v)| vec![k.clone(), v.clone()])
+                .collect::<Vec<_>>();
+            write_sequence(entries.iter(), "{", "}", out)?;
+        },
+        _ => return Err(Error::EvalError(format!("{} can't be represented in EDN", value))),
+    }
+
+    Ok(())
+}
+
+fn write_sequence<'a, I>(values: I, start: &str, end: &str, out: &mut String) -> Result<()>
+where
+    I: Iterator<Item = &'a MValue>,
+{
+    out.push_str(start);
+
+    for (i, value) in values.enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        write_value(value, out)?;
+    }
+
+    out.push_str(end);
+    Ok(())
+}
+
+#[test]
+fn test_read() {
+    let input = r#"#_ignored {:a/b [1 -2.5 \newline "s"] :c #{nil} :d (x #inst "1985-04-12T23:20:50.52Z")}"#;
+    let value = read(input, &default_readers()).unwrap();
+
+    let expected = MValue::hashmap(&mut vec![
+        MValue::keyword("a/b"),
+        MValue::vector(vec![
+            MValue::integer(1), MValue::float(-2.5), MValue::string("\n"), MValue::string("s")]),
+        MValue::keyword("c"),
+        MValue::set(vec![MValue::nil()]).unwrap(),
+        MValue::keyword("d"),
+        MValue::list(vec![
+            MValue::symbol("x"), MValue::tagged("inst", MValue::string("1985-04-12T23:20:50.52Z"))]),
+    ]);
+
+    assert_eq!(value, expected);
+}
+
+#[test]
+fn test_read_errors() {
+    let readers = default_readers();
+
+    assert!(read("#uuid \"not-a-uuid\"", &readers).is_err());
+    assert!(read("#point [1 2]", &readers).is_err());
+    assert!(read("{:a 1 :a 2}", &readers).is_err());
+    assert!(read("::a", &readers).is_err());
+
+    assert_eq!(
+        read("#{[1 2]}", &readers).unwrap_err().to_string(),
+        "Parse error: EDN set elements must be nil, booleans, numbers, strings, keywords or \
+         symbols, not [1 2] at line 1, column 2");
+    assert_eq!(
+        read("{:a 1\n [1 2] :b}", &readers).unwrap_err().to_string(),
+        "Parse error: EDN map keys must be nil, booleans, numbers, strings, keywords or \
+         symbols, not [1 2] at line 1, column 1");
+}
+
+#[test]
+fn test_write() {
+    let value = MValue::list(vec![
+        MValue::keyword("k"), MValue::string("a\tb"), MValue::float(1.0), MValue::nil()]);
+
+    assert_eq!(write(&value).unwrap(), r#"(:k "a\tb" 1.0 nil)"#);
+    assert!(write(&MValue::atom(MValue::nil())).is_err());
+
+    let input = r#"[#inst "1985-04-12T23:20:50.52Z" #uuid "f81d4fae-7dec-11d0-a765-00a0c91e6bf6"]"#;
+    assert_eq!(write(&read(input, &default_readers()).unwrap()).unwrap(), input);
+}
@@ -1,12 +1,27 @@
 use std::collections::HashMap;
 
+use crate::gc::{self, Edge};
 use crate::types::*;
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 
 #[derive(Debug, Clone)]
 pub struct Env(Rc<RefCell<SharedEnv>>);
 
+/// An environment that does not keep its bindings alive, as the collector
+/// holds on to every environment.
+pub(crate) struct WeakEnv(Weak<RefCell<SharedEnv>>);
+
+impl WeakEnv {
+    pub(crate) fn upgrade(&self) -> Option<Env> {
+        self.0.upgrade().map(Env)
+    }
+
+    pub(crate) fn strong_count(&self) -> usize {
+        self.0.strong_count()
+    }
+}
+
 #[derive(Debug, Clone)]
 struct SharedEnv {
     mappings: HashMap<String, MValue>,
@@ -15,10 +30,16 @@ struct SharedEnv {
 
 impl Env {
     pub fn new(outer: Option<Env>) -> Self {
-        Env(Rc::new(RefCell::new(SharedEnv {
+        Env::register(SharedEnv {
             mappings: HashMap::new(),
             outer,
-        })))
+        })
+    }
+
+    fn register(shared: SharedEnv) -> Self {
+        let env = Env(Rc::new(RefCell::new(shared)));
+        gc::register(WeakEnv(Rc::downgrade(&env.0)));
+        env
     }
 
     pub fn with_binds(outer: Option<Env>, binds: Vec<String>, exprs: Vec<MValue>) -> Result<Self> {
@@ -42,7 +63,7 @@ impl Env {
             mappings.insert(binds[consumed + 1].clone(), MValue::list(rest));
         }
 
-        Ok(Env(Rc::new(RefCell::new(SharedEnv { mappings, outer }))))
+        Ok(Env::register(SharedEnv { mappings, outer }))
     }
 
     pub fn get(&self, key: &str) -> Option<MValue> {
@@ -58,4 +79,65 @@ impl Env {
     pub fn set<T: ToString>(&self, key: T, value: MValue) {
         self.0.borrow_mut().mappings.insert(key.to_string(), value);
     }
+
+    /// The names bound in this environment, not counting outer ones.
+    pub fn names(&self) -> Vec<String> {
+        self.0.borrow().mappings.keys().cloned().collect()
+    }
+
+    /// The names bound here and in the outer environments.
+    pub fn visible_names(&self) -> Vec<String> {
+        let mut names = self.names();
+
+        if let Some(ref outer) = self.0.borrow().outer {
+            names.extend(outer.visible_names());
+        }
+
+        names
+    }
+
+    /// Replaces the value of `key` where it is defined, returning the value
+    /// it had, or `None` when it isn't defined anywhere.
+    pub fn rebind(&self, key: &str, value: MValue) -> Option<MValue> {
+        let mut shared = self.0.borrow_mut();
+
+        match shared.mappings.get_mut(key) {
+            Some(v) => Some(std::mem::replace(v, value)),
+            None => shared.outer.as_ref()?.rebind(key, value),
+        }
+    }
+
+    pub(crate) fn id(&self) -> usize {
+        Rc::as_ptr(&self.0) as usize
+    }
+
+    pub(crate) fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.0)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.borrow().mappings.len()
+    }
+
+    /// Calls `f` with the outer environment and every bound value.
+    pub(crate) fn trace(&self, f: &mut dyn FnMut(Edge)) {
+        let shared = self.0.borrow();
+
+        if let Some(ref outer) = shared.outer {
+            f(Edge::Env(outer));
+        }
+
+        shared.mappings.values().for_each(|v| f(Edge::Value(v)));
+    }
+
+    /// Drops every binding and the outer environment, breaking the cycles
+    /// this environment is part of.
+    pub(crate) fn clear(&self) {
+        let shared = std::mem::replace(&mut *self.0.borrow_mut(), SharedEnv {
+            mappings: HashMap::new(),
+            outer: None,
+        });
+
+        drop(shared);
+    }
 }
@@ -0,0 +1,408 @@
+use itertools::Itertools;
+
+use crate::env::Env;
+use crate::limits;
+use crate::sandbox;
+use crate::types::*;
+
+pub fn eval_ast(value: MValue, env: &Env) -> Result<MValue> {
+    if value.is_symbol() {
+        let x = value.cast_to_string()?;
+        env.get(&x)
+           .ok_or_else(|| sandbox::unbound(x))
+    } else if value.is_list() {
+        value.cast_to_list()?.into_iter()
+           .map(|x| eval(x, &env))
+           .collect::<Result<_>>()
+           .map(MValue::list)
+    } else if value.is_hashmap() {
+        value.cast_to_hashmap()?.into_iter()
+           .map(|(k, v)| eval(v, &env).map(|v| (k,v)) )
+           .collect::<Result<_>>()
+           .map(MValue::from_hashmap)
+    } else if value.is_vector() {
+        value.cast_to_list()?.into_iter()
+           .map(|x| eval(x, &env))
+           .collect::<Result<_>>()
+           .map(MValue::vector)
+    } else if value.is_set() {
+        value.cast_to_list()?.into_iter()
+           .map(|x| eval(x, env))
+           .collect::<Result<_>>()
+           .and_then(MValue::set)
+    } else {
+        Ok(value)
+   }
+}
+
+pub fn eval(input: MValue, env: &Env) -> Result<MValue> {
+    let mut env = env.clone();
+    let mut input = input.clone();
+
+    loop {
+        limits::step()?;
+
+        if !input.is_list() {
+            return eval_ast(input, &env);
+        }
+
+        input = macro_expand(input, &env)?;
+
+        if !input.is_list() {
+            return eval_ast(input, &env);
+        }
+
+        let mut l = input.clone().cast_to_list()?;
+
+        if l.is_empty() {
+            return Ok(MValue::list(l));
+        }
+
+        match *l[0].0 {
+            MalVal::Sym(ref sym) if sym == "do" => {
+                input = l
+                    .pop()
+                    .ok_or_else(|| Error::EvalError(
+                            "No argument was provided".to_string()))?;
+
+                let v = MValue::list(l[1..].to_vec());
+                eval_ast(v, &env)?;
+            },
+
+            MalVal::Sym(ref sym) if sym == "if" => {
+                let condition = eval(l[1].clone(), &env)?;
+                match *condition.0 {
+                    MalVal::Bool(false) | MalVal::Nil if l.len() >= 4 =>
+                        input = l[3].clone(),
+                    MalVal::Bool(false) | MalVal::Nil =>
+                        return Ok(MValue::nil()),
+                    _ =>
+                        input = l[2].clone(),
+                }
+            },
+
+            MalVal::Sym(ref sym) if sym == "macroexpand" => {
+                return macro_expand(l[1].clone(), &env);
+            },
+
+            MalVal::Sym(ref sym) if sym == "var" => {
+                let x = l[1].cast_to_string()?;
+                return env.get(&x).ok_or(Error::NoSymbolFound(x));
+            },
+
+            MalVal::Sym(ref sym) if sym == "quote" => {
+                return Ok(l[1].clone());
+            },
+
+            MalVal::Sym(ref sym) if sym == "quasiquote" => {
+                input = quasiquote(l[1].clone())?;
+            },
+
+            MalVal::Sym(ref sym) if sym == "fn*" => {
+                let parameters = l[1].clone()
+                    .cast_to_list()?
+                    .iter()
+                    .flat_map(MValue::cast_to_string)
+                    .collect::<Vec<String>>();
+
+                let body = l[2].clone();
+
+                return Ok(MValue::lambda(env.clone(), parameters, body));
+            },
+
+            MalVal::Sym(ref sym) if sym == "lazy-seq" => {
+                let mut body = l.clone();
+                body[0] = MValue::symbol("do");
+
+                let body = MValue::list(body);
+                let env = env.clone();
+
+                return Ok(MValue::lazy_seq(move || eval(body.clone(), &env)));
+            },
+
+            MalVal::Sym(ref sym) if sym == "def!" => {
+                return define(&input, &l, &env, false);
+            },
+
+            MalVal::Sym(ref sym) if sym == "try*" => {
+                let try_expr = l[1].clone();
+
+                let result = eval(try_expr, &env);
+
+                if result.is_ok() || l.len() < 3 || matches!(result, Err(Error::Exit(_))) {
+                    return result;
+                }
+
+                let error = result.unwrap_err();
+                let catch_block = l[2].clone().cast_to_list()?;
+                let err_symbol = catch_block[1].clone().cast_to_string()?;
+                let catch_expr = catch_block[2].clone();
+                limits::grant_grace(&error);
+                env.set(err_symbol, error.catch());
+                return eval(catch_expr, &env);
+            },
+
+            MalVal::Sym(ref sym) if sym == "defmacro!" => {
+                return define(&input, &l, &env, true);
+            },
+
+            MalVal::Sym(ref sym) if sym == "let*" => {
+                env = Env::new(Some(env.clone()));
+
+                let binds = l[1].clone().cast_to_list()?;
+
+                for (bind, expr) in binds.clone().into_iter().tuples() {
+                    let bind = bind.cast_to_string()?;
+                    let v = eval(expr, &env)?;
+
+                    env.set(bind, v);
+                }
+
+                input = l[2].clone();
+            },
+
+            MalVal::Sym(ref sym) if sym == "binding" => {
+                return binding(&l, &env);
+            },
+
+            MalVal::Sym(ref sym) if sym == "with-open" => {
+                return with_open(&l, &env);
+            },
+
+            _ => {
+                let evaluated_list = eval_ast(MValue::list(l), &env)?.cast_to_list()?;
+
+                // Calling a lambda in tail position continues this loop, so
+                // tail recursion runs in constant stack.
+                match *evaluated_list[0].0 {
+                    MalVal::Lambda(ref fun, _) => {
+                        let (body, new_env) = fun.apply(evaluated_list[1..].to_vec())?;
+                        input = body;
+                        env = new_env;
+                    },
+                    _ => return handle_function(&evaluated_list[0], evaluated_list[1..].to_vec()),
+                }
+            },
+        }
+    }
+}
+
+/// `(def! name value)` or `(def! name "docstring" value)`, and likewise
+/// `defmacro!`.
+fn define(form: &MValue, l: &[MValue], env: &Env, is_macro: bool) -> Result<MValue> {
+    let key = l[1].cast_to_string()?;
+
+    let (doc, expr) = match l.len() {
+        4 if l[2].is_string() => (Some(l[2].clone()), l[3].clone()),
+        _ => (None, l[2].clone()),
+    };
+
+    let mut v = eval(expr, env)?;
+    if is_macro {
+        v.set_macro();
+    }
+
+    let v = describe(v, &key, doc, form, env)?;
+    env.set(key, v.clone());
+    Ok(v)
+}
+
+/// Adds what is known about a definition to the value's metadata: a
+/// function made for it gets its `:name`, `:arglists` and where it was
+/// read from, and any value that can hold metadata its `:doc`.
+fn describe(value: MValue, name: &str, doc: Option<MValue>, form: &MValue, env: &Env) -> Result<MValue> {
+    let mut entries = vec![];
+
+    if let MalVal::Lambda(ref closure, ref meta) = *value.0 {
+        if meta.is_nil() {
+            let parameters = closure.parameters().iter().map(MValue::symbol).collect();
+
+            entries.push(MValue::keyword("name"));
+            entries.push(MValue::symbol(name));
+            entries.push(MValue::keyword("arglists"));
+            entries.push(MValue::list(vec![MValue::vector(parameters)]));
+
+            if let Some(file) = env.get("*file*").filter(MValue::is_string) {
+                entries.push(MValue::keyword("file"));
+                entries.push(file);
+            }
+
+            if let Ok(position) = form.meta().and_then(|meta| meta.cast_to_hashmap()) {
+                for (k, v) in position {
+                    entries.push(MValue::reconstruct(&k)?);
+                    entries.push(v);
+                }
+            }
+        }
+    }
+
+    if let Some(doc) = doc {
+        entries.push(MValue::keyword("doc"));
+        entries.push(doc);
+    }
+
+    if entries.is_empty() {
+        return Ok(value);
+    }
+
+    let meta = match value.meta()? {
+        meta if meta.is_nil() => MValue::hashmap(&mut vec![]),
+        meta => meta,
+    };
+
+    value.with_meta(meta.hassoc(&mut entries)?)
+}
+
+fn is_nonempty_list(value: &MValue) -> bool {
+    (value.is_list() || value.is_vector()) && !value.clone().cast_to_list().unwrap().is_empty()
+}
+
+fn quasiquote(value: MValue) -> Result<MValue> {
+    if !is_nonempty_list(&value) {
+        return Ok(MValue::list(vec![MValue::symbol("quote"), value.clone()]));
+    }
+
+    let ast = value.clone().cast_to_list()?;
+
+    if let MalVal::Sym(ref unquote) = *ast[0].0 {
+        if unquote == "unquote" {
+            return Ok(value.cast_to_list()?[1].clone());
+        }
+    }
+
+    let rest = MValue::list(ast[1..].to_vec());
+
+    if is_nonempty_list(&ast[0]) {
+        let m1 = ast[0].clone().cast_to_list()?;
+        if let MalVal::Sym(ref splice_unquote) = *m1[0].0 {
+            if splice_unquote == "splice-unquote" {
+                return Ok(MValue::list(
+                        vec![MValue::symbol("concat"),
+                        m1[1].clone(),
+                        quasiquote(rest)?]));
+            }
+        }
+    }
+
+    Ok(MValue::list(vec![
+                    MValue::symbol("cons"),
+                    quasiquote(ast[0].clone())?,
+                    quasiquote(rest)?]))
+}
+
+fn body(l: &[MValue]) -> MValue {
+    let mut body = vec![MValue::symbol("do")];
+    body.extend_from_slice(&l[2..]);
+    MValue::list(body)
+}
+
+/// `(binding (var value ...) body...)` gives defined variables new values
+/// while the body runs, which everything it calls sees, and restores them
+/// however it ends.
+fn binding(l: &[MValue], env: &Env) -> Result<MValue> {
+    let binds = l.get(1)
+        .ok_or_else(|| Error::EvalError("binding needs a list of bindings".to_string()))?
+        .cast_to_list()?;
+
+    let mut values = vec![];
+    for (var, expr) in binds.into_iter().tuples() {
+        values.push((var.cast_to_string()?, eval(expr, env)?));
+    }
+
+    let mut saved = vec![];
+    for (var, value) in values {
+        match env.rebind(&var, value) {
+            Some(old) => saved.push((var, old)),
+            None => {
+                restore(env, saved);
+                return Err(Error::EvalError(format!("Can't bind {}, which isn't defined", var)));
+            },
+        }
+    }
+
+    let result = eval(body(l), env);
+    restore(env, saved);
+    result
+}
+
+fn restore(env: &Env, saved: Vec<(String, MValue)>) {
+    for (var, value) in saved.into_iter().rev() {
+        env.rebind(&var, value);
+    }
+}
+
+/// `(with-open (name port ...) body...)` binds ports like `let*` and
+/// closes them, last first, however the body ends.
+fn with_open(l: &[MValue], env: &Env) -> Result<MValue> {
+    let binds = l.get(1)
+        .ok_or_else(|| Error::EvalError("with-open needs a list of bindings".to_string()))?
+        .cast_to_list()?;
+
+    let env = Env::new(Some(env.clone()));
+    let mut ports = vec![];
+
+    let mut result = Ok(MValue::nil());
+    for (name, expr) in binds.into_iter().tuples() {
+        result = name.cast_to_string().and_then(|name| {
+            let port = eval(expr, &env)?;
+            port.cast_to_port()?;
+            env.set(name, port.clone());
+            ports.push(port);
+            Ok(MValue::nil())
+        });
+
+        if result.is_err() {
+            break;
+        }
+    }
+
+    if result.is_ok() {
+        result = eval(body(l), &env);
+    }
+
+    for port in ports.iter().rev() {
+        let closed = port.cast_to_port().and_then(|p| p.close());
+        if result.is_ok() {
+            result = closed.and(result);
+        }
+    }
+
+    result
+}
+
+pub fn handle_function(function: &MValue, args: Vec<MValue>) -> Result<MValue> {
+    match *function.0 {
+        MalVal::Fun(fun, ref env, _) => {
+            // Builtins can build large values without going through `eval`.
+            fun(args, env.clone()).and_then(|v| limits::check().map(|_| v))
+        },
+
+        MalVal::Lambda(ref fun, _) => {
+            let (body, new_env) = fun.apply(args)?;
+            eval(body, &new_env)
+        },
+
+        _ => Err(Error::EvalError(format!("No function provided: {:?}", *function.0)))
+    }
+}
+
+pub fn macro_expand(value: MValue, env: &Env) -> Result<MValue> {
+    let position = value.meta().unwrap_or_else(|_| MValue::nil());
+    let mut value = value;
+    let mut expanded = false;
+
+    while value.is_macro_call(&env) {
+        let list = value.clone().cast_to_list()?;
+        let lambda = env.get(&list[0].cast_to_string()?).unwrap();
+        value = handle_function(&lambda, list[1..].to_vec())?;
+        expanded = true;
+    }
+
+    // The expansion is where the macro call was, as far as the definitions
+    // it makes are concerned.
+    if expanded && value.is_list() && !position.is_nil() && value.meta()?.is_nil() {
+        value = value.with_meta(position)?;
+    }
+
+    Ok(value)
+}
@@ -0,0 +1,366 @@
+//! Builtins for the file system.
+//!
+//! Paths go through the sandbox of the environment the builtins were made
+//! for, and failures are reported as `Error::IoError` with the path.
+
+use crate::core::assert_min_args;
+use crate::env::Env;
+use crate::sandbox::resolve_path;
+use crate::sequences::unfold;
+use crate::types::*;
+
+use regex::Regex;
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+fn path_arg(args: &[MValue], i: usize, env: &Option<Env>) -> Result<PathBuf> {
+    resolve_path(env, &args[i].cast_to_string()?)
+}
+
+fn path_string(path: &Path) -> MValue {
+    MValue::string(path.display())
+}
+
+/// Whether trailing keyword options such as `:append true` set `name`.
+fn option(args: &[MValue], name: &str) -> bool {
+    args.chunks(2)
+        .any(|pair| pair.len() == 2 && pair[0] == MValue::keyword(name) && pair[1].is_truthy())
+}
+
+/// `(spit path content)` writes `content`, as `str` would print it, to the
+/// file. With `:append true` it is added to the end instead.
+pub fn spit(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let path = path_arg(&args, 0, &env)?;
+    let content = args[1].pr_str(false);
+
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(option(&args[2..], "append"))
+        .truncate(!option(&args[2..], "append"))
+        .open(&path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|e| Error::io(&path, e))?;
+
+    Ok(MValue::nil())
+}
+
+/// Like `resolve_path`, but a path that can't be found is `None` rather
+/// than an error.
+fn existing_path(args: &[MValue], env: &Option<Env>) -> Result<Option<PathBuf>> {
+    match path_arg(args, 0, env) {
+        Ok(path) => Ok(Some(path).filter(|path| path.exists())),
+        Err(Error::IoError(ref failure)) if failure.kind == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn file_exists_q(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::bool(existing_path(&args, &env)?.is_some()))
+}
+
+pub fn directory_q(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::bool(existing_path(&args, &env)?.is_some_and(|path| path.is_dir())))
+}
+
+/// `(list-dir path)` is the sorted names of the entries in a directory.
+pub fn list_dir(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let path = path_arg(&args, 0, &env)?;
+    let mut names = fs::read_dir(&path)
+        .and_then(|entries| entries
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<std::io::Result<Vec<String>>>())
+        .map_err(|e| Error::io(&path, e))?;
+
+    names.sort();
+    Ok(MValue::list(names.into_iter().map(MValue::string).collect()))
+}
+
+/// `(mkdir path)` creates a directory and any missing parents.
+pub fn mkdir(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let path = path_arg(&args, 0, &env)?;
+    fs::create_dir_all(&path).map_err(|e| Error::io(&path, e))?;
+
+    Ok(MValue::nil())
+}
+
+/// `(delete-file path)` deletes a file or an empty directory.
+pub fn delete_file(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let path = path_arg(&args, 0, &env)?;
+    let deleted = if path.is_dir() { fs::remove_dir(&path) } else { fs::remove_file(&path) };
+    deleted.map_err(|e| Error::io(&path, e))?;
+
+    Ok(MValue::nil())
+}
+
+/// `(rename-file from to)`
+pub fn rename_file(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let from = path_arg(&args, 0, &env)?;
+    let to = path_arg(&args, 1, &env)?;
+    fs::rename(&from, &to).map_err(|e| Error::io(&from, e))?;
+
+    Ok(MValue::nil())
+}
+
+fn metadata(args: &[MValue], env: &Option<Env>) -> Result<fs::Metadata> {
+    let path = path_arg(args, 0, env)?;
+    fs::metadata(&path).map_err(|e| Error::io(&path, e))
+}
+
+/// `(file-size path)` in bytes.
+pub fn file_size(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::integer(i64::try_from(metadata(&args, &env)?.len())?))
+}
+
+/// `(file-mtime path)` is when the file was last modified, in milliseconds
+/// since the epoch like `time-ms`.
+pub fn file_mtime(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let path = path_arg(&args, 0, &env)?;
+    let millis = fs::metadata(&path)
+        .and_then(|m| m.modified())
+        .map_err(|e| Error::io(&path, e))?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+
+    Ok(MValue::integer(i64::try_from(millis)?))
+}
+
+/// Translates one component of a glob, where `*` matches any characters
+/// and `?` any one character.
+fn component_regex(component: &str) -> Result<Regex> {
+    let mut pattern = String::from("^");
+
+    for c in component.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    pattern.push('$');
+    Regex::new(&pattern).map_err(|e| Error::EvalError(e.to_string()))
+}
+
+fn is_wildcard(component: &str) -> bool {
+    component.contains('*') || component.contains('?')
+}
+
+fn glob_from(dir: &Path, components: &[&str], matches: &mut Vec<PathBuf>) -> Result<()> {
+    let (component, rest) = match components.split_first() {
+        Some(split) => split,
+        None => {
+            matches.push(dir.to_path_buf());
+            return Ok(());
+        },
+    };
+
+    if !is_wildcard(component) {
+        let path = dir.join(component);
+        if path.exists() {
+            glob_from(&path, rest, matches)?;
+        }
+        return Ok(());
+    }
+
+    let mut entries = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect::<Vec<PathBuf>>(),
+        Err(_) => return Ok(()),
+    };
+    entries.sort();
+
+    if *component == "**" {
+        // Zero directories, or one more and `**` again.
+        glob_from(dir, rest, matches)?;
+
+        let hidden = |e: &PathBuf| e.file_name().is_some_and(|n| n.to_string_lossy().starts_with('.'));
+
+        // Links to directories aren't followed, since one that leads back
+        // up would recurse forever.
+        let directory = |e: &PathBuf| fs::symlink_metadata(e).is_ok_and(|m| m.is_dir());
+
+        for entry in entries.iter().filter(|e| directory(e) && !hidden(e)) {
+            glob_from(entry, components, matches)?;
+        }
+
+        return Ok(());
+    }
+
+    let regex = component_regex(component)?;
+
+    for entry in entries {
+        let name = entry.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+
+        // As in shells, wildcards don't match hidden files.
+        let hidden = name.starts_with('.') && !component.starts_with('.');
+
+        if regex.is_match(&name) && !hidden {
+            glob_from(&entry, rest, matches)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `(glob pattern)` is the sorted paths matching a pattern, where `*` and
+/// `?` match within a name and `**` matches any number of directories.
+/// Matches outside the allowed paths are left out.
+pub fn glob(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let pattern = args[0].cast_to_string()?;
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+
+    let base = if pattern.starts_with('/') { PathBuf::from("/") } else { PathBuf::from(".") };
+    let mut matches = vec![];
+    glob_from(&base, &components, &mut matches)?;
+
+    let mut result = vec![];
+
+    for path in matches {
+        let path = path.strip_prefix("./").map(Path::to_path_buf).unwrap_or(path);
+
+        if resolve_path(&env, &path.display().to_string()).is_ok() {
+            result.push(path_string(&path));
+        }
+    }
+
+    Ok(MValue::list(result))
+}
+
+/// `(temp-file)` or `(temp-file prefix)` creates an empty file with a new
+/// name in the temporary directory and returns its path.
+pub fn temp_file(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let prefix = match args.first() {
+        Some(prefix) => prefix.cast_to_string()?,
+        None => "mal".to_string(),
+    };
+
+    let dir = std::env::temp_dir();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+
+    for attempt in 0..100u32 {
+        let name = format!("{}-{}-{}-{}", prefix, std::process::id(), nanos, attempt);
+        let path = resolve_path(&env, &dir.join(name).display().to_string())?;
+
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(path_string(&path)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(Error::io(&path, e)),
+        }
+    }
+
+    Err(Error::EvalError("Could not find an unused name for a temporary file".to_string()))
+}
+
+/// `(read-lines path)` is a lazy sequence of the lines of a file, read as
+/// they are needed.
+pub fn read_lines(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let path = path_arg(&args, 0, &env)?;
+    let file = File::open(&path).map_err(|e| Error::io(&path, e))?;
+    let reader = Rc::new(RefCell::new(BufReader::new(file)));
+
+    Ok(unfold((), Rc::new(move |_| {
+        let mut line = String::new();
+        let read = reader.borrow_mut().read_line(&mut line).map_err(|e| Error::io(&path, e))?;
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+
+        Ok(Some((MValue::string(line), ())))
+    })))
+}
+
+/// `(read-bytes path)` or `(read-bytes path offset length)` is a vector of
+/// the bytes of a file, or of `length` of them from `offset` on.
+pub fn read_bytes(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let path = path_arg(&args, 0, &env)?;
+    let mut file = File::open(&path).map_err(|e| Error::io(&path, e))?;
+    let mut bytes = vec![];
+
+    let read = match (args.get(1), args.get(2)) {
+        (Some(offset), Some(length)) => {
+            let offset = u64::try_from(offset.cast_to_int()?)?;
+            let length = u64::try_from(length.cast_to_int()?)?;
+
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.take(length).read_to_end(&mut bytes))
+        },
+        _ => file.read_to_end(&mut bytes),
+    };
+    read.map_err(|e| Error::io(&path, e))?;
+
+    Ok(MValue::vector(bytes.into_iter().map(|b| MValue::integer(i64::from(b))).collect()))
+}
+
+#[test]
+fn test_files() {
+    let dir = std::env::temp_dir().join(format!("mal-files-{}", std::process::id()));
+    let path = |name: &str| MValue::string(dir.join(name).display());
+
+    mkdir(vec![path("a/b")], None).unwrap();
+    spit(vec![path("a/b/x.txt"), MValue::string("one\n")], None).unwrap();
+    spit(vec![path("a/b/x.txt"), MValue::string("two"), MValue::keyword("append"), MValue::bool(true)], None).unwrap();
+
+    let lines = read_lines(vec![path("a/b/x.txt")], None).unwrap();
+    assert_eq!(lines.pr_str(true), "(\"one\" \"two\")");
+
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(".", dir.join("a/loop")).unwrap();
+
+    let found = glob(vec![MValue::string(format!("{}/**/*.txt", dir.display()))], None).unwrap();
+    assert_eq!(found, MValue::list(vec![path("a/b/x.txt")]));
+
+    match file_size(vec![path("missing")], None) {
+        Err(Error::IoError(failure)) => {
+            assert_eq!(failure.kind, std::io::ErrorKind::NotFound);
+            assert_eq!(failure.path, Some(dir.join("missing").display().to_string()));
+        },
+        r => panic!("expected an IO error, got {:?}", r),
+    }
+
+    // A relative path that doesn't exist yet is created where it says.
+    let relative = format!("mal-files-{}.txt", std::process::id());
+    spit(vec![MValue::string(&relative), MValue::string("x")], None).unwrap();
+    assert_eq!(fs::read_to_string(&relative).unwrap(), "x");
+    fs::remove_file(&relative).unwrap();
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,226 @@
+//! The directive language of `format` and `printf`.
+//!
+//! A directive is `%[index$][flags][width][.precision]conversion` where the
+//! flags are `-` (align left), `0` (pad with zeros), `+` (always print the
+//! sign) and ` ` (a space in place of `+`), and the conversions are `s`
+//! (as `str` prints it), `d`, `f`, `e`, `x`, `X`, `o`, `b` and `c`. `%%` and
+//! `%n` produce a percent sign and a newline. Indices, widths and
+//! precisions are at most 10000.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::types::*;
+
+#[derive(Debug, Default)]
+struct Directive {
+    index: Option<usize>,
+    left: bool,
+    zero: bool,
+    plus: bool,
+    space: bool,
+    width: Option<usize>,
+    precision: Option<usize>,
+    conversion: char,
+}
+
+pub fn format(template: &str, args: &[MValue]) -> Result<String> {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    let mut next_arg = 0;
+    let mut used = vec![false; args.len()];
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let directive = parse_directive(&mut chars)?;
+
+        match directive.conversion {
+            '%' => out.push('%'),
+            'n' => out.push('\n'),
+            _ => {
+                let i = match directive.index {
+                    Some(i) => i,
+                    None => {
+                        next_arg += 1;
+                        next_arg
+                    },
+                };
+
+                let arg = args.get(i - 1).ok_or_else(|| Error::EvalError(format!(
+                    "format: missing argument {} for %{}", i, directive.conversion)))?;
+                used[i - 1] = true;
+
+                out.push_str(&apply(&directive, arg)?);
+            },
+        }
+    }
+
+    if let Some(unused) = used.iter().position(|u| !u) {
+        return Err(Error::EvalError(format!(
+            "format: {} argument(s) given but argument {} is never used", args.len(), unused + 1)));
+    }
+
+    Ok(out)
+}
+
+/// The largest index, width or precision a directive may have, so that a
+/// typo like `%1000000000d` is an error rather than a huge allocation.
+const MAX_NUMBER: usize = 10_000;
+
+fn parse_number<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) -> Result<Option<usize>> {
+    let mut n = None;
+
+    while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+        n = n.unwrap_or(0usize).checked_mul(10)
+            .and_then(|n| n.checked_add(d as usize))
+            .filter(|&n| n <= MAX_NUMBER);
+
+        if n.is_none() {
+            return Err(Error::EvalError(format!(
+                "format: numbers in directives can be at most {}", MAX_NUMBER)));
+        }
+        chars.next();
+    }
+
+    Ok(n)
+}
+
+fn parse_directive<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) -> Result<Directive> {
+    let mut directive = Directive::default();
+
+    loop {
+        match chars.peek() {
+            Some('-') => directive.left = true,
+            Some('0') => directive.zero = true,
+            Some('+') => directive.plus = true,
+            Some(' ') => directive.space = true,
+            _ => break,
+        }
+        chars.next();
+    }
+
+    directive.width = parse_number(chars)?;
+
+    if chars.peek() == Some(&'$') {
+        chars.next();
+        directive.index = match directive.width.take() {
+            Some(0) | None => return Err(Error::EvalError(
+                "format: argument indices start at 1".to_string())),
+            index => index,
+        };
+
+        return parse_directive(chars).map(|d| Directive { index: directive.index, ..d });
+    }
+
+    if chars.peek() == Some(&'.') {
+        chars.next();
+        directive.precision = Some(parse_number(chars)?.unwrap_or(0));
+    }
+
+    directive.conversion = match chars.next() {
+        Some(c) if "sdfexXobc%n".contains(c) => c,
+        Some(c) => return Err(Error::EvalError(format!("format: unknown directive %{}", c))),
+        None => return Err(Error::EvalError("format: incomplete directive at end".to_string())),
+    };
+
+    Ok(directive)
+}
+
+fn apply(directive: &Directive, arg: &MValue) -> Result<String> {
+    let mismatch = || Error::EvalError(format!(
+        "format: %{} can't format {}", directive.conversion, arg.pr_str(true)));
+
+    let (sign, body) = match directive.conversion {
+        's' => {
+            let s = arg.pr_str(false);
+            let s = match directive.precision {
+                Some(p) => s.graphemes(true).take(p).collect(),
+                None => s,
+            };
+            return Ok(pad(directive, "", &s, false));
+        },
+        'c' => {
+            let s = arg.cast_to_string().map_err(|_| mismatch())?;
+            if s.graphemes(true).count() != 1 {
+                return Err(mismatch());
+            }
+            return Ok(pad(directive, "", &s, false));
+        },
+        'd' | 'x' | 'X' | 'o' | 'b' => {
+            let n = match *arg.0 {
+                MalVal::Int(n) => n,
+                _ => return Err(mismatch()),
+            };
+            let magnitude = n.unsigned_abs();
+            let body = match directive.conversion {
+                'x' => format!("{:x}", magnitude),
+                'X' => format!("{:X}", magnitude),
+                'o' => format!("{:o}", magnitude),
+                'b' => format!("{:b}", magnitude),
+                _ => magnitude.to_string(),
+            };
+            (sign(directive, n < 0), body)
+        },
+        'f' | 'e' => {
+            let f = arg.cast_to_float().map_err(|_| mismatch())?;
+            let precision = directive.precision.unwrap_or(6);
+            let body = if directive.conversion == 'f' {
+                format!("{:.*}", precision, f.abs())
+            } else {
+                format!("{:.*e}", precision, f.abs())
+            };
+            (sign(directive, f.is_sign_negative() && f != 0.0), body)
+        },
+        _ => unreachable!("conversion was validated when parsing"),
+    };
+
+    Ok(pad(directive, &sign, &body, true))
+}
+
+fn sign(directive: &Directive, negative: bool) -> String {
+    if negative {
+        "-".to_string()
+    } else if directive.plus {
+        "+".to_string()
+    } else if directive.space {
+        " ".to_string()
+    } else {
+        String::new()
+    }
+}
+
+fn pad(directive: &Directive, sign: &str, body: &str, numeric: bool) -> String {
+    let len = sign.len() + body.graphemes(true).count();
+    let missing = directive.width.unwrap_or(0).saturating_sub(len);
+
+    if directive.left {
+        format!("{}{}{}", sign, body, " ".repeat(missing))
+    } else if directive.zero && numeric {
+        format!("{}{}{}", sign, "0".repeat(missing), body)
+    } else {
+        format!("{}{}{}", " ".repeat(missing), sign, body)
+    }
+}
+
+#[test]
+fn test_format() {
+    let args = vec![
+        MValue::string("ab"), MValue::integer(-42), MValue::float(1.255), MValue::integer(255)];
+
+    assert_eq!(
+        format("[%-4s|%5d|%08.2f|%x|%2$+d|%%]", &args).unwrap(),
+        "[ab  |  -42|00001.25|ff|-42|%]");
+    assert_eq!(format("%s %s", &args[..1]).unwrap_err().to_string(),
+               "Eval error: format: missing argument 2 for %s");
+    assert!(format("%d", &args[..1]).is_err());
+    assert!(format("%s", &args[..2]).is_err());
+
+    assert_eq!(format("%99999999999999999999d", &args[1..2]).unwrap_err().to_string(),
+               "Eval error: format: numbers in directives can be at most 10000");
+    assert!(format("%1000000000d", &args[1..2]).is_err());
+    assert!(format("%.10001f", &args[2..3]).is_err());
+    assert_eq!(format("%10000d", &args[1..2]).unwrap().len(), 10000);
+}
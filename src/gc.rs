@@ -0,0 +1,303 @@
+//! A cycle collector for environments.
+//!
+//! Values and environments are reference counted, and a function defined in
+//! an environment is stored in the very environment it closes over, so the
+//! two keep each other alive forever. Every environment is registered here,
+//! and `collect` finds the ones only kept alive by such cycles by trial
+//! deletion: it walks everything reachable from the registered environments
+//! and counts the references it finds along the way. Whatever has more
+//! references than that is held from outside, by the interpreter or an
+//! embedding program, and so is everything reachable from it. The rest is
+//! garbage, and clearing its environments, atoms and lazy sequences breaks
+//! the cycles so that reference counting frees them.
+
+use crate::env::{Env, WeakEnv};
+use crate::limits;
+use crate::types::*;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// A reference from an environment or a value to another.
+pub(crate) enum Edge<'a> {
+    Env(&'a Env),
+    Value(&'a MValue),
+}
+
+#[derive(Default)]
+struct Registry {
+    envs: Vec<WeakEnv>,
+    /// How many environments were alive after the registry was last pruned.
+    pruned: usize,
+    collections: usize,
+    collected: usize,
+}
+
+impl Registry {
+    /// Forgets environments that were freed. Done whenever the registry has
+    /// doubled in size, which keeps registering cheap on average.
+    fn prune(&mut self) {
+        self.envs.retain(|env| env.strong_count() > 0);
+        self.pruned = self.envs.len();
+    }
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+}
+
+pub(crate) fn register(env: WeakEnv) {
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        registry.envs.push(env);
+
+        if registry.envs.len() >= 2 * registry.pruned.max(1024) {
+            registry.prune();
+        }
+    });
+}
+
+enum Object {
+    Env(Env),
+    Value(MValue),
+}
+
+struct Node {
+    object: Object,
+    /// The reference count when first reached.
+    strong: usize,
+    /// How many of those references were found during the walk.
+    internal: usize,
+    children: Vec<usize>,
+}
+
+fn trace_value(value: &MValue, f: &mut dyn FnMut(Edge)) {
+    use MalVal::*;
+
+    match *value.0 {
+        List(ref l, ref meta) | Vector(ref l, ref meta) => {
+            l.iter().for_each(|v| f(Edge::Value(v)));
+            f(Edge::Value(meta));
+        },
+        HashMap(ref m, ref meta) | Set(ref m, ref meta) => {
+            m.values().for_each(|v| f(Edge::Value(v)));
+            f(Edge::Value(meta));
+        },
+        SortedMap(ref s, ref meta) | SortedSet(ref s, ref meta) => {
+            for (k, v) in s.iter() {
+                f(Edge::Value(k));
+                f(Edge::Value(v));
+            }
+            if let Some(comparator) = s.comparator() {
+                f(Edge::Value(comparator));
+            }
+            f(Edge::Value(meta));
+        },
+        Fun(_, ref env, ref meta) => {
+            if let Some(env) = env {
+                f(Edge::Env(env));
+            }
+            f(Edge::Value(meta));
+        },
+        Lambda(ref closure, ref meta) => {
+            f(Edge::Env(closure.env()));
+            f(Edge::Value(closure.body()));
+            f(Edge::Value(meta));
+        },
+        Atom(ref v) => f(Edge::Value(&v.borrow())),
+        LazySeq(ref lazy) => lazy.trace(&mut |v| f(Edge::Value(v))),
+        Reduced(ref v) | Tagged(_, ref v) => f(Edge::Value(v)),
+        Int(_) | Float(_) | Bool(_) | Sym(_) | Str(_) | Keyword(_) | Regex(_) | Port(_) | Nil => {},
+    }
+}
+
+/// Adds what `edge` refers to to the graph, unless it is there already,
+/// and counts the reference. Reference counts are read before the object is
+/// cloned into the graph so that they only count references from outside.
+fn visit(graph: &mut HashMap<usize, Node>, pending: &mut Vec<usize>, edge: Edge) -> usize {
+    let (id, strong, object) = match edge {
+        Edge::Env(env) => (env.id(), env.strong_count(), Object::Env(env.clone())),
+        Edge::Value(value) => (Rc::as_ptr(&value.0) as usize, Rc::strong_count(&value.0), Object::Value(value.clone())),
+    };
+
+    graph.entry(id)
+        .or_insert_with(|| {
+            pending.push(id);
+            Node { object, strong, internal: 0, children: vec![] }
+        })
+        .internal += 1;
+
+    id
+}
+
+/// What `collect` found.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Collection {
+    /// Environments that were only kept alive by cycles.
+    pub collected: usize,
+    /// Environments that are still in use.
+    pub live: usize,
+}
+
+/// Frees the environments, and the values they bind, that are only kept
+/// alive by reference cycles.
+pub fn collect() -> Collection {
+    let weak: Vec<WeakEnv> = REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        registry.prune();
+        std::mem::take(&mut registry.envs)
+    });
+
+    let mut graph: HashMap<usize, Node> = HashMap::new();
+    let mut pending = vec![];
+
+    for env in weak.iter() {
+        let strong = env.strong_count();
+
+        if let Some(env) = env.upgrade() {
+            let id = env.id();
+            graph.entry(id).or_insert_with(|| {
+                pending.push(id);
+                Node { object: Object::Env(env), strong, internal: 0, children: vec![] }
+            });
+        }
+    }
+
+    while let Some(id) = pending.pop() {
+        let object = match graph[&id].object {
+            Object::Env(ref env) => Object::Env(env.clone()),
+            Object::Value(ref value) => Object::Value(value.clone()),
+        };
+
+        let mut children = vec![];
+        let mut reach = |edge: Edge| children.push(visit(&mut graph, &mut pending, edge));
+
+        match object {
+            Object::Env(ref env) => env.trace(&mut reach),
+            Object::Value(ref value) => trace_value(value, &mut reach),
+        }
+
+        if let Some(node) = graph.get_mut(&id) {
+            node.children = children;
+        }
+    }
+
+    // Anything referenced from outside the graph is in use, and so is
+    // everything it refers to.
+    let mut live: Vec<usize> = graph.iter()
+        .filter(|(_, node)| node.strong > node.internal)
+        .map(|(id, _)| *id)
+        .collect();
+    let mut marked: HashSet<usize> = live.iter().cloned().collect();
+
+    while let Some(id) = live.pop() {
+        for child in graph[&id].children.iter() {
+            if marked.insert(*child) {
+                live.push(*child);
+            }
+        }
+    }
+
+    let mut result = Collection::default();
+
+    for (id, node) in graph.iter() {
+        match node.object {
+            Object::Env(_) if marked.contains(id) => result.live += 1,
+            Object::Env(ref env) => {
+                env.clear();
+                result.collected += 1;
+            },
+            Object::Value(ref value) if !marked.contains(id) => match *value.0 {
+                MalVal::Atom(ref v) => drop(v.replace(MValue::nil())),
+                MalVal::LazySeq(ref lazy) => lazy.clear(),
+                _ => {},
+            },
+            Object::Value(_) => {},
+        }
+    }
+
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        registry.envs.extend(weak);
+        registry.prune();
+        registry.collections += 1;
+        registry.collected += result.collected;
+    });
+
+    result
+}
+
+/// Memory use as seen by the collector.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Environments that have not been freed, including garbage that has
+    /// not been collected yet.
+    pub envs: usize,
+    /// Values bound in those environments.
+    pub bindings: usize,
+    pub collections: usize,
+    /// Environments freed by all collections so far.
+    pub collected: usize,
+}
+
+pub fn stats() -> Stats {
+    REGISTRY.with(|registry| {
+        let registry = registry.borrow();
+        let envs: Vec<Env> = registry.envs.iter().filter_map(WeakEnv::upgrade).collect();
+
+        Stats {
+            envs: envs.len(),
+            bindings: envs.iter().map(Env::len).sum(),
+            collections: registry.collections,
+            collected: registry.collected,
+        }
+    })
+}
+
+fn stats_map(entries: Vec<(&str, usize)>) -> Result<MValue> {
+    let mut kvs = entries.into_iter()
+        .flat_map(|(k, n)| vec![MValue::keyword(k), MValue::integer(n as i64)])
+        .collect();
+
+    Ok(MValue::hashmap(&mut kvs))
+}
+
+/// `(gc)` collects garbage cycles and returns how many environments were
+/// freed and how many are still live.
+pub fn gc(_args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let result = collect();
+    stats_map(vec![("collected", result.collected), ("live", result.live)])
+}
+
+/// `(memory-stats)`, with the bytes used by values now and at their peak.
+pub fn memory_stats(_args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let stats = stats();
+    let usage = limits::memory_usage();
+
+    stats_map(vec![
+        ("envs", stats.envs),
+        ("bindings", stats.bindings),
+        ("collections", stats.collections),
+        ("collected", stats.collected),
+        ("used", usage.used),
+        ("peak", usage.peak),
+    ])
+}
+
+#[test]
+fn test_collect() {
+    use crate::eval::eval;
+    use crate::reader::read_form;
+
+    let root = Env::new(None);
+    let session = Env::new(Some(root.clone()));
+    let code = read_form().parse(b"(def! f (fn* [x] (f x)))").unwrap();
+    eval(code, &session).unwrap();
+
+    assert_eq!(collect(), Collection { collected: 0, live: 2 });
+
+    drop(session);
+    assert_eq!(collect(), Collection { collected: 1, live: 1 });
+    assert_eq!(stats().envs, 1);
+}
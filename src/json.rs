@@ -0,0 +1,522 @@
+use indexmap::IndexMap;
+
+use crate::types::*;
+
+#[derive(Debug, Clone, Default)]
+pub struct JsonOptions {
+    /// Turn object keys into keywords instead of strings.
+    pub keywordize_keys: bool,
+    /// Only accept RFC 8259 JSON: no comments, no trailing commas and no
+    /// duplicate keys.
+    pub strict: bool,
+    /// Indent nested values when writing.
+    pub pretty: bool,
+}
+
+/// How deeply arrays and objects may nest, so deep input is an error rather
+/// than a stack overflow.
+const MAX_DEPTH: usize = 512;
+
+pub fn parse(input: &str, options: &JsonOptions) -> Result<MValue> {
+    let mut parser = JsonParser {
+        input: input.chars().collect(),
+        pos: 0,
+        depth: 0,
+        options,
+    };
+
+    parser.skip_ignored()?;
+    let value = parser.parse_value()?;
+    parser.skip_ignored()?;
+
+    if parser.pos < parser.input.len() {
+        return Err(parser.error("unexpected trailing characters"));
+    }
+
+    Ok(value)
+}
+
+struct JsonParser<'a> {
+    input: Vec<char>,
+    pos: usize,
+    /// The arrays and objects the parser is inside.
+    depth: usize,
+    options: &'a JsonOptions,
+}
+
+impl<'a> JsonParser<'a> {
+    fn error(&self, message: &str) -> Error {
+        let consumed = &self.input[..self.pos.min(self.input.len())];
+        let line = consumed.iter().filter(|&&c| c == '\n').count() + 1;
+        let column = consumed.iter().rev().take_while(|&&c| c != '\n').count() + 1;
+
+        Error::ParseError(format!("JSON {} at line {}, column {}", message, line, column))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            },
+            Some(c) => Err(self.error(&format!("expected '{}' but found '{}'", expected, c))),
+            None => Err(self.error(&format!("expected '{}' but input ended", expected))),
+        }
+    }
+
+    fn skip_ignored(&mut self) -> Result<()> {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') | Some('\n') | Some('\r') => self.pos += 1,
+                Some('/') if !self.options.strict => self.skip_comment()?,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) -> Result<()> {
+        self.pos += 1;
+
+        match self.next() {
+            Some('/') => {
+                while let Some(c) = self.next() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                Ok(())
+            },
+            Some('*') => loop {
+                match self.next() {
+                    Some('*') if self.peek() == Some('/') => {
+                        self.pos += 1;
+                        return Ok(());
+                    },
+                    Some(_) => {},
+                    None => return Err(self.error("unterminated comment")),
+                }
+            },
+            _ => {
+                self.pos -= 1;
+                Err(self.error("unexpected character '/'"))
+            },
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<MValue> {
+        match self.peek() {
+            Some('{') => self.nested(Self::parse_object),
+            Some('[') => self.nested(Self::parse_array),
+            Some('"') => self.parse_string().map(MValue::string),
+            Some('-') | Some('0'..='9') => self.parse_number(),
+            Some('t') => self.parse_literal("true", MValue::bool(true)),
+            Some('f') => self.parse_literal("false", MValue::bool(false)),
+            Some('n') => self.parse_literal("null", MValue::nil()),
+            Some(c) => Err(self.error(&format!("unexpected character '{}'", c))),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<MValue>) -> Result<MValue> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(&format!("nesting deeper than {}", MAX_DEPTH)));
+        }
+
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_literal(&mut self, literal: &str, value: MValue) -> Result<MValue> {
+        for expected in literal.chars() {
+            if self.peek() != Some(expected) {
+                return Err(self.error(&format!("invalid literal, expected '{}'", literal)));
+            }
+            self.pos += 1;
+        }
+
+        Ok(value)
+    }
+
+    fn parse_array(&mut self) -> Result<MValue> {
+        let mut values = Vec::new();
+
+        self.expect('[')?;
+        self.skip_ignored()?;
+
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(MValue::vector(values));
+        }
+
+        loop {
+            values.push(self.parse_value()?);
+            self.skip_ignored()?;
+
+            match self.next() {
+                Some(',') => {
+                    self.skip_ignored()?;
+                    if self.peek() == Some(']') && !self.options.strict {
+                        self.pos += 1;
+                        return Ok(MValue::vector(values));
+                    }
+                },
+                Some(']') => return Ok(MValue::vector(values)),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("expected ',' or ']' in array"));
+                },
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<MValue> {
+        let mut map = IndexMap::new();
+
+        self.expect('{')?;
+        self.skip_ignored()?;
+
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(MValue::from_hashmap(map));
+        }
+
+        loop {
+            if self.peek() != Some('"') {
+                return Err(self.error("expected string key in object"));
+            }
+
+            let key_pos = self.pos;
+            let key = self.parse_string()?;
+            let key = if self.options.keywordize_keys {
+                MValue::keyword(key)
+            } else {
+                MValue::string(key)
+            };
+
+            self.skip_ignored()?;
+            self.expect(':')?;
+            self.skip_ignored()?;
+
+            let value = self.parse_value()?;
+            let key = (key.cast_to_string()?, key.enum_key());
+
+            if map.insert(key.clone(), value).is_some() && self.options.strict {
+                self.pos = key_pos;
+                return Err(self.error(&format!("duplicate key \"{}\"", key.0)));
+            }
+
+            self.skip_ignored()?;
+
+            match self.next() {
+                Some(',') => {
+                    self.skip_ignored()?;
+                    if self.peek() == Some('}') && !self.options.strict {
+                        self.pos += 1;
+                        return Ok(MValue::from_hashmap(map));
+                    }
+                },
+                Some('}') => return Ok(MValue::from_hashmap(map)),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("expected ',' or '}' in object"));
+                },
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+
+        self.expect('"')?;
+
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(self.parse_escape()?),
+                Some(c) if c < ' ' && self.options.strict => {
+                    self.pos -= 1;
+                    return Err(self.error("unescaped control character in string"));
+                },
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char> {
+        let c = match self.next() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => return self.parse_unicode_escape(),
+            _ => {
+                self.pos -= 1;
+                return Err(self.error("invalid escape sequence"));
+            },
+        };
+
+        Ok(c)
+    }
+
+    fn parse_unicode_escape(&mut self) -> Result<char> {
+        let high = self.parse_hex4()?;
+
+        if !(0xD800..0xDC00).contains(&high) {
+            return std::char::from_u32(high)
+                .ok_or_else(|| self.error("invalid unicode escape"));
+        }
+
+        if self.peek() == Some('\\') && self.input.get(self.pos + 1) == Some(&'u') {
+            self.pos += 2;
+            let low = self.parse_hex4()?;
+
+            if (0xDC00..0xE000).contains(&low) {
+                let c = 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00);
+                return std::char::from_u32(c)
+                    .ok_or_else(|| self.error("invalid unicode escape"));
+            }
+        }
+
+        if self.options.strict {
+            Err(self.error("unpaired surrogate in unicode escape"))
+        } else {
+            Ok(std::char::REPLACEMENT_CHARACTER)
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32> {
+        let mut code = 0;
+
+        for _ in 0..4 {
+            let digit = self.peek()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("expected four hex digits in unicode escape"))?;
+
+            code = code * 16 + digit;
+            self.pos += 1;
+        }
+
+        Ok(code)
+    }
+
+    fn parse_number(&mut self) -> Result<MValue> {
+        let start = self.pos;
+        let mut is_float = false;
+
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+
+        match self.peek() {
+            Some('0') => self.pos += 1,
+            Some('1'..='9') => self.skip_digits(),
+            _ => return Err(self.error("expected digit")),
+        }
+
+        if self.peek() == Some('.') {
+            is_float = true;
+            self.pos += 1;
+            self.expect_digits()?;
+        }
+
+        if let Some('e') | Some('E') = self.peek() {
+            is_float = true;
+            self.pos += 1;
+            if let Some('+') | Some('-') = self.peek() {
+                self.pos += 1;
+            }
+            self.expect_digits()?;
+        }
+
+        let literal = self.input[start..self.pos].iter().collect::<String>();
+
+        if !is_float {
+            if let Ok(n) = literal.parse::<i64>() {
+                return Ok(MValue::integer(n));
+            }
+        }
+
+        match literal.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(MValue::float(n)),
+            _ => {
+                self.pos = start;
+                Err(self.error("number out of range"))
+            },
+        }
+    }
+
+    fn skip_digits(&mut self) {
+        while let Some('0'..='9') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect_digits(&mut self) -> Result<()> {
+        match self.peek() {
+            Some('0'..='9') => {
+                self.skip_digits();
+                Ok(())
+            },
+            _ => Err(self.error("expected digit")),
+        }
+    }
+}
+
+pub fn stringify(value: &MValue, options: &JsonOptions) -> Result<String> {
+    let mut out = String::new();
+    write_value(value, options, 0, &mut out)?;
+    Ok(out)
+}
+
+fn write_value(value: &MValue, options: &JsonOptions, depth: usize, out: &mut String) -> Result<()> {
+    match *value.0 {
+        MalVal::Nil => out.push_str("null"),
+        MalVal::Bool(b) => out.push_str(&b.to_string()),
+        MalVal::Int(n) => out.push_str(&n.to_string()),
+        MalVal::Float(n) if n.is_finite() => out.push_str(&format!("{:?}", n)),
+        MalVal::Float(n) => {
+            return Err(Error::EvalError(format!("{} can't be represented in JSON", n)));
+        },
+        MalVal::Str(ref s) | MalVal::Keyword(ref s) | MalVal::Sym(ref s) => write_string(s, out),
+        MalVal::List(ref l, _) | MalVal::Vector(ref l, _) => {
+            let items = l.iter().map(|v| (None, v)).collect::<Vec<_>>();
+            write_compound(&items, '[', ']', options, depth, out)?;
+        },
+        MalVal::HashMap(ref hm, _) => {
+            let items = hm.iter().map(|(k, v)| (Some(k.0.as_str()), v)).collect::<Vec<_>>();
+            write_compound(&items, '{', '}', options, depth, out)?;
+        },
+        MalVal::SortedMap(ref sorted, _) => {
+            let keys = sorted.keys().iter().map(|k| k.pr_str(false)).collect::<Vec<_>>();
+            let items = keys.iter()
+                .zip(sorted.iter())
+                .map(|(k, (_, v))| (Some(k.as_str()), v))
+                .collect::<Vec<_>>();
+            write_compound(&items, '{', '}', options, depth, out)?;
+        },
+        _ => {
+            return Err(Error::EvalError(format!("{} can't be represented in JSON", value)));
+        },
+    }
+
+    Ok(())
+}
+
+fn write_compound(
+    items: &[(Option<&str>, &MValue)],
+    start: char,
+    end: char,
+    options: &JsonOptions,
+    depth: usize,
+    out: &mut String) -> Result<()>
+{
+    out.push(start);
+
+    for (i, (key, value)) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+
+        if options.pretty {
+            out.push('\n');
+            out.push_str(&"  ".repeat(depth + 1));
+        }
+
+        if let Some(key) = key {
+            write_string(key, out);
+            out.push_str(if options.pretty { ": " } else { ":" });
+        }
+
+        write_value(value, options, depth + 1, out)?;
+    }
+
+    if options.pretty && !items.is_empty() {
+        out.push('\n');
+        out.push_str(&"  ".repeat(depth));
+    }
+
+    out.push(end);
+    Ok(())
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+#[test]
+fn test_parse() {
+    let options = JsonOptions { keywordize_keys: true, ..Default::default() };
+    let value = parse(r#"{"a": [1, 2.5, null, true], "b": "é\n"}"#, &options).unwrap();
+
+    let expected = MValue::hashmap(&mut vec![
+        MValue::keyword("a"),
+        MValue::vector(vec![
+            MValue::integer(1), MValue::float(2.5), MValue::nil(), MValue::bool(true)]),
+        MValue::keyword("b"),
+        MValue::string("é\n"),
+    ]);
+
+    assert_eq!(value, expected);
+}
+
+#[test]
+fn test_parse_errors() {
+    let strict = JsonOptions { strict: true, ..Default::default() };
+
+    assert!(parse("[1, 2,]", &JsonOptions::default()).is_ok());
+    assert!(parse("[1, 2,]", &strict).is_err());
+    assert!(parse(r#"{"a": 1, "a": 2}"#, &strict).is_err());
+
+    let error = parse("{\n  \"a\": tru\n}", &JsonOptions::default()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Parse error: JSON invalid literal, expected 'true' at line 2, column 11");
+
+    let deep = "[".repeat(100_000);
+    assert_eq!(
+        parse(&deep, &JsonOptions::default()).unwrap_err().to_string(),
+        "Parse error: JSON nesting deeper than 512 at line 1, column 513");
+    assert!(parse(&format!("{}{}", "[".repeat(512), "]".repeat(512)), &JsonOptions::default()).is_ok());
+}
+
+#[test]
+fn test_stringify() {
+    let value = MValue::vector(vec![
+        MValue::integer(1), MValue::float(1.0), MValue::string("\"q\""), MValue::nil()]);
+
+    assert_eq!(stringify(&value, &JsonOptions::default()).unwrap(), r#"[1,1.0,"\"q\"",null]"#);
+
+    let options = JsonOptions { pretty: true, ..Default::default() };
+    assert_eq!(stringify(&value, &options).unwrap(), "[\n  1,\n  1.0,\n  \"\\\"q\\\"\",\n  null\n]");
+}
@@ -2,3 +2,19 @@ pub mod reader;
 pub mod types;
 pub mod env;
 pub mod core;
+pub mod eval;
+pub mod json;
+pub mod edn;
+pub mod strings;
+pub mod format;
+pub mod sequences;
+pub mod transducers;
+pub mod sorted;
+pub mod pprint;
+pub mod gc;
+pub mod limits;
+pub mod sandbox;
+pub mod files;
+pub mod ports;
+pub mod editor;
+pub mod docs;
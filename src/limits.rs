@@ -0,0 +1,274 @@
+//! Limits on what an evaluation may use, for running code that isn't
+//! trusted.
+//!
+//! Values account for their own size as they are created and freed. Going
+//! over the memory budget can't fail the allocation itself, so `eval`
+//! checks the budget as it goes and fails with `Error::ResourceLimit`,
+//! which `try*` can catch once enough has been freed. The checks come
+//! between steps and after each builtin returns, so the budget is a soft
+//! one: a single call such as `(vec (range n))` or a `slurp` of a large
+//! file goes over it by as much as it builds before it fails. Embedders
+//! that need a hard cap should also limit the process itself.
+//!
+//! Every step of `eval` and of realizing a lazy sequence also burns fuel,
+//! when a supply of it was given, and checks the deadline and the interrupt
+//! flag. Running out of fuel or time can be caught with `try*`, whose
+//! handler is given a small grace allowance of steps or time to recover in.
+//! The grace is only given once for each limit the embedder sets, so a
+//! script can't catch its way past them; either way the environment is left
+//! as the last completed step left it.
+
+use crate::types::*;
+
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Default)]
+struct Memory {
+    used: usize,
+    peak: usize,
+    budget: Option<usize>,
+}
+
+/// The steps and time a `try*` handler gets after the limit it catches ran
+/// out.
+const GRACE_FUEL: u64 = 1_000;
+const GRACE_TIME: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Default)]
+struct Steps {
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    fuel_grace_used: bool,
+    time_grace_used: bool,
+}
+
+/// Stops the evaluation on the thread it was taken from at its next step.
+/// It can be set from any thread, or from a signal handler.
+#[derive(Clone, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+thread_local! {
+    static MEMORY: Cell<Memory> = Cell::new(Memory::default());
+    static STEPS: Cell<Steps> = Cell::new(Steps::default());
+    static INTERRUPT: Interrupt = Interrupt::default();
+}
+
+fn update<F: FnOnce(&mut Memory)>(f: F) {
+    // Values can outlive the thread locals while a thread shuts down.
+    let _ = MEMORY.try_with(|memory| {
+        let mut m = memory.get();
+        f(&mut m);
+        memory.set(m);
+    });
+}
+
+/// Bytes used by values, now and at most since the peak was last reset.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+    pub used: usize,
+    pub peak: usize,
+}
+
+/// Caps the bytes values may use on this thread, or lifts the cap.
+pub fn set_memory_budget(budget: Option<usize>) {
+    update(|m| m.budget = budget);
+}
+
+pub fn memory_usage() -> MemoryUsage {
+    let m = MEMORY.with(Cell::get);
+    MemoryUsage { used: m.used, peak: m.peak }
+}
+
+/// Starts measuring the peak afresh, as before an evaluation whose peak is
+/// to be reported.
+pub fn reset_peak() {
+    update(|m| m.peak = m.used);
+}
+
+pub(crate) fn allocate(bytes: usize) {
+    update(|m| {
+        m.used += bytes;
+        m.peak = m.peak.max(m.used);
+    });
+}
+
+pub(crate) fn release(bytes: usize) {
+    update(|m| m.used = m.used.saturating_sub(bytes));
+}
+
+/// Limits evaluation on this thread to `fuel` more steps, or lifts the
+/// limit.
+pub fn set_fuel(fuel: Option<u64>) {
+    STEPS.with(|steps| steps.set(Steps { fuel, fuel_grace_used: false, ..steps.get() }));
+}
+
+/// How many steps are left, when limited.
+pub fn fuel() -> Option<u64> {
+    STEPS.with(|steps| steps.get().fuel)
+}
+
+/// Interrupts evaluation on this thread once `deadline` has passed, or
+/// lifts the deadline.
+pub fn set_deadline(deadline: Option<Instant>) {
+    STEPS.with(|steps| steps.set(Steps { deadline, time_grace_used: false, ..steps.get() }));
+}
+
+/// Sets the deadline to `timeout` from now.
+pub fn set_timeout(timeout: Option<Duration>) {
+    set_deadline(timeout.map(|timeout| Instant::now() + timeout));
+}
+
+/// The interrupt flag of this thread, to hand to whatever should be able to
+/// stop its evaluation.
+pub fn interrupt_handle() -> Interrupt {
+    INTERRUPT.with(Interrupt::clone)
+}
+
+/// Fails when interrupted or out of time, for builtins that wait on
+/// something else without taking steps.
+pub(crate) fn poll() -> Result<()> {
+    if INTERRUPT.with(|interrupt| interrupt.0.swap(false, Ordering::SeqCst)) {
+        return Err(Error::Interrupted("interrupted".to_string()));
+    }
+
+    let deadline = STEPS.with(Cell::get).deadline;
+
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Err(Error::Interrupted("deadline exceeded".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Takes a step of evaluation, failing when out of fuel or time, when
+/// interrupted or when over the memory budget.
+pub(crate) fn step() -> Result<()> {
+    poll()?;
+
+    let mut steps = STEPS.with(Cell::get);
+
+    match steps.fuel {
+        Some(0) => return Err(Error::OutOfFuel),
+        Some(n) => {
+            steps.fuel = Some(n - 1);
+            STEPS.with(|s| s.set(steps));
+        },
+        None => {},
+    }
+
+    check()
+}
+
+/// Gives the handler of a `try*` that caught `error` its grace allowance,
+/// when `error` is running out of fuel or time and the allowance hasn't
+/// been used since the limit was set.
+pub(crate) fn grant_grace(error: &Error) {
+    let mut steps = STEPS.with(Cell::get);
+
+    match error {
+        Error::OutOfFuel if steps.fuel == Some(0) && !steps.fuel_grace_used => {
+            steps.fuel = Some(GRACE_FUEL);
+            steps.fuel_grace_used = true;
+        },
+        Error::Interrupted(_) if !steps.time_grace_used
+            && steps.deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+            steps.deadline = Some(Instant::now() + GRACE_TIME);
+            steps.time_grace_used = true;
+        },
+        _ => return,
+    }
+
+    STEPS.with(|s| s.set(steps));
+}
+
+/// Fails when values use more memory than the budget allows.
+pub(crate) fn check() -> Result<()> {
+    let m = MEMORY.with(Cell::get);
+
+    match m.budget {
+        Some(budget) if m.used > budget => Err(Error::ResourceLimit(
+            format!("memory budget of {} bytes exceeded, {} in use", budget, m.used))),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_fuel() {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::reader::read_form;
+
+    let env = Env::new(None);
+    let run = |code: &str| eval(read_form().parse(code.as_bytes()).unwrap(), &env);
+
+    run("(def! f (fn* [] (f)))").unwrap();
+    set_fuel(Some(10_000));
+    assert!(matches!(run("(f)"), Err(Error::OutOfFuel)));
+
+    // The handler gets a grace allowance, but only once per limit.
+    set_fuel(Some(10_000));
+    assert_eq!(run("(try* (f) (catch* e e))").unwrap(), MValue::string("Out of fuel"));
+    assert!(matches!(run("(try* (f) (catch* e 1))"), Err(Error::OutOfFuel)));
+
+    set_fuel(None);
+    set_timeout(Some(Duration::from_millis(10)));
+    assert_eq!(run("(try* (f) (catch* e 1))").unwrap(), MValue::integer(1));
+    assert!(matches!(run("(f)"), Err(Error::Interrupted(_))));
+
+    set_deadline(None);
+    interrupt_handle().interrupt();
+    assert!(matches!(run("(f)"), Err(Error::Interrupted(_))));
+    assert_eq!(run("(let* [x 1] x)").unwrap(), MValue::integer(1));
+}
+
+#[test]
+fn test_memory_budget() {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::reader::read_form;
+
+    let env = Env::new(None);
+    env.set("range", MValue::function(crate::sequences::range, None));
+    env.set("doall", MValue::function(crate::sequences::doall, None));
+    let run = |code: &str| eval(read_form().parse(code.as_bytes()).unwrap(), &env);
+
+    set_memory_budget(Some(memory_usage().used + 100_000));
+    reset_peak();
+
+    match run("(doall (range 10000))") {
+        Err(Error::ResourceLimit(_)) => {},
+        r => panic!("expected the budget to be exceeded, got {:?}", r),
+    }
+
+    assert!(memory_usage().peak > 100_000);
+    assert_eq!(run("(try* (doall (range 10000)) (catch* e :caught))").unwrap(), MValue::keyword("caught"));
+    assert_eq!(run("(doall (range 3))").unwrap().pr_str(true), "(0 1 2)");
+
+    // Maps built up with assoc count towards the budget, and give it back
+    // when they go.
+    env.set("assoc", MValue::function(crate::core::assoc, None));
+    env.set("reduce", MValue::function(crate::sequences::reduce, None));
+    set_memory_budget(None);
+
+    let before = memory_usage().used;
+    let map = run("(reduce (fn* [m i] (assoc m i i)) {} (range 1000))").unwrap();
+    assert!(memory_usage().used > before + 1000);
+    drop(map);
+    assert_eq!(memory_usage().used, before);
+
+    set_memory_budget(Some(before + 100_000));
+    match run("(reduce (fn* [m i] (assoc m i i)) {} (range 10000))") {
+        Err(Error::ResourceLimit(_)) => {},
+        r => panic!("expected the budget to be exceeded, got {:?}", r),
+    }
+    set_memory_budget(None);
+}
@@ -3,385 +3,527 @@ extern crate rust;
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
 
-use itertools::Itertools;
-
 use rust::reader::*;
 use rust::types::*;
 use rust::env::Env;
-use rust::core::*;
+use rust::editor::{self, ReplHelper};
+use rust::eval::*;
+use rust::limits;
+use rust::pprint;
+use rust::sandbox::{self, Sandbox};
 
 use std::env::args;
-
-fn eval_ast(value: MValue, env: &Env) -> Result<MValue> {
-    if value.is_symbol() {
-        let x = value.cast_to_string()?;
-        env.get(&x)
-           .ok_or_else(|| Error::NoSymbolFound(x))
-    } else if value.is_list() {
-        value.cast_to_list()?.into_iter()
-           .map(|x| eval(x, &env))
-           .collect::<Result<_>>()
-           .map(MValue::list)
-    } else if value.is_hashmap() {
-        value.cast_to_hashmap()?.into_iter()
-           .map(|(k, v)| eval(v, &env).map(|v| (k,v)) )
-           .collect::<Result<_>>()
-           .map(MValue::from_hashmap)
-    } else if value.is_vector() {
-        value.cast_to_list()?.into_iter()
-           .map(|x| eval(x, &env))
-           .collect::<Result<_>>()
-           .map(MValue::vector)
-    } else {
-        Ok(value)
-   }
-}
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+#[cfg(unix)]
+use std::sync::OnceLock;
 
 fn read(input: &str) -> Result<MValue> {
     read_form().parse(input.as_bytes()).map_err(From::from)
 }
 
-fn eval(input: MValue, env: &Env) -> Result<MValue> {
-    let mut env = env.clone();
-    let mut input = input.clone();
+fn terminal_width() -> usize {
+    std::env::var("COLUMNS").ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(pprint::DEFAULT_WIDTH)
+}
 
-    loop {
-        if !input.is_list() {
-            return eval_ast(input, &env);
-        }
+fn print(input: Result<MValue>, limits: PrintLimits) -> String {
+    match input {
+        Ok(mvalue) => {
+            let printed = mvalue.pr_str_limited(true, limits);
+            let width = terminal_width();
+            let limited = limits.level.is_some() || limits.length.is_some();
+
+            // The pretty-printer shows everything, so it is left out when
+            // the output is limited.
+            if printed.chars().count() > width && !limited {
+                pprint::pretty(&mvalue, width)
+            } else {
+                printed
+            }
+        },
+        Err(error) => error.to_string(),
+    }
+}
 
-        input = macro_expand(input, &env)?;
+fn read_eval(input: &str, env: &Env, limits: PrintLimits) -> Result<MValue> {
+    // Realizing lazy results before printing reports their errors as such,
+    // unless `*print-length*` is set to print only the start of them.
+    read(input)
+        .and_then(|v| eval(v, &env))
+        .and_then(|v| match limits.length {
+            Some(_) => Ok(v),
+            None => v.force().map(|_| v),
+        })
+}
 
-        if !input.is_list() {
-            return eval_ast(input, &env);
-        }
+/// Ends the process, flushing what was printed first since `exit` doesn't.
+fn exit(status: i32) -> ! {
+    std::io::stdout().flush().ok();
+    std::process::exit(status)
+}
 
-        let mut l = input.clone().cast_to_list()?;
+/// Makes Ctrl-C interrupt the evaluation in progress instead of the REPL.
+/// While reading a line the terminal is in raw mode, and Ctrl-C is a key.
+#[cfg(unix)]
+fn handle_interrupts() {
+    static INTERRUPT: OnceLock<limits::Interrupt> = OnceLock::new();
 
-        if l.is_empty() {
-            return Ok(MValue::list(l));
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        if let Some(interrupt) = INTERRUPT.get() {
+            interrupt.interrupt();
         }
+    }
 
-        match *l[0].0 {
-            MalVal::Sym(ref sym) if sym == "do" => {
-                input = l
-                    .pop()
-                    .ok_or_else(|| Error::EvalError(
-                            "No argument was provided".to_string()))?;
+    INTERRUPT.get_or_init(limits::interrupt_handle);
 
-                let v = MValue::list(l[1..].to_vec());
-                eval_ast(v, &env)?;
-            },
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
 
-            MalVal::Sym(ref sym) if sym == "if" => {
-                let condition = eval(l[1].clone(), &env)?;
-                match *condition.0 {
-                    MalVal::Bool(false) | MalVal::Nil if l.len() >= 4 =>
-                        input = l[3].clone(),
-                    MalVal::Bool(false) | MalVal::Nil =>
-                        return Ok(MValue::nil()),
-                    _ =>
-                        input = l[2].clone(),
-                }
-            },
+#[cfg(not(unix))]
+fn handle_interrupts() {}
 
-            MalVal::Sym(ref sym) if sym == "macroexpand" => {
-                return macro_expand(l[1].clone(), &env);
-            },
+const USAGE: &str = "\
+Usage: rust [options] [script | -] [args...]
 
-            MalVal::Sym(ref sym) if sym == "quote" => {
-                return Ok(l[1].clone());
-            },
+Runs the script, or the program read from standard input for -, with the
+arguments after it in *command-line-args*. Without either or -e it starts
+the REPL.
 
-            MalVal::Sym(ref sym) if sym == "quasiquote" => {
-                input = quasiquote(l[1].clone())?;
-            },
+Options:
+  -e EXPR            Evaluate EXPR and print its value unless it is nil
+  -i                 Start the REPL after running the script or expressions
+  --no-prelude       Leave out what is defined in Lisp, such as cond and load-file
+  --load-path DIR    Look for files given to load-file in DIR too
+  --version          Print the version
+  -h, --help         Print this help";
 
-            MalVal::Sym(ref sym) if sym == "fn*" => {
-                let parameters = l[1].clone()
-                    .cast_to_list()?
-                    .iter()
-                    .flat_map(MValue::cast_to_string)
-                    .collect::<Vec<String>>();
+enum Script {
+    Path(String),
+    Stdin,
+}
 
-                let body = l[2].clone();
+#[derive(Default)]
+struct Options {
+    exprs: Vec<String>,
+    script: Option<Script>,
+    args: Vec<String>,
+    interactive: bool,
+    no_prelude: bool,
+    load_path: Vec<String>,
+}
 
-                return Ok(MValue::lambda(env.clone(), parameters, body));
+/// What the command line asks for, or a message for bad usage.
+fn parse_args<I: Iterator<Item = String>>(mut argv: I) -> std::result::Result<Options, String> {
+    let mut options = Options::default();
+
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-e" => options.exprs.push(argv.next().ok_or("-e needs an expression")?),
+            "-i" => options.interactive = true,
+            "--no-prelude" => options.no_prelude = true,
+            "--load-path" => options.load_path.push(argv.next().ok_or("--load-path needs a directory")?),
+            "--version" => {
+                println!("Mal [Rust] {}", env!("CARGO_PKG_VERSION"));
+                exit(0);
             },
-
-            MalVal::Sym(ref sym) if sym == "def!" => {
-                let key = l[1].cast_to_string()?;
-                let v = eval(l[2].clone(), &env)?;
-                env.set(key, v.clone());
-                return Ok(v);
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            },
+            "-" => {
+                options.script = Some(Script::Stdin);
+                break;
+            },
+            "--" => {
+                options.script = argv.next().map(Script::Path);
+                break;
+            },
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ => {
+                options.script = Some(Script::Path(arg));
+                break;
             },
+        }
+    }
 
-            MalVal::Sym(ref sym) if sym == "try*" => {
-                let try_expr = l[1].clone();
+    options.args = argv.collect();
+    Ok(options)
+}
 
-                let result = eval(try_expr, &env);
+/// The forms of a whole program, to be evaluated in order.
+fn read_program(source: &str) -> Result<MValue> {
+    let mut forms = vec![MValue::symbol("do"), MValue::nil()];
+    forms.extend(read_all().parse(source.as_bytes())?);
+    Ok(MValue::list(forms))
+}
 
-                if result.is_ok() || l.len() < 3 {
-                    return result;
-                }
+/// Evaluates a script with `*file*` bound to its path, so what it defines
+/// knows where it came from.
+fn run_script(script: &Script, env: &Env) -> Result<MValue> {
+    let program = read_program(&load(script)?)?;
 
-                let error = result.unwrap_err();
-                let catch_block = l[2].clone().cast_to_list()?;
-                let err_symbol = catch_block[1].clone().cast_to_string()?;
-                let catch_expr = catch_block[2].clone();
-                env.set(err_symbol, error.catch());
-                return eval(catch_expr, &env);
-            },
+    let file = match script {
+        Script::Path(path) => MValue::string(path),
+        Script::Stdin => MValue::nil(),
+    };
 
-            MalVal::Sym(ref sym) if sym == "defmacro!" => {
-                let key = l[1].cast_to_string()?;
-                let mut v = eval(l[2].clone(), &env)?;
-                v.set_macro();
-                env.set(key, v.clone());
-                return Ok(v);
-            },
+    let saved = env.rebind("*file*", file);
+    let result = eval(program, env);
+    if let Some(saved) = saved {
+        env.rebind("*file*", saved);
+    }
+
+    result
+}
+
+fn load(script: &Script) -> Result<String> {
+    match script {
+        Script::Path(path) => std::fs::read_to_string(path).map_err(|e| Error::io(path, e)),
+        Script::Stdin => {
+            let mut source = String::new();
+            std::io::stdin().read_to_string(&mut source)?;
+            Ok(source)
+        },
+    }
+}
 
-            MalVal::Sym(ref sym) if sym == "let*" => {
-                env = Env::new(Some(env.clone()));
+/// Exits with the status a program gives `exit`, or with 1 when it fails
+/// with an error it doesn't catch.
+fn check<T>(result: Result<T>) -> T {
+    match result {
+        Ok(v) => v,
+        Err(Error::Exit(status)) => exit(status),
+        Err(error) => {
+            std::io::stdout().flush().ok();
+            eprintln!("{}", error);
+            exit(1);
+        },
+    }
+}
 
-                let binds = l[1].clone().cast_to_list()?;
+const COMMANDS: &str = "\
+:doc name      Show the documentation of what name is bound to
+:source name   Show the definition of a function or macro
+:load file     Evaluate the forms in a file
+:reload        Load the last file loaded again
+:env [all]     List what was defined in this session, or everything
+:time expr     Evaluate expr and show how long it took
+:help          Show this list
+:quit          Leave the REPL";
+
+/// Where the REPL keeps its history, following the XDG base directories.
+fn history_path() -> Option<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))?;
+
+    let dir = state.join("mal");
+    std::fs::create_dir_all(&dir).ok()?;
+    Some(dir.join("history"))
+}
 
-                for (bind, expr) in binds.clone().into_iter().tuples() {
-                    let bind = bind.cast_to_string()?;
-                    let v = eval(expr, &env)?;
+/// The name and argument of a colon command such as `:doc map`. Other
+/// lines, keywords among them, are evaluated.
+fn command(line: &str) -> Option<(&str, &str)> {
+    let (name, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
 
-                    env.set(bind, v);
-                }
+    match name {
+        ":doc" | ":source" | ":load" | ":reload" | ":env" | ":time" | ":help" | ":quit" =>
+            Some((name, arg.trim())),
+        _ => None,
+    }
+}
 
-                input = l[2].clone();
+struct Repl {
+    env: Env,
+    /// What the environment had before the session, for `:env`.
+    builtins: Vec<String>,
+    last_loaded: Option<String>,
+}
+
+impl Repl {
+    fn new(env: &Env, last_loaded: Option<String>) -> Self {
+        for name in &["*1", "*2", "*3", "*e"] {
+            env.set(name, MValue::nil());
+        }
+
+        Repl { env: env.clone(), builtins: env.names(), last_loaded }
+    }
+
+    /// Evaluates a line and prints the result, which becomes `*1`, or the
+    /// error, which becomes `*e`.
+    fn eval(&self, input: &str) {
+        let limits = PrintLimits::from_env(&self.env);
+
+        match read_eval(input, &self.env, limits) {
+            Ok(value) => {
+                self.env.set("*3", self.env.get("*2").unwrap_or_else(MValue::nil));
+                self.env.set("*2", self.env.get("*1").unwrap_or_else(MValue::nil));
+                self.env.set("*1", value.clone());
+                println!("{}", print(Ok(value), limits));
+            },
+            Err(Error::Exit(status)) => exit(status),
+            Err(error) => {
+                self.env.set("*e", error.catch());
+                println!("{}", error);
             },
+        }
+    }
 
+    fn run(&mut self, name: &str, arg: &str) -> Result<()> {
+        match name {
+            ":doc" => self.describe("doc", arg),
+            ":source" => self.describe("source", arg),
+            ":load" if !arg.is_empty() => {
+                self.last_loaded = Some(arg.to_string());
+                self.load(arg)
+            },
+            ":reload" => match self.last_loaded.clone() {
+                Some(path) => self.load(&path),
+                None => Err(Error::EvalError("Nothing was loaded yet".to_string())),
+            },
+            ":env" => {
+                let mut names = self.env.names();
+                if arg != "all" {
+                    names.retain(|name| !self.builtins.contains(name));
+                }
+                names.sort();
 
+                for name in names {
+                    let value = self.env.get(&name).unwrap_or_else(MValue::nil);
+                    let limits = PrintLimits { level: Some(2), length: Some(8) };
+                    println!("{} = {}", name, value.pr_str_limited(true, limits));
+                }
+                Ok(())
+            },
+            ":time" if !arg.is_empty() => {
+                let start = Instant::now();
+                self.eval(arg);
+                println!("Elapsed: {:.3} ms", start.elapsed().as_secs_f64() * 1000.0);
+                Ok(())
+            },
+            ":quit" => exit(0),
             _ => {
-                let evaluated_list = eval_ast(MValue::list(l), &env)?.cast_to_list()?;
-
-                return handle_function(&evaluated_list[0], evaluated_list[1..].to_vec());
+                println!("{}", COMMANDS);
+                Ok(())
             },
         }
     }
-}
 
-fn is_nonempty_list(value: &MValue) -> bool {
-    (value.is_list() || value.is_vector()) && !value.clone().cast_to_list().unwrap().is_empty()
-}
+    fn lookup(&self, name: &str) -> Result<MValue> {
+        if name.is_empty() {
+            return Err(Error::EvalError("A name is needed".to_string()));
+        }
 
-fn quasiquote(value: MValue) -> Result<MValue> {
-    if !is_nonempty_list(&value) {
-        return Ok(MValue::list(vec![MValue::symbol("quote"), value.clone()]));
+        self.env.get(name).ok_or_else(|| Error::NoSymbolFound(name.to_string()))
     }
 
-    let ast = value.clone().cast_to_list()?;
+    /// Calls a builtin such as `doc` with the value `name` is bound to,
+    /// leaving `*1` alone.
+    fn describe(&self, builtin: &str, name: &str) -> Result<()> {
+        self.lookup(name)?;
+        read_eval(&format!("({} {})", builtin, name), &self.env, PrintLimits::default()).map(|_| ())
+    }
 
-    if let MalVal::Sym(ref unquote) = *ast[0].0 {
-        if unquote == "unquote" {
-            return Ok(value.cast_to_list()?[1].clone());
-        }
+    fn load(&self, path: &str) -> Result<()> {
+        let value = run_script(&Script::Path(path.to_string()), &self.env)?;
+
+        println!("{}", print(Ok(value), PrintLimits::from_env(&self.env)));
+        Ok(())
     }
+}
 
-    let rest = MValue::list(ast[1..].to_vec());
+/// Reads an entry, going on to more lines until its brackets and strings
+/// are closed. An end of input in the middle leaves the rest to the reader
+/// to complain about.
+fn read_entry(ed: &mut Editor<ReplHelper>) -> std::result::Result<String, ReadlineError> {
+    // Lines read from a pipe rather than a terminal keep their line ending.
+    let chomp = |line: String| line.trim_end_matches(&['\r', '\n'][..]).to_string();
+    let mut entry = chomp(ed.readline("user> ")?);
 
-    if is_nonempty_list(&ast[0]) {
-        let m1 = ast[0].clone().cast_to_list()?;
-        if let MalVal::Sym(ref splice_unquote) = *m1[0].0 {
-            if splice_unquote == "splice-unquote" {
-                return Ok(MValue::list(
-                        vec![MValue::symbol("concat"),
-                        m1[1].clone(),
-                        quasiquote(rest)?]));
-            }
+    while !editor::is_complete(&entry) {
+        match ed.readline("  ...> ") {
+            Ok(line) => {
+                entry.push('\n');
+                entry.push_str(&chomp(line));
+            },
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err),
         }
     }
 
-    Ok(MValue::list(vec![
-                    MValue::symbol("cons"),
-                    quasiquote(ast[0].clone())?,
-                    quasiquote(rest)?]))
+    Ok(entry)
 }
 
-fn meval(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
-    eval(args[0].clone(), &env.unwrap())
-}
+fn repl(env: &Env, last_loaded: Option<String>) {
+    let mut ed = Editor::<ReplHelper>::new();
+    ed.set_helper(Some(ReplHelper::new(env)));
+    let history = history_path();
 
-pub fn swap(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    let mut args = args.clone();
-    let atom = args.remove(0);
-    let f = args.remove(0);
+    if let Some(ref history) = history {
+        ed.load_history(history).ok();
+    }
 
-    args.insert(0, atom.atom_deref()?);
+    read_eval("(println (str \"Mal [\" *host-language* \"]\"))", env, PrintLimits::default()).ok();
+    handle_interrupts();
 
-    let v = handle_function(&f, args)?;
-    Ok(atom.atom_reset(v)?)
-}
+    let mut repl = Repl::new(env, last_loaded);
 
-fn handle_function(function: &MValue, args: Vec<MValue>) -> Result<MValue> {
-    match *function.0 {
-        MalVal::Fun(fun, ref env, _) => {
-            fun(args, env.clone())
-        }, 
+    loop {
+        match read_entry(&mut ed) {
+            Ok(line) => {
+                ed.add_history_entry(&line);
+                if let Some(helper) = ed.helper() {
+                    helper.remember(&line);
+                }
+                if let Some(ref history) = history {
+                    ed.save_history(history).ok();
+                }
 
-        MalVal::Lambda(ref fun, _) => {
-            let (body, new_env) = fun.apply(args)?;
-            eval(body, &new_env)
+                match command(line.trim()) {
+                    Some((name, arg)) => match repl.run(name, arg) {
+                        Err(Error::Exit(status)) => exit(status),
+                        Err(error) => println!("{}", error),
+                        Ok(()) => {},
+                    },
+                    None => repl.eval(&line),
+                }
+            },
+            Err(ReadlineError::Eof) => break,
+            Err(err) => println!("Error: {:?}", err),
+        }
+    }
+}
+
+fn main() {
+    let options = match parse_args(args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            exit(2);
         },
+    };
+
+    let sandbox = Sandbox { prelude: !options.no_prelude, ..Sandbox::unrestricted() };
+    let repl_env = check(sandbox::environment(&sandbox));
+
+    let margv = MValue::list(options.args.iter().map(MValue::string).collect());
+    repl_env.set("*ARGV*", margv.clone());
+    repl_env.set("*command-line-args*", margv);
+    repl_env.set("*load-path*", MValue::vector(options.load_path.iter().map(MValue::string).collect()));
+
+    for expr in &options.exprs {
+        let limits = PrintLimits::from_env(&repl_env);
+        let value = check(read_eval(expr, &repl_env, limits));
 
-        _ => Err(Error::EvalError(format!("No function provided: {:?}", *function.0)))
+        if !value.is_nil() {
+            println!("{}", print(Ok(value), limits));
+        }
     }
-}
 
-pub fn apply(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    let mut args = args.clone();
-    let f = args.remove(0);
+    if let Some(ref script) = options.script {
+        check(run_script(script, &repl_env));
+    }
 
-    let mut last_arguments = args.pop()
-        .ok_or_else(|| Error::EvalError("Not enough arguments".to_string()))?
-        .cast_to_list()?;
+    if options.interactive || (options.exprs.is_empty() && options.script.is_none()) {
+        let last_loaded = match options.script {
+            Some(Script::Path(path)) => Some(path),
+            _ => None,
+        };
 
-    args.append(&mut last_arguments);
+        repl(&repl_env, last_loaded);
+    }
 
-    handle_function(&f, args)
+    exit(0);
 }
 
-pub fn map(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
-    let f = args[0].clone();
-    let values: Vec<MValue> = args[1].clone().cast_to_list()?;
+/// Writes a script to a file of its own, to be run as from the command line.
+#[cfg(test)]
+fn script_file(name: &str, source: &str) -> Script {
+    let dir = std::env::temp_dir().join(format!("mal-main-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
 
-    values.iter()
-        .map(|v| handle_function(&f, vec![v.clone()]))
-        .collect::<Result<Vec<MValue>>>()
-        .map(MValue::list)
+    let path = dir.join(name);
+    std::fs::write(&path, source).unwrap();
+    Script::Path(path.to_string_lossy().into_owned())
 }
 
-pub fn macro_expand(value: MValue, env: &Env) -> Result<MValue> {
-    let mut value = value;
+#[test]
+fn test_exit() {
+    let env = sandbox::environment(&Sandbox::unrestricted()).unwrap();
 
-    while value.is_macro_call(&env) {
-        let list = value.clone().cast_to_list()?;
-        let lambda = env.get(&list[0].cast_to_string()?).unwrap();
-        value = handle_function(&lambda, list[1..].to_vec())?;
-    }
+    // The status given to exit gets past try*, to be the process's own.
+    let script = script_file("exit.mal", "(try* (exit 3) (catch* e 0))\n(def! after 1)");
+    assert!(matches!(run_script(&script, &env), Err(Error::Exit(3))));
+    assert!(env.get("after").is_none());
 
-    Ok(value)
+    let script = script_file("throw.mal", "(throw \"oops\")");
+    assert!(matches!(run_script(&script, &env), Err(Error::Throw(_))));
 }
 
-fn print(input: Result<MValue>) -> String {
-    match input {
-        Ok(mvalue) => mvalue.pr_str(true),
-        Err(error) => error.to_string(),
-    }
-}
+#[test]
+fn test_parse_args() {
+    let parse = |argv: &[&str]| parse_args(argv.iter().map(|arg| arg.to_string()));
+
+    let options = parse(&["-e", "(+ 1 2)", "-i", "--load-path", "lib", "script.mal", "a", "-e", "--"]).unwrap();
+    assert_eq!(options.exprs, vec!["(+ 1 2)"]);
+    assert!(options.interactive && !options.no_prelude);
+    assert_eq!(options.load_path, vec!["lib"]);
+    assert!(matches!(options.script, Some(Script::Path(ref path)) if path == "script.mal"));
+    assert_eq!(options.args, vec!["a", "-e", "--"]);
+
+    let options = parse(&["--no-prelude", "-", "x"]).unwrap();
+    assert!(options.no_prelude && matches!(options.script, Some(Script::Stdin)));
+    assert_eq!(options.args, vec!["x"]);
 
-fn rep(input: &str, env: &Env) -> String {
-    let v = read(input).and_then(|v| eval(v, &env));
+    let options = parse(&["--", "-odd.mal", "y"]).unwrap();
+    assert!(matches!(options.script, Some(Script::Path(ref path)) if path == "-odd.mal"));
+    assert_eq!(options.args, vec!["y"]);
 
-    print(v)
+    assert!(parse(&[]).unwrap().script.is_none());
+    assert_eq!(parse(&["-x"]).err().unwrap(), "Unknown option -x");
+    assert_eq!(parse(&["-e"]).err().unwrap(), "-e needs an expression");
 }
 
-fn main() {
-    let mut ed = Editor::<()>::new();
-    ed.load_history(".mal_history").ok();
-
-
-    let repl_env = Env::new(None);
-    repl_env.set("+", MValue::function(add, None));
-    repl_env.set("-", MValue::function(sub, None));
-    repl_env.set("*", MValue::function(mul, None));
-    repl_env.set("/", MValue::function(div, None));
-    repl_env.set("list", MValue::function(list, None));
-    repl_env.set("vector", MValue::function(vector, None));
-    repl_env.set("hash-map", MValue::function(hashmap, None));
-    repl_env.set("symbol", MValue::function(symbol, None));
-    repl_env.set("keyword", MValue::function(keyword, None));
-    repl_env.set("list?", MValue::function(list_q, None));
-    repl_env.set("vector?", MValue::function(vector_q, None));
-    repl_env.set("sequential?", MValue::function(sequential_q, None));
-    repl_env.set("map?", MValue::function(map_q, None));
-    repl_env.set("empty?", MValue::function(empty_q, None));
-    repl_env.set("assoc", MValue::function(assoc, None));
-    repl_env.set("dissoc", MValue::function(dissoc, None));
-    repl_env.set("count", MValue::function(count, None));
-    repl_env.set("=", MValue::function(eq, None));
-    repl_env.set(">", MValue::function(gt, None));
-    repl_env.set("<", MValue::function(lt, None));
-    repl_env.set(">=", MValue::function(gte, None));
-    repl_env.set("<=", MValue::function(lte, None));
-    repl_env.set("pr-str", MValue::function(print_str, None));
-    repl_env.set("str", MValue::function(string, None));
-    repl_env.set("prn", MValue::function(prn, None));
-    repl_env.set("println", MValue::function(println, None));
-    repl_env.set("read-string", MValue::function(read_str, None));
-    repl_env.set("slurp", MValue::function(slurp, None));
-    repl_env.set("atom", MValue::function(atom, None));
-    repl_env.set("atom?", MValue::function(atom_q, None));
-    repl_env.set("deref", MValue::function(deref, None));
-    repl_env.set("reset!", MValue::function(reset, None));
-    repl_env.set("swap!", MValue::function(swap, Some(repl_env.clone())));
-    repl_env.set("cons", MValue::function(cons, None));
-    repl_env.set("concat", MValue::function(concat, None));
-    repl_env.set("nth", MValue::function(nth, None));
-    repl_env.set("first", MValue::function(first, None));
-    repl_env.set("rest", MValue::function(rest, None));
-    repl_env.set("throw", MValue::function(throw, None));
-    repl_env.set("apply", MValue::function(apply, None));
-    repl_env.set("map", MValue::function(map, None));
-    repl_env.set("symbol?", MValue::function(symbol_q, None));
-    repl_env.set("nil?", MValue::function(nil_q, None));
-    repl_env.set("true?", MValue::function(true_q, None));
-    repl_env.set("false?", MValue::function(false_q, None));
-    repl_env.set("keyword?", MValue::function(keyword_q, None));
-    repl_env.set("get", MValue::function(get, None));
-    repl_env.set("contains?", MValue::function(contains_q, None));
-    repl_env.set("keys", MValue::function(keys, None));
-    repl_env.set("vals", MValue::function(values, None));
-    repl_env.set("readline", MValue::function(readline, None));
-    repl_env.set("time-ms", MValue::function(time_ms, None));
-    repl_env.set("meta", MValue::function(meta, None));
-    repl_env.set("with-meta", MValue::function(with_meta, None));
-    repl_env.set("fn?", MValue::function(fn_q, None));
-    repl_env.set("string?", MValue::function(string_q, None));
-    repl_env.set("number?", MValue::function(number_q, None));
-    repl_env.set("macro?", MValue::function(macro_q, None));
-    repl_env.set("seq", MValue::function(seq, None));
-    repl_env.set("conj", MValue::function(conj, None));
-    repl_env.set("*host-language*", MValue::string("Rust"));
-    repl_env.set("eval", MValue::function(meval, Some(repl_env.clone())));
-
-    rep("(def! not (fn* (a) (if a false true)))", &repl_env);
-    rep("(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \")\")))))"
-        , &repl_env);
-    rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &repl_env);
-    rep("(def! *gensym-counter* (atom 0))", &repl_env);
-    rep("(def! gensym (fn* [] (symbol (str \"G__\" (swap! *gensym-counter* (fn* [x] (+ 1 x)))))))", &repl_env);
-    rep("(defmacro! or (fn* (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) (let* (condvar (gensym)) `(let* (~condvar ~(first xs)) (if ~condvar ~condvar (or ~@(rest xs)))))))))", &repl_env);
-
-    let mut argv = args().skip(1);
-    let path = argv.next();
-    let margv = argv.map(MValue::string).collect();
-    repl_env.set("*ARGV*", MValue::list(margv));
-
-    if let Some(path) = path {
-        let command = format!("(load-file \"{}\")", path);
-        rep(&command, &repl_env);
-        return;
-    }
+#[test]
+fn test_run_script() {
+    let env = sandbox::environment(&Sandbox::unrestricted()).unwrap();
 
-    rep("(println (str \"Mal [\" *host-language* \"]\"))", &repl_env);
+    // A shebang line is skipped, and the value is that of the last form.
+    let script = script_file("ok.mal", "#!/usr/bin/env rust\n(def! x 1)\n(str *file* \" \" (+ x 1))");
+    let path = match script {
+        Script::Path(ref path) => path.clone(),
+        Script::Stdin => unreachable!(),
+    };
+    assert_eq!(run_script(&script, &env).unwrap(), MValue::string(format!("{} 2", path)));
 
-    loop {
-        let line = ed.readline("user> ");
+    assert!(matches!(run_script(&script_file("bad.mal", "(+ 1"), &env), Err(Error::ParseError(_))));
+}
 
-        match line {
-            Ok(line) => {
-                println!("{}", &rep(&line, &repl_env));
-                ed.add_history_entry(line);
-                ed.save_history(".mal_history").ok();
-            },
-            Err(ReadlineError::Eof) => break,
-            Err(err) => println!("Error: {:?}", err),
-        }
-    }
+#[test]
+fn test_repl() {
+    let env = sandbox::environment(&Sandbox::unrestricted()).unwrap();
+    let mut repl = Repl::new(&env, None);
+    let get = |name: &str| env.get(name).unwrap();
+
+    repl.eval("1");
+    repl.eval("2");
+    repl.eval("(+ *1 *2)");
+    assert_eq!((get("*1"), get("*2"), get("*3")), (MValue::integer(3), MValue::integer(2), MValue::integer(1)));
+
+    repl.eval("(throw :oops)");
+    assert_eq!(get("*e"), MValue::keyword("oops"));
+    assert_eq!(get("*1"), MValue::integer(3));
+
+    assert_eq!(command(":doc  map "), Some((":doc", "map")));
+    assert_eq!(command(":reload"), Some((":reload", "")));
+    assert_eq!(command(":kw"), None);
+    assert!(repl.run(":reload", "").is_err());
+    assert!(matches!(repl.run(":doc", "no-such-name"), Err(Error::NoSymbolFound(_))));
 }
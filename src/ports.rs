@@ -0,0 +1,323 @@
+//! Ports: values that input is read from or output is written to.
+//!
+//! The printing builtins write to whatever `*out*` is bound to, which
+//! `binding` can change for the extent of its body, so output can go to a
+//! file or be captured in a string as `with-out-str` does.
+
+use rustyline::Editor;
+use rustyline::error::ReadlineError;
+
+use crate::core::assert_min_args;
+use crate::env::Env;
+use crate::sandbox::resolve_path;
+use crate::types::*;
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::rc::Rc;
+
+enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+    Reader(BufReader<Box<dyn Read>>),
+    Writer(BufWriter<Box<dyn Write>>),
+    InputString(String),
+    OutputString(String),
+    Closed,
+}
+
+/// A port is shared by the values that refer to it, so a copy writes to
+/// the same stream and closing one closes them all.
+#[derive(Clone)]
+pub struct Port {
+    name: String,
+    stream: Rc<RefCell<Stream>>,
+}
+
+impl Port {
+    fn open<T: ToString>(name: T, stream: Stream) -> MValue {
+        MValue::port(Port { name: name.to_string(), stream: Rc::new(RefCell::new(stream)) })
+    }
+
+    pub(crate) fn reader<T: ToString, R: Read + 'static>(name: T, r: R) -> MValue {
+        Port::open(name, Stream::Reader(BufReader::new(Box::new(r))))
+    }
+
+    pub(crate) fn writer<T: ToString, W: Write + 'static>(name: T, w: W) -> MValue {
+        Port::open(name, Stream::Writer(BufWriter::new(Box::new(w))))
+    }
+
+    pub fn stdin() -> MValue {
+        Port::open("stdin", Stream::Stdin)
+    }
+
+    pub fn stdout() -> MValue {
+        Port::open("stdout", Stream::Stdout)
+    }
+
+    pub fn stderr() -> MValue {
+        Port::open("stderr", Stream::Stderr)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn same(&self, other: &Port) -> bool {
+        Rc::ptr_eq(&self.stream, &other.stream)
+    }
+
+    fn closed(&self) -> Error {
+        Error::io(&self.name, io::Error::other("the port is closed"))
+    }
+
+    pub fn write_str(&self, s: &str) -> Result<()> {
+        let written = match *self.stream.borrow_mut() {
+            Stream::Stdout => io::stdout().write_all(s.as_bytes()),
+            Stream::Stderr => io::stderr().write_all(s.as_bytes()),
+            Stream::Writer(ref mut w) => w.write_all(s.as_bytes()),
+            Stream::OutputString(ref mut string) => {
+                string.push_str(s);
+                Ok(())
+            },
+            Stream::Closed => return Err(self.closed()),
+            _ => return Err(Error::EvalError(format!("{} is not an output port", self.name))),
+        };
+
+        written.map_err(|e| Error::io(&self.name, e))
+    }
+
+    /// The next line without its line ending, or `None` at the end.
+    pub fn read_line(&self) -> Result<Option<String>> {
+        let mut line = String::new();
+
+        let read = match *self.stream.borrow_mut() {
+            Stream::Stdin => io::stdin().lock().read_line(&mut line),
+            Stream::Reader(ref mut r) => r.read_line(&mut line),
+            Stream::InputString(ref mut string) => {
+                let end = string.find('\n').map_or(string.len(), |i| i + 1);
+                line = string.drain(..end).collect();
+                Ok(line.len())
+            },
+            Stream::Closed => return Err(self.closed()),
+            _ => return Err(Error::EvalError(format!("{} is not an input port", self.name))),
+        };
+
+        if read.map_err(|e| Error::io(&self.name, e))? == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+
+        Ok(Some(line))
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        let flushed = match *self.stream.borrow_mut() {
+            Stream::Stdout => io::stdout().flush(),
+            Stream::Stderr => io::stderr().flush(),
+            Stream::Writer(ref mut w) => w.flush(),
+            _ => Ok(()),
+        };
+
+        flushed.map_err(|e| Error::io(&self.name, e))
+    }
+
+    /// Flushes what was written and closes the port. The standard streams
+    /// are only flushed, and closing twice does nothing.
+    pub fn close(&self) -> Result<()> {
+        self.flush()?;
+
+        let mut stream = self.stream.borrow_mut();
+
+        if !matches!(*stream, Stream::Stdin | Stream::Stdout | Stream::Stderr) {
+            *stream = Stream::Closed;
+        }
+
+        Ok(())
+    }
+}
+
+impl MValue {
+    pub fn cast_to_port(&self) -> Result<&Port> {
+        match *self.0 {
+            MalVal::Port(ref port) => Ok(port),
+            _ => Err(Error::EvalError(format!("{} is not a port", self))),
+        }
+    }
+}
+
+/// The port a dynamic variable such as `*out*` is bound to in `env`, or
+/// the standard stream for builtins that have no environment.
+pub(crate) fn current(env: &Option<Env>, name: &str) -> Result<MValue> {
+    let port = env.as_ref().and_then(|env| env.get(name));
+
+    match (port, name) {
+        (Some(port), _) if !port.is_nil() => Ok(port),
+        (Some(_), _) => Err(Error::EvalError(format!("{} is not bound to a port", name))),
+        (None, "*in*") => Ok(Port::stdin()),
+        (None, "*err*") => Ok(Port::stderr()),
+        (None, _) => Ok(Port::stdout()),
+    }
+}
+
+/// Writes `s` to `*out*`.
+pub(crate) fn print(env: &Option<Env>, s: &str) -> Result<()> {
+    let out = current(env, "*out*")?;
+    out.cast_to_port()?.write_str(s)
+}
+
+/// `(open-input-file path)`
+pub fn open_input_file(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let path = resolve_path(&env, &args[0].cast_to_string()?)?;
+    let file = File::open(&path).map_err(|e| Error::io(&path, e))?;
+
+    Ok(Port::reader(path.display(), file))
+}
+
+/// `(open-output-file path)`, or with `:append true` to write after what
+/// the file holds.
+pub fn open_output_file(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let path = resolve_path(&env, &args[0].cast_to_string()?)?;
+    let append = args.get(1) == Some(&MValue::keyword("append")) && args.get(2).is_some_and(MValue::is_truthy);
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(&path)
+        .map_err(|e| Error::io(&path, e))?;
+
+    Ok(Port::writer(path.display(), file))
+}
+
+/// `(open-input-string s)` reads from `s`.
+pub fn open_input_string(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(Port::open("string", Stream::InputString(args[0].cast_to_string()?)))
+}
+
+/// `(open-output-string)` collects what is written to it, for
+/// `get-output-string`.
+pub fn open_output_string(_args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    Ok(Port::open("string", Stream::OutputString(String::new())))
+}
+
+pub fn get_output_string(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    match *args[0].cast_to_port()?.stream.borrow() {
+        Stream::OutputString(ref s) => Ok(MValue::string(s)),
+        _ => Err(Error::EvalError(format!("{} is not a string output port", args[0]))),
+    }
+}
+
+pub fn port_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::bool(args[0].cast_to_port().is_ok()))
+}
+
+/// The port given as the argument at `i`, or the one bound to `name`.
+fn port_arg(args: &[MValue], i: usize, env: &Option<Env>, name: &str) -> Result<MValue> {
+    match args.get(i) {
+        Some(port) => Ok(port.clone()),
+        None => current(env, name),
+    }
+}
+
+/// `(read-line)` or `(read-line port)` is the next line from `*in*` or the
+/// port, or `nil` at the end.
+pub fn read_line(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let port = port_arg(&args, 0, &env, "*in*")?;
+    let line = port.cast_to_port()?.read_line()?;
+
+    Ok(line.map_or_else(MValue::nil, MValue::string))
+}
+
+/// `(write x)` or `(write x port)` writes `x` to `*out*` or the port, as
+/// `str` would print it.
+pub fn write(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let port = port_arg(&args, 1, &env, "*out*")?;
+    port.cast_to_port()?.write_str(&args[0].pr_str(false))?;
+
+    Ok(MValue::nil())
+}
+
+/// `(flush)` or `(flush port)`
+pub fn flush(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let port = port_arg(&args, 0, &env, "*out*")?;
+    port.cast_to_port()?.flush()?;
+
+    Ok(MValue::nil())
+}
+
+pub fn close(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    args[0].cast_to_port()?.close()?;
+    Ok(MValue::nil())
+}
+
+/// `(readline prompt)` prompts for a line on the terminal, with line
+/// editing, unless `*in*` was bound to another port.
+pub fn readline(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let prompt = args[0].cast_to_string()?;
+    let input = current(&env, "*in*")?;
+
+    if !matches!(*input.cast_to_port()?.stream.borrow(), Stream::Stdin) {
+        print(&env, &prompt)?;
+        return read_line(vec![input.clone()], env);
+    }
+
+    match Editor::<()>::new().readline(&prompt) {
+        Ok(line) => Ok(MValue::string(line)),
+        Err(ReadlineError::Eof) => Ok(MValue::nil()),
+        Err(err) => Err(io::Error::other(format!("Failed reading line: {:?}", err)).into()),
+    }
+}
+
+#[test]
+fn test_ports() {
+    use crate::eval::eval;
+    use crate::reader::read_form;
+    use crate::sandbox::{environment, Sandbox};
+
+    let env = environment(&Sandbox::unrestricted()).unwrap();
+    let run = |code: &str| eval(read_form().parse(code.as_bytes()).unwrap(), &env);
+
+    assert_eq!(run("(with-out-str (println \"a\" 1) (write [2]))").unwrap(), MValue::string("a 1\n[2]"));
+    assert_eq!(run("(port? *out*)").unwrap(), MValue::bool(true));
+
+    let path = std::env::temp_dir().join(format!("mal-ports-{}", std::process::id()));
+    let path = format!("{:?}", path.display().to_string());
+
+    run(&format!("(with-open (out (open-output-file {})) (write \"x\\ny\" out))", path)).unwrap();
+    assert_eq!(
+        run(&format!("(with-open (in (open-input-file {})) (list (read-line in) (read-line in) (read-line in)))", path)).unwrap(),
+        MValue::list(vec![MValue::string("x"), MValue::string("y"), MValue::nil()]));
+
+    // The port is closed and `*out*` restored even when the body throws.
+    run("(def! p (open-output-string))").unwrap();
+    assert!(run("(with-open (q p) (binding (*out* q) (throw 1)))").is_err());
+    assert!(run("(write 1 p)").is_err());
+    assert_eq!(run("(= *out* *err*)").unwrap(), MValue::bool(false));
+    assert!(run("(binding (undefined 1) nil)").is_err());
+
+    std::fs::remove_file(path.trim_matches('"')).unwrap();
+}
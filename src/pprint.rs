@@ -0,0 +1,276 @@
+//! A width-aware pretty-printer after Wadler's "A prettier printer".
+//!
+//! Values are turned into a document of text, line breaks and groups, and
+//! each group is printed on one line when it fits in the remaining width
+//! and with every line break taken otherwise. Lists whose head is a special
+//! form are laid out like code, with their bodies indented by two.
+
+use crate::core::assert_min_args;
+use crate::env::Env;
+use crate::ports;
+use crate::types::*;
+
+use std::convert::TryFrom;
+
+pub const DEFAULT_WIDTH: usize = 80;
+
+enum Doc {
+    Text(String),
+    /// A space when its group is flat, a newline otherwise.
+    Line,
+    /// Indents the lines of the document by some more columns.
+    Nest(usize, Box<Doc>),
+    /// Indents the lines of the document to the column it starts at.
+    Align(Box<Doc>),
+    Concat(Vec<Doc>),
+    Group(Box<Doc>),
+}
+
+use Doc::*;
+
+fn text<T: ToString>(s: T) -> Doc {
+    Text(s.to_string())
+}
+
+fn group(docs: Vec<Doc>) -> Doc {
+    Group(Box::new(Concat(docs)))
+}
+
+fn join(docs: Vec<Doc>, separator: fn() -> Doc) -> Doc {
+    let mut joined = vec![];
+
+    for (i, doc) in docs.into_iter().enumerate() {
+        if i > 0 {
+            joined.push(separator());
+        }
+        joined.push(doc);
+    }
+
+    Concat(joined)
+}
+
+fn collection(open: &str, items: Vec<Doc>, close: &str) -> Doc {
+    group(vec![text(open), Align(Box::new(join(items, || Line))), text(close)])
+}
+
+fn pairs(values: &[MValue]) -> Vec<Doc> {
+    values.chunks(2)
+        .map(|pair| match pair {
+            [k, v] => group(vec![to_doc(k), Nest(2, Box::new(Concat(vec![Line, to_doc(v)])))]),
+            _ => to_doc(&pair[0]),
+        })
+        .collect()
+}
+
+/// How many arguments of a special form stay on the line of its name; the
+/// rest are the body.
+fn special_arguments(head: &str) -> Option<usize> {
+    match head {
+        "do" | "cond" | "try*" => Some(0),
+        "if" | "fn*" | "let*" | "def!" | "defmacro!" | "catch*" | "lazy-seq"
+        | "binding" | "with-open" => Some(1),
+        _ => None,
+    }
+}
+
+fn form(l: &[MValue]) -> Doc {
+    let head = match l.first() {
+        Some(head) => head,
+        None => return text("()"),
+    };
+
+    let name = match *head.0 {
+        MalVal::Sym(ref s) => Some(s.as_str()),
+        _ => None,
+    };
+
+    let special = match name.and_then(special_arguments) {
+        Some(n) => n.min(l.len() - 1),
+        None if l.len() == 1 => return group(vec![text("("), to_doc(head), text(")")]),
+        None => return group(vec![text("("), Align(Box::new(Concat(vec![
+            to_doc(head), text(" "), Align(Box::new(join(l[1..].iter().map(to_doc).collect(), || Line))),
+        ]))), text(")")]),
+    };
+
+    let mut line = vec![to_doc(head)];
+    for arg in l[1..=special].iter() {
+        line.push(text(" "));
+        line.push(match name {
+            Some("let*") => bindings(arg),
+            _ => to_doc(arg),
+        });
+    }
+
+    let body = &l[special + 1..];
+    let body = match name {
+        Some("cond") => pairs(body),
+        _ => body.iter().map(to_doc).collect(),
+    };
+
+    if !body.is_empty() {
+        line.push(Nest(1, Box::new(Concat(vec![Line, join(body, || Line)]))));
+    }
+
+    group(vec![text("("), Align(Box::new(Concat(line))), text(")")])
+}
+
+/// The binding vector of `let*`, one name and value per line when broken.
+fn bindings(value: &MValue) -> Doc {
+    match *value.0 {
+        MalVal::Vector(ref l, _) => collection("[", pairs(l), "]"),
+        MalVal::List(ref l, _) => collection("(", pairs(l), ")"),
+        _ => to_doc(value),
+    }
+}
+
+fn to_doc(value: &MValue) -> Doc {
+    match *value.0 {
+        MalVal::List(ref l, _) => form(l),
+        MalVal::Vector(ref l, _) => collection("[", l.iter().map(to_doc).collect(), "]"),
+        MalVal::Set(ref s, _) => collection("#{", s.values().map(to_doc).collect(), "}"),
+        MalVal::SortedSet(ref s, _) => collection("#{", s.keys().iter().map(to_doc).collect(), "}"),
+        MalVal::HashMap(ref hm, _) => {
+            let entries = hm.iter()
+                .map(|(k, v)| MValue::reconstruct(k).map(|k| vec![k, v.clone()]))
+                .collect::<Result<Vec<_>>>();
+
+            match entries {
+                Ok(entries) => collection("{", pairs(&entries.concat()), "}"),
+                Err(_) => text(value.pr_str(true)),
+            }
+        },
+        MalVal::SortedMap(ref s, _) => {
+            let entries: Vec<MValue> = s.iter()
+                .flat_map(|(k, v)| vec![k.clone(), v.clone()])
+                .collect();
+            collection("{", pairs(&entries), "}")
+        },
+        MalVal::LazySeq(_) => match value.cast_to_list() {
+            Ok(l) => collection("(", l.iter().map(to_doc).collect(), ")"),
+            Err(_) => text(value.pr_str(true)),
+        },
+        _ => text(value.pr_str(true)),
+    }
+}
+
+type Command<'a> = (usize, bool, &'a Doc);
+
+/// Whether `next` fits in `width` columns when laid out flat, followed by
+/// `rest` up to its first line break.
+fn fits(width: isize, next: Command, rest: &[Command]) -> bool {
+    let mut width = width;
+    let mut stack = vec![next];
+    let mut rest = rest.iter().rev();
+
+    while width >= 0 {
+        let (i, flat, doc) = match stack.pop().or_else(|| rest.next().copied()) {
+            Some(command) => command,
+            None => return true,
+        };
+
+        match doc {
+            Text(s) => width -= isize::try_from(s.chars().count()).unwrap_or(isize::MAX),
+            Line if flat => width -= 1,
+            Line => return true,
+            Nest(j, d) => stack.push((i + j, flat, d)),
+            Align(d) | Group(d) => stack.push((i, flat, d)),
+            Concat(docs) => stack.extend(docs.iter().rev().map(|d| (i, flat, d))),
+        }
+    }
+
+    false
+}
+
+fn layout(doc: &Doc, width: usize) -> String {
+    let mut out = String::new();
+    let mut column = 0;
+    let mut stack: Vec<Command> = vec![(0, false, doc)];
+
+    while let Some((i, flat, doc)) = stack.pop() {
+        match doc {
+            Text(s) => {
+                out.push_str(s);
+                column += s.chars().count();
+            },
+            Line if flat => {
+                out.push(' ');
+                column += 1;
+            },
+            Line => {
+                out.push('\n');
+                out.push_str(&" ".repeat(i));
+                column = i;
+            },
+            Nest(j, d) => stack.push((i + j, flat, d)),
+            Align(d) => stack.push((column, flat, d)),
+            Concat(docs) => stack.extend(docs.iter().rev().map(|d| (i, flat, d))),
+            Group(d) => {
+                let room = width.saturating_sub(column);
+                let flat = flat || fits(isize::try_from(room).unwrap_or(isize::MAX), (i, true, d), &stack);
+                stack.push((i, flat, d));
+            },
+        }
+    }
+
+    out
+}
+
+/// Prints `value` readably, breaking lines to stay within `width` columns
+/// where it can.
+pub fn pretty(value: &MValue, width: usize) -> String {
+    layout(&to_doc(value), width)
+}
+
+fn width_arg(args: &[MValue]) -> Result<usize> {
+    match args.get(1) {
+        Some(w) => Ok(usize::try_from(w.cast_to_int()?)?),
+        None => Ok(DEFAULT_WIDTH),
+    }
+}
+
+/// `(pprint-str x)` or `(pprint-str x width)`
+pub fn pprint_str(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::string(pretty(&args[0], width_arg(&args)?)))
+}
+
+/// `(pprint x)` or `(pprint x width)`
+pub fn pprint(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    ports::print(&env, &format!("{}\n", pretty(&args[0], width_arg(&args)?)))?;
+    ports::current(&env, "*out*")?.cast_to_port()?.flush()?;
+
+    Ok(MValue::nil())
+}
+
+#[test]
+fn test_pretty() {
+    use crate::reader::read_form;
+
+    let read = |s: &str| read_form().parse(s.as_bytes()).unwrap();
+
+    let code = read("(fn* [x] (let* [a (+ x 1) b (* a 2)] (if (> b 10) (cond (= b 12) :twelve :else :big) b)))");
+    assert_eq!(pretty(&code, 40), "\
+(fn* [x]
+  (let* [a (+ x 1) b (* a 2)]
+    (if (> b 10)
+      (cond (= b 12) :twelve :else :big)
+      b)))");
+    assert_eq!(pretty(&code, 24), "\
+(fn* [x]
+  (let* [a (+ x 1)
+         b (* a 2)]
+    (if (> b 10)
+      (cond
+        (= b 12) :twelve
+        :else :big)
+      b)))");
+
+    let data = read("{:name \"pprint\" :tags [:wadler :layout] :nested {:a 1 :b 2}}");
+    assert_eq!(pretty(&data, 80), data.pr_str(true));
+    assert_eq!(pretty(&data, 30), "\
+{:name \"pprint\"
+ :tags [:wadler :layout]
+ :nested {:a 1 :b 2}}");
+}
@@ -3,6 +3,88 @@ use pom::char_class::*;
 
 use crate::types::*;
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Reads a `#` dispatch macro from `input` at `start`, which is right after
+/// the `#` so the reader sees the dispatch character itself. Returns `None`
+/// when the macro reads as nothing (like `#_`), along with the end position.
+pub type DispatchReader = fn(&[u8], usize) -> pom::Result<(Option<MValue>, usize)>;
+
+thread_local! {
+    static DISPATCH_TABLE: RefCell<HashMap<u8, DispatchReader>> = RefCell::new(default_dispatch_table());
+    static LINES: RefCell<Vec<Lines>> = const { RefCell::new(vec![]) };
+}
+
+/// Where the lines of an input being read start, found the first time a
+/// position in it is needed and kept until reading it is done.
+struct Lines {
+    input: (*const u8, usize),
+    starts: Option<Vec<usize>>,
+}
+
+/// Ends the reading of an input, even when a reader panics.
+struct Reading;
+
+impl Drop for Reading {
+    fn drop(&mut self) {
+        LINES.with(|lines| lines.borrow_mut().pop());
+    }
+}
+
+/// Reads with `parser`, sharing the line starts of the input with every
+/// form read from it until the outermost one is done.
+fn reading<'a, T: 'a>(parser: Parser<'a, u8, T>) -> Parser<'a, u8, T> {
+    Parser::new(move |input: &'a [u8], start: usize| {
+        let key = (input.as_ptr(), input.len());
+        let started = LINES.with(|lines| {
+            let mut lines = lines.borrow_mut();
+            let reading = lines.last().is_some_and(|l| l.input == key);
+            if !reading {
+                lines.push(Lines { input: key, starts: None });
+            }
+            !reading
+        });
+
+        let _reading = started.then(|| Reading);
+        parser.parse_at(input, start)
+    })
+}
+
+/// The line and column of `offset`, counting from one, in an input being
+/// read.
+fn position(input: &[u8], offset: usize) -> (usize, usize) {
+    LINES.with(|lines| {
+        let mut lines = lines.borrow_mut();
+        let lines = lines.last_mut().expect("positions are only needed while reading");
+        let starts = lines.starts.get_or_insert_with(|| {
+            let newlines = input.iter().enumerate().filter(|&(_, &c)| c == b'\n');
+            std::iter::once(0).chain(newlines.map(|(i, _)| i + 1)).collect()
+        });
+
+        let line = starts.partition_point(|&start| start <= offset);
+        (line, offset - starts[line - 1] + 1)
+    })
+}
+
+fn default_dispatch_table() -> HashMap<u8, DispatchReader> {
+    let mut table: HashMap<u8, DispatchReader> = HashMap::new();
+    table.insert(b'_', read_discard);
+    table.insert(b'(', read_anonymous_fn);
+    table.insert(b'"', read_regex);
+    table.insert(b'\'', read_var_quote);
+    table.insert(b'?', read_conditional);
+    table.insert(b'{', read_set);
+    table.insert(b'!', read_shebang);
+    table.insert(b'#', read_symbolic_value);
+    table
+}
+
+/// Registers (or replaces) the reader used for `#` followed by `c`.
+pub fn register_dispatch(c: u8, reader: DispatchReader) {
+    DISPATCH_TABLE.with(|table| table.borrow_mut().insert(c, reader));
+}
+
 fn spaces<'a>() -> Parser<'a, u8, ()> {
     one_of(b" \n\r,").repeat(0..).discard()
 }
@@ -13,7 +95,13 @@ fn comment<'a>() -> Parser<'a, u8, ()> {
 
 
 fn ignored<'a>() -> Parser<'a, u8, ()> {
-    comment() | spaces()
+    let blank = one_of(b" \n\r,").repeat(1..).discard();
+    let nothing = read_dispatch().convert(|v| match v {
+        None => Ok(()),
+        Some(_) => Err("dispatch macro produced a form"),
+    });
+
+    (comment() | blank | nothing).repeat(0..).discard()
 }
 
 fn symbol(term: u8) -> bool {
@@ -29,8 +117,25 @@ fn escaped<'a>() -> Parser<'a, u8, u8> {
     })
 }
 
+fn form<'a>() -> Parser<'a, u8, MValue> {
+    let dispatch = read_dispatch().convert(|v| v.ok_or("dispatch macro produced no form"));
+
+    dispatch | read_atom() | read_list() | read_macro() | read_vector() | read_hashmap()
+}
+
 pub fn read_form<'a>() -> Parser<'a, u8, MValue> {
-    ignored() * (read_atom() | read_list() | read_macro() | read_vector() | read_hashmap()) - ignored()
+    reading(ignored() * form() - ignored())
+}
+
+/// Every form in the input, as a whole program is read.
+pub fn read_all<'a>() -> Parser<'a, u8, Vec<MValue>> {
+    reading(ignored() * read_form().repeat(0..) - end())
+}
+
+/// Where the form that starts at `start` ends, to cut its text out of the
+/// source it was read from.
+pub fn form_end(input: &[u8], start: usize) -> Option<usize> {
+    reading(form()).parse_at(input, start).ok().map(|(_, end)| end)
 }
 
 fn delimited<'a, T>(
@@ -43,8 +148,22 @@ where
     start * ignored() * list(elem, ignored()) - ignored() - end
 }
 
+/// Lists carry their `:line` and `:column` as metadata, so that
+/// definitions can tell where they came from.
 fn read_list<'a>() -> Parser<'a, u8, MValue> {
-    delimited(sym(b'('), sym(b')'), call(read_form)).map(MValue::list)
+    let list = delimited(sym(b'('), sym(b')'), call(read_form)).map(MValue::list);
+
+    reading(Parser::new(move |input: &'a [u8], start: usize| {
+        let (value, end) = list.parse_at(input, start)?;
+        let (line, column) = position(input, start);
+
+        let position = MValue::hashmap(&mut vec![
+            MValue::keyword("line"), MValue::integer(line as i64),
+            MValue::keyword("column"), MValue::integer(column as i64),
+        ]);
+
+        Ok((value.with_meta(position).unwrap(), end))
+    }))
 }
 
 fn read_vector<'a>() -> Parser<'a, u8, MValue> {
@@ -61,6 +180,10 @@ fn read_atom<'a>() -> Parser<'a, u8, MValue> {
 }
 
 fn read_number<'a>() -> Parser<'a, u8, MValue> {
+    read_float() | read_integer()
+}
+
+fn read_integer<'a>() -> Parser<'a, u8, MValue> {
     let p = sym(b'-').opt() + one_of(b"1234567890").repeat(1..);
 
     p.collect()
@@ -70,6 +193,20 @@ fn read_number<'a>() -> Parser<'a, u8, MValue> {
      .map(MValue::integer)
 }
 
+fn read_float<'a>() -> Parser<'a, u8, MValue> {
+    let digits = || one_of(b"1234567890").repeat(1..);
+    let exponent = || one_of(b"eE") + one_of(b"+-").opt() + digits();
+    let fraction = sym(b'.') + digits();
+    let p = (sym(b'-').opt() + digits() + (fraction + exponent().opt()).discard())
+        | (sym(b'-').opt() + digits() + exponent().discard());
+
+    p.collect()
+     .map(|k| k.to_vec() )
+     .convert(String::from_utf8)
+     .convert(|k| k.parse())
+     .map(MValue::float)
+}
+
 fn read_metadata<'a>() -> Parser<'a, u8, MValue> {
     let p = sym(b'^') * call(read_form) + call(read_form);
     p.map(|(mv1, mv2)| {
@@ -87,6 +224,156 @@ fn read_macro<'a>() -> Parser<'a, u8, MValue> {
         | read_metadata()
 }
 
+fn read_dispatch<'a>() -> Parser<'a, u8, Option<MValue>> {
+    Parser::new(|input: &'a [u8], start: usize| {
+        let reader = match input.get(start..start + 2) {
+            Some(&[b'#', c]) => DISPATCH_TABLE.with(|table| table.borrow().get(&c).cloned()),
+            _ => None,
+        };
+
+        match reader {
+            Some(reader) => reader(input, start + 1),
+            None => Err(pom::Error::Mismatch {
+                message: "expected dispatch macro".to_string(),
+                position: start,
+            }),
+        }
+    })
+}
+
+fn read_discard(input: &[u8], start: usize) -> pom::Result<(Option<MValue>, usize)> {
+    let p = sym(b'_') * call(read_form);
+    p.map(|_| None).parse_at(input, start)
+}
+
+/// `#!` comments out the rest of the line, so scripts can start with a
+/// shebang line.
+fn read_shebang(input: &[u8], start: usize) -> pom::Result<(Option<MValue>, usize)> {
+    let p = sym(b'!') * none_of(b"\r\n").repeat(0..);
+    p.map(|_| None).parse_at(input, start)
+}
+
+/// `##Inf`, `##-Inf` and `##NaN` are the floats that have no digits to
+/// print, as the printer writes them.
+fn read_symbolic_value(input: &[u8], start: usize) -> pom::Result<(Option<MValue>, usize)> {
+    let p = sym(b'#') * call(read_symbol);
+    p.convert(|v| match v.cast_to_string().as_deref() {
+        Ok("Inf") => Ok(Some(MValue::float(f64::INFINITY))),
+        Ok("-Inf") => Ok(Some(MValue::float(f64::NEG_INFINITY))),
+        Ok("NaN") => Ok(Some(MValue::float(f64::NAN))),
+        _ => Err("unknown symbolic value"),
+    }).parse_at(input, start)
+}
+
+/// Sets can only hold values that work as hash keys, so a literal with
+/// other elements, like `#{(inc x)}`, reads as a call to `hash-set` instead.
+fn read_set(input: &[u8], start: usize) -> pom::Result<(Option<MValue>, usize)> {
+    delimited(sym(b'{'), sym(b'}'), call(read_form))
+        .map(|values| {
+            MValue::set(values.clone()).unwrap_or_else(|_| {
+                let mut v = vec![MValue::symbol("hash-set")];
+                v.extend(values);
+                MValue::list(v)
+            })
+        })
+        .map(Some)
+        .parse_at(input, start)
+}
+
+fn read_var_quote(input: &[u8], start: usize) -> pom::Result<(Option<MValue>, usize)> {
+    let p = sym(b'\'') * call(read_symbol);
+    p.map(|mv| {
+        let v = vec![MValue::symbol("var"), mv];
+        Some(MValue::list(v))
+    }).parse_at(input, start)
+}
+
+/// Regex literals are read raw: only `\"` is unescaped, since it's needed to
+/// embed a quote, and every other backslash is left for the regex engine.
+fn read_regex(input: &[u8], start: usize) -> pom::Result<(Option<MValue>, usize)> {
+    let quote = sym(b'\\') * sym(b'\"');
+    let escape = sym(b'\\') + take(1);
+    let p = sym(b'\"')
+        * (quote.collect().map(|_| &b"\""[..]) | escape.collect() | none_of(b"\\\"").collect())
+            .repeat(0..)
+        - sym(b'\"');
+
+    p.map(|parts| parts.concat())
+     .convert(String::from_utf8)
+     .convert(|pattern| MValue::regex(&pattern))
+     .map(Some)
+     .parse_at(input, start)
+}
+
+fn read_anonymous_fn(input: &[u8], start: usize) -> pom::Result<(Option<MValue>, usize)> {
+    read_list().convert(|body| {
+        let mut arity = 0;
+        let mut variadic = false;
+        let body = anonymous_fn_args(&body, &mut arity, &mut variadic)?;
+
+        let mut parameters = (1..=arity)
+            .map(|i| MValue::symbol(format!("%{}", i)))
+            .collect::<Vec<_>>();
+
+        if variadic {
+            parameters.push(MValue::symbol("&"));
+            parameters.push(MValue::symbol("%&"));
+        }
+
+        let v = vec![MValue::symbol("fn*"), MValue::vector(parameters), body];
+        Ok::<_, Error>(Some(MValue::list(v)))
+    }).parse_at(input, start)
+}
+
+/// Renames `%` to `%1` while recording the highest `%n` and whether `%&`
+/// appears, which together give the arity of an anonymous function.
+fn anonymous_fn_args(form: &MValue, arity: &mut usize, variadic: &mut bool) -> Result<MValue> {
+    match *form.0 {
+        MalVal::Sym(ref s) if s == "%" => {
+            *arity = (*arity).max(1);
+            Ok(MValue::symbol("%1"))
+        },
+        MalVal::Sym(ref s) if s == "%&" => {
+            *variadic = true;
+            Ok(form.clone())
+        },
+        MalVal::Sym(ref s) if s.starts_with('%') => {
+            if let Ok(n) = s[1..].parse::<usize>() {
+                *arity = (*arity).max(n);
+            }
+            Ok(form.clone())
+        },
+        MalVal::List(ref l, _) => l.iter()
+            .map(|v| anonymous_fn_args(v, arity, variadic))
+            .collect::<Result<_>>()
+            .map(MValue::list),
+        MalVal::Vector(ref l, _) => l.iter()
+            .map(|v| anonymous_fn_args(v, arity, variadic))
+            .collect::<Result<_>>()
+            .map(MValue::vector),
+        _ => Ok(form.clone()),
+    }
+}
+
+/// Selects the branch of `#?(...)` for the first feature this reader
+/// supports, `:yul` or `:default`. With no matching branch it reads as nothing.
+fn read_conditional(input: &[u8], start: usize) -> pom::Result<(Option<MValue>, usize)> {
+    let p = sym(b'?') * delimited(sym(b'('), sym(b')'), call(read_form));
+
+    p.convert(|branches| {
+        if branches.len() % 2 != 0 {
+            return Err("reader conditional requires an even number of forms");
+        }
+
+        let selected = branches
+            .chunks(2)
+            .find(|pair| pair[0] == MValue::keyword("yul") || pair[0] == MValue::keyword("default"))
+            .map(|pair| pair[1].clone());
+
+        Ok(selected)
+    }).parse_at(input, start)
+}
+
 fn read_quote<'a>() -> Parser<'a, u8, MValue> {
     let p = sym(b'\'') * call(read_form);
     p.map(|mv| {
@@ -159,6 +446,26 @@ fn read_symbol<'a>() -> Parser<'a, u8, MValue> {
      })
 }
 
+#[test]
+fn test_dispatch() {
+    let value = read_form().parse("(1 #_ 2 #?(:clj 3 :yul 4) #?(:clj 5))".as_bytes()).unwrap();
+    assert_eq!(value, MValue::list(vec![MValue::integer(1), MValue::integer(4)]));
+
+    let value = read_form().parse("#(+ % %3 %&)".as_bytes()).unwrap();
+    let expected = read_form().parse("(fn* [%1 %2 %3 & %&] (+ %1 %3 %&))".as_bytes()).unwrap();
+    assert_eq!(value, expected);
+
+    let value = read_form().parse(r#"#"\d+\"""#.as_bytes()).unwrap();
+    let expected = MValue::regex(r#"\d+""#).unwrap();
+    assert_eq!(value, expected);
+
+    for x in [f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+        let printed = MValue::float(x).pr_str(true);
+        let value = read_form().parse(printed.as_bytes()).unwrap();
+        assert_eq!(value.pr_str(true), printed);
+    }
+}
+
 #[test]
 fn test_read_keyword() {
     let value = read_keyword().parse(":ok".as_bytes()).unwrap();
@@ -181,4 +488,33 @@ fn test_comment() {
     assert_eq!(value, MValue::list(
             vec![MValue::symbol("+"),
                  MValue::integer(3), MValue::integer(5)]));
+
+    let value = read_form().parse("#!/usr/bin/env mal\n(+ 3 5)".as_bytes()).unwrap();
+    assert_eq!(value, MValue::list(
+            vec![MValue::symbol("+"),
+                 MValue::integer(3), MValue::integer(5)]));
+}
+
+#[test]
+fn test_positions() {
+    let forms = read_all().parse("; two forms\n(a)\n  [(b)]".as_bytes()).unwrap();
+    let position = |line, column| MValue::hashmap(&mut vec![
+        MValue::keyword("line"), MValue::integer(line),
+        MValue::keyword("column"), MValue::integer(column),
+    ]);
+
+    assert_eq!(forms[0].meta().unwrap(), position(2, 1));
+    assert_eq!(forms[1].cast_to_list().unwrap()[0].meta().unwrap(), position(3, 4));
+    assert_eq!(form_end(b"x (f [1]) y", 2), Some(9));
+
+    let source = "(a)\n".repeat(5000) + "  (z)";
+    let forms = read_all().parse(source.as_bytes()).unwrap();
+    assert_eq!(forms[5000].meta().unwrap(), position(5001, 3));
+    assert_eq!(read_form().parse(b"\n\n (c)").unwrap().meta().unwrap(), position(3, 2));
+}
+
+#[test]
+fn test_map_order() {
+    let value = read_form().parse("{:z 1 \"a\" 2 3 #{:c :b}}".as_bytes()).unwrap();
+    assert_eq!(value.pr_str(true), "{:z 1 \"a\" 2 3 #{:c :b}}");
 }
@@ -0,0 +1,556 @@
+//! Assembling environments from groups of builtins.
+//!
+//! Builtins are grouped by the capability they need, and an environment
+//! only gets the groups its `Sandbox` grants, so code run in it can't reach
+//! the others at all. Referring to a builtin it lacks fails with
+//! `Error::PermissionDenied` naming the capability rather than as an
+//! unknown symbol. File access can further be kept to some directories.
+
+use crate::core::*;
+use crate::docs;
+use crate::edn;
+use crate::env::Env;
+use crate::eval::eval;
+use crate::files;
+use crate::gc;
+use crate::ports::{self, Port};
+use crate::pprint;
+use crate::reader::read_form;
+use crate::sequences;
+use crate::sorted;
+use crate::strings;
+use crate::transducers;
+use crate::types::*;
+
+use std::fmt::{self, Display};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Computing with values, which can't affect anything outside.
+    Pure,
+    FsRead,
+    FsWrite,
+    /// Running other programs and reaching the process environment.
+    Process,
+    /// Printing and reading lines.
+    Terminal,
+    /// Reading the clock.
+    Time,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Capability::Pure,
+        Capability::FsRead,
+        Capability::FsWrite,
+        Capability::Process,
+        Capability::Terminal,
+        Capability::Time,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Pure => "pure",
+            Capability::FsRead => "fs-read",
+            Capability::FsWrite => "fs-write",
+            Capability::Process => "process",
+            Capability::Terminal => "terminal",
+            Capability::Time => "time",
+        }
+    }
+
+    /// The capability a builtin was left out for, if it is one.
+    fn of_builtin(name: &str) -> Option<Capability> {
+        match name {
+            "slurp" | "load-file" | "file-exists?" | "directory?" | "list-dir" | "file-size"
+                | "file-mtime" | "glob" | "read-lines" | "read-bytes" | "open-input-file" | "source" => Some(Capability::FsRead),
+            "spit" | "mkdir" | "delete-file" | "rename-file" | "temp-file"
+                | "open-output-file" => Some(Capability::FsWrite),
+            "prn" | "println" | "printf" | "pprint" | "readline" => Some(Capability::Terminal),
+            "sh" | "process" | "process-wait" | "process-kill" | "getenv" | "setenv" | "env-vars"
+                | "exit" => Some(Capability::Process),
+            "time-ms" => Some(Capability::Time),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// What an environment may do.
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    pub capabilities: Vec<Capability>,
+    /// The directories files may be read and written in, or any when
+    /// `None`.
+    pub allowed_paths: Option<Vec<PathBuf>>,
+    /// Whether to define what is written in Lisp, such as `cond`, `or`,
+    /// `with-out-str` and `load-file`.
+    pub prelude: bool,
+}
+
+impl Sandbox {
+    /// Everything, as the REPL has.
+    pub fn unrestricted() -> Self {
+        Sandbox { capabilities: Capability::ALL.to_vec(), allowed_paths: None, prelude: true }
+    }
+
+    /// Nothing but computing with values.
+    pub fn pure() -> Self {
+        Sandbox { capabilities: vec![Capability::Pure], allowed_paths: None, prelude: true }
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+/// The error for a symbol that isn't bound, which for a builtin left out of
+/// the environment says what it would have needed.
+pub(crate) fn unbound(name: String) -> Error {
+    match Capability::of_builtin(&name) {
+        Some(capability) => Error::PermissionDenied(
+            format!("{} needs the {} capability, which this environment lacks", name, capability)),
+        None => Error::NoSymbolFound(name),
+    }
+}
+
+/// Resolves links and `..` in what exists of `path`. The rest is yet to be
+/// created, and can't have a `..` that would have to be taken on trust.
+fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+    let mut existing = if path.is_absolute() { PathBuf::new() } else { PathBuf::from(".") };
+    let mut missing = vec![];
+
+    for component in path.components() {
+        let next = existing.join(component);
+        let root = matches!(component, Component::Prefix(_) | Component::RootDir);
+
+        if missing.is_empty() && (root || next.exists()) {
+            existing = next;
+        } else if component == Component::ParentDir {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound,
+                "a directory before .. does not exist"));
+        } else {
+            missing.push(component);
+        }
+    }
+
+    let mut resolved = existing.canonicalize()?;
+    resolved.extend(missing);
+    Ok(resolved)
+}
+
+/// Where `path` leads, if the sandbox of a file builtin allows it. Links
+/// and `..` are resolved first, so they can't lead out of the allowed
+/// directories.
+pub(crate) fn resolve_path(env: &Option<Env>, path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    let resolved = canonicalize(path).map_err(|e| Error::io(path, e))?;
+
+    let allowed = match env.as_ref().and_then(|env| env.get("allowed-paths")) {
+        Some(allowed) if !allowed.is_nil() => allowed.cast_to_list()?,
+        _ => return Ok(resolved),
+    };
+
+    for dir in allowed {
+        if resolved.starts_with(dir.cast_to_string()?) {
+            return Ok(resolved);
+        }
+    }
+
+    Err(Error::PermissionDenied(format!("{} is outside the allowed paths", path.display())))
+}
+
+/// Registers a builtin with its arglists and docstring, for `doc`.
+fn set(env: &Env, name: &str, f: FnExpr, arglists: &str, doc: &str) {
+    env.set(name, docs::document(MValue::function(f, None), name, arglists, doc));
+}
+
+/// Registers a builtin that is called with this environment, to look up
+/// `*out*` and the like when it runs.
+fn set_env(env: &Env, name: &str, f: FnExpr, arglists: &str, doc: &str) {
+    env.set(name, docs::document(MValue::function(f, Some(env.clone())), name, arglists, doc));
+}
+
+fn pure(env: &Env) {
+    set(env, "+", add, "[& xs]", "Returns the sum of the numbers, or 0.");
+    set(env, "-", sub, "[x & ys]", "Subtracts the ys from x, or negates x when there are none.");
+    set(env, "*", mul, "[& xs]", "Returns the product of the numbers, or 1.");
+    set(env, "/", div, "[x y & more]", "Divides x by y, then by each of the rest in turn.");
+    set(env, "list", list, "[& items]", "Returns a list of the arguments.");
+    set(env, "vector", vector, "[& items]", "Returns a vector of the arguments.");
+    set(env, "hash-map", hashmap, "[& kvs]", "Returns a map of the keys and values given in turn.");
+    set(env, "symbol", symbol, "[name]", "Returns the symbol with this name.");
+    set(env, "keyword", keyword, "[name]", "Returns the keyword with this name.");
+    set(env, "list?", list_q, "[x]", "Returns true if x is a list.");
+    set(env, "vector?", vector_q, "[x]", "Returns true if x is a vector.");
+    set(env, "sequential?", sequential_q, "[x]", "Returns true if x is a list or a vector.");
+    set(env, "map?", map_q, "[x]", "Returns true if x is a map.");
+    set(env, "empty?", empty_q, "[coll]", "Returns true if coll has no elements.");
+    set(env, "assoc", assoc, "[map & kvs]", "Returns map with the keys mapped to the values.");
+    set(env, "dissoc", dissoc, "[map & ks]", "Returns map without the keys.");
+    set(env, "count", count, "[coll]", "Returns the number of elements in coll.");
+    set(env, "=", eq, "[x y & more]", "Returns true if the values are equal.");
+    set(env, ">", gt, "[x y & more]", "Returns true if the numbers are in decreasing order.");
+    set(env, "<", lt, "[x y & more]", "Returns true if the numbers are in increasing order.");
+    set(env, ">=", gte, "[x y & more]", "Returns true if the numbers are in non-increasing order.");
+    set(env, "<=", lte, "[x y & more]", "Returns true if the numbers are in non-decreasing order.");
+    set_env(env, "pr-str", print_str, "[& xs]", "Prints the values readably, separated by spaces, to a string.");
+    set_env(env, "str", string, "[& xs]", "Concatenates the values, printing strings as they are.");
+    set(env, "format", format, "[fmt & args]", "Formats the arguments into the format string.");
+    set(env, "read-string", read_str, "[s]", "Reads the first form in s.");
+    set(env, "atom", atom, "[x]", "Returns an atom holding x.");
+    set(env, "atom?", atom_q, "[x]", "Returns true if x is an atom.");
+    set(env, "deref", deref, "[atom]", "Returns the value the atom holds.");
+    set(env, "reset!", reset, "[atom x]", "Sets the value of the atom to x and returns it.");
+    set_env(env, "swap!", swap, "[atom f & args]", "Sets the value of the atom to (apply f value args) and returns it.");
+    set(env, "cons", cons, "[x coll]", "Returns a list of x followed by the elements of coll.");
+    set(env, "concat", concat, "[& colls]", "Returns a list of the elements of each collection in turn.");
+    set(env, "nth", nth, "[coll index]", "Returns the element of coll at index.");
+    set(env, "first", first, "[coll]", "Returns the first element of coll, or nil.");
+    set(env, "rest", rest, "[coll]", "Returns a list of the elements of coll after the first.");
+    set(env, "throw", throw, "[x]", "Throws x, to be caught by try*.");
+    set(env, "apply", apply, "[f & args]", "Calls f with the arguments, the last of which is a collection of more.");
+    set(env, "map", sequences::map, "[f] [f coll & colls]",
+        "Returns a lazy sequence of f called with one element of each collection, stopping at the \
+         shortest. Without a collection, returns a transducer.");
+    set(env, "reduce", sequences::reduce, "[f coll] [f init coll]",
+        "Combines the elements of coll with f, starting from init or the first element. Stops early \
+         when f returns a reduced value.");
+    set(env, "filter", sequences::filter, "[pred] [pred coll]",
+        "Returns a lazy sequence of the elements for which pred is truthy, or a transducer.");
+    set(env, "remove", sequences::remove, "[pred] [pred coll]",
+        "Returns a lazy sequence of the elements for which pred is falsy, or a transducer.");
+    set(env, "some", sequences::some, "[pred coll]", "Returns the first truthy (pred x), or nil.");
+    set(env, "every?", sequences::every_q, "[pred coll]", "Returns true if pred holds for every element.");
+    set(env, "take", sequences::take, "[n] [n coll]",
+        "Returns a lazy sequence of the first n elements, or a transducer.");
+    set(env, "drop", sequences::drop, "[n] [n coll]",
+        "Returns a lazy sequence of the elements after the first n, or a transducer.");
+    set(env, "take-while", sequences::take_while, "[pred] [pred coll]",
+        "Returns a lazy sequence of the elements before the first for which pred is falsy, or a \
+         transducer.");
+    set(env, "drop-while", sequences::drop_while, "[pred] [pred coll]",
+        "Returns a lazy sequence of the elements from the first for which pred is falsy, or a \
+         transducer.");
+    set(env, "partition", sequences::partition, "[n coll] [n step coll] [n step pad coll]",
+        "Returns groups of n elements, step apart. Without pad a short last group is dropped; with \
+         it the group is filled from pad.");
+    set(env, "group-by", sequences::group_by, "[f coll]",
+        "Returns a map of each (f x) to a vector of the elements that produced it.");
+    set(env, "frequencies", sequences::frequencies, "[coll]",
+        "Returns a map of each distinct element to how often it occurs.");
+    set(env, "sort", sequences::sort, "[coll] [comparator coll]", "Returns the elements in order. The sort is stable.");
+    set(env, "sort-by", sequences::sort_by, "[keyfn coll] [keyfn comparator coll]",
+        "Returns the elements in the order of (keyfn x). The sort is stable.");
+    set(env, "distinct", sequences::distinct, "[] [coll]",
+        "Returns the elements without repeats, keeping the first of each, or a transducer.");
+    set(env, "interleave", sequences::interleave, "[c1 c2 & colls]",
+        "Returns a lazy sequence of one element of each collection in turn, stopping at the shortest.");
+    set(env, "zipmap", sequences::zipmap, "[keys vals]", "Returns a map of the keys to the values in the same places.");
+    set(env, "range", sequences::range, "[] [end] [start end] [start end step]",
+        "Returns a lazy sequence of numbers from start, by step, up to end.");
+    set(env, "repeat", sequences::repeat, "[x] [n x]", "Returns a lazy sequence of x, forever or n times.");
+    set(env, "iterate", sequences::iterate, "[f x]", "Returns a lazy sequence of x, (f x), (f (f x)) and so on.");
+    set(env, "cycle", sequences::cycle, "[coll]", "Returns a lazy sequence repeating the elements of coll forever.");
+    set(env, "doall", sequences::doall, "[coll]", "Realizes every lazy sequence in coll and returns it.");
+    set(env, "mapcat", sequences::mapcat, "[f] [f & colls]",
+        "Returns the concatenation of the collections map produces, or a transducer.");
+    env.set("cat", docs::document(transducers::cat(), "cat", "[rf]",
+        "A transducer that concatenates the collections it is given."));
+    set(env, "transduce", transducers::transduce, "[xform f coll] [xform f init coll]",
+        "Reduces coll with f transformed by xform, starting from init or (f).");
+    set(env, "into", transducers::into, "[to coll] [to xform coll]",
+        "Adds the elements of coll, transformed by xform, to to with conj.");
+    set(env, "sequence", transducers::sequence, "[coll] [xform coll]",
+        "Returns a lazy sequence of the elements of coll, transformed by xform.");
+    set(env, "eduction", transducers::eduction, "[& xforms coll]",
+        "Returns the elements of coll transformed by each of the transducers, the first outermost.");
+    set(env, "reduced", transducers::reduced, "[x]", "Wraps x to stop a reduction, which returns x.");
+    set(env, "reduced?", transducers::reduced_q, "[x]", "Returns true if x is a reduced value.");
+    set(env, "comp", comp, "[& fs]", "Returns a function that calls the functions from last to first.");
+    set(env, "identity", identity, "[x]", "Returns x.");
+    set(env, "compare", sorted::compare, "[x y]",
+        "Returns a negative number, zero or a positive number as x sorts before, with or after y.");
+    set(env, "sorted-map", sorted::sorted_map, "[& kvs]", "Returns a map that keeps its keys in order.");
+    set(env, "sorted-map-by", sorted::sorted_map_by, "[comparator & kvs]",
+        "Returns a map that keeps its keys in the order of the comparator.");
+    set(env, "sorted-set", sorted::sorted_set, "[& values]", "Returns a set that keeps its values in order.");
+    set(env, "sorted-set-by", sorted::sorted_set_by, "[comparator & values]",
+        "Returns a set that keeps its values in the order of the comparator.");
+    set(env, "subseq", sorted::subseq, "[sc test key] [sc start-test start-key end-test end-key]",
+        "Returns the entries of a sorted collection whose keys pass the tests, in order.");
+    set(env, "rsubseq", sorted::rsubseq, "[sc test key] [sc start-test start-key end-test end-key]",
+        "Returns the entries of a sorted collection whose keys pass the tests, in reverse order.");
+    set(env, "pprint-str", pprint::pprint_str, "[x] [x width]", "Pretty-prints x to a string.");
+    set(env, "symbol?", symbol_q, "[x]", "Returns true if x is a symbol.");
+    set(env, "nil?", nil_q, "[x]", "Returns true if x is nil.");
+    set(env, "true?", true_q, "[x]", "Returns true if x is true.");
+    set(env, "false?", false_q, "[x]", "Returns true if x is false.");
+    set(env, "keyword?", keyword_q, "[x]", "Returns true if x is a keyword.");
+    set(env, "get", get, "[map key]", "Returns the value of key in a map or set, or nil.");
+    set(env, "contains?", contains_q, "[coll key]", "Returns true if the map or set has key.");
+    set(env, "keys", keys, "[map]", "Returns a list of the keys of the map.");
+    set(env, "vals", values, "[map]", "Returns a list of the values of the map.");
+    set(env, "meta", meta, "[x]", "Returns the metadata of x, or nil.");
+    set(env, "with-meta", with_meta, "[x meta]", "Returns x with meta as its metadata.");
+    set(env, "fn?", fn_q, "[x]", "Returns true if x is a function, not counting macros.");
+    set(env, "string?", string_q, "[x]", "Returns true if x is a string.");
+    set(env, "number?", number_q, "[x]", "Returns true if x is a number.");
+    set(env, "macro?", macro_q, "[x]", "Returns true if x is a macro.");
+    set(env, "seq", seq, "[coll]", "Returns a list of the elements of coll, or nil when it is empty.");
+    set(env, "conj", conj, "[coll & xs]",
+        "Adds the values to coll: at the front of a list, at the end of a vector.");
+    set(env, "json-parse", json_parse, "[s]", "Reads a JSON string into maps, vectors and values.");
+    set(env, "json-stringify", json_stringify, "[x]", "Writes x as a JSON string.");
+    set(env, "hash-set", hash_set, "[& values]", "Returns a set of the arguments.");
+    set(env, "set", crate::core::set, "[coll]", "Returns a set of the elements of coll.");
+    set(env, "set?", set_q, "[x]", "Returns true if x is a set.");
+    set(env, "disj", disj, "[set & values]", "Returns set without the values.");
+    env.set("*data-readers*", edn::default_readers());
+    set_env(env, "read-edn", read_edn, "[s]", "Reads an EDN string, with the tags in *data-readers*.");
+    set(env, "write-edn", write_edn, "[x]", "Writes x as an EDN string.");
+    set_env(env, "register-tag", register_tag, "[tag f]", "Has read-edn read values tagged with tag by calling f.");
+    set(env, "re-pattern", re_pattern, "[s]", "Returns a regex for the pattern s.");
+    set(env, "regex?", regex_q, "[x]", "Returns true if x is a regex.");
+    set(env, "re-find", re_find, "[re s]",
+        "Returns the first match of re in s, with its groups as a vector, or nil.");
+    set(env, "re-matches", re_matches, "[re s]", "Returns the match of re with the whole of s, or nil.");
+    set(env, "re-seq", re_seq, "[re s]", "Returns a list of every match of re in s.");
+    set(env, "re-groups", re_groups, "[re s]", "Returns a map of the named groups of the first match of re in s.");
+    set(env, "str/split", strings::split, "[s separator] [s separator limit]",
+        "Splits s on a string or regex into at most limit parts. An empty separator splits into \
+         graphemes.");
+    set(env, "str/join", strings::join, "[coll] [separator coll]",
+        "Concatenates the elements of coll as str would, with separator between them.");
+    set(env, "str/trim", strings::trim, "[s]", "Removes whitespace from both ends of s.");
+    set(env, "str/triml", strings::triml, "[s]", "Removes whitespace from the start of s.");
+    set(env, "str/trimr", strings::trimr, "[s]", "Removes whitespace from the end of s.");
+    set(env, "str/upper-case", strings::upper_case, "[s]", "Returns s in upper case.");
+    set(env, "str/lower-case", strings::lower_case, "[s]", "Returns s in lower case.");
+    set(env, "str/starts-with?", strings::starts_with_q, "[s prefix]", "Returns true if s starts with prefix.");
+    set(env, "str/ends-with?", strings::ends_with_q, "[s suffix]", "Returns true if s ends with suffix.");
+    set(env, "str/includes?", strings::includes_q, "[s substring]", "Returns true if s contains substring.");
+    set(env, "str/index-of", strings::index_of, "[s value] [s value from]",
+        "Returns the grapheme index of the first value in s at or after from, or nil.");
+    set(env, "str/subs", strings::subs, "[s start] [s start end]",
+        "Returns the graphemes of s from start up to, but excluding, end.");
+    set(env, "str/replace", strings::replace, "[s match replacement]",
+        "Replaces every occurrence of a string or regex with a string, where $1 and ${name} refer to \
+         groups, or with what a function returns for the match.");
+    set(env, "str/split-lines", strings::split_lines, "[s]", "Splits s into lines.");
+    set(env, "str/blank?", strings::blank_q, "[s]", "Returns true if s is nil or only whitespace.");
+    set(env, "str/pad-left", strings::pad_left, "[s width] [s width pad]",
+        "Prepends pad, a space by default, until s is width graphemes long.");
+    set(env, "str/reverse", strings::reverse, "[s]", "Reverses the graphemes of s.");
+    set(env, "gc", gc::gc, "[]", "Collects garbage cycles and returns how many environments were freed and are live.");
+    set(env, "memory-stats", gc::memory_stats, "[]", "Returns the bytes used by values now and at their peak.");
+    set(env, "open-input-string", ports::open_input_string, "[s]", "Returns a port that reads from s.");
+    set(env, "open-output-string", ports::open_output_string, "[]",
+        "Returns a port that collects what is written to it, for get-output-string.");
+    set(env, "get-output-string", ports::get_output_string, "[port]", "Returns what was written to a string port.");
+    set(env, "port?", ports::port_q, "[x]", "Returns true if x is a port.");
+    set(env, "close", ports::close, "[port]", "Closes the port, flushing what was written to it.");
+    set_env(env, "read-line", ports::read_line, "[] [port]",
+        "Returns the next line from *in* or the port, or nil at the end.");
+    set_env(env, "write", ports::write, "[x] [x port]", "Writes x to *out* or the port, as str would print it.");
+    set_env(env, "flush", ports::flush, "[] [port]", "Flushes *out* or the port.");
+    // Without the terminal there is nothing to read or print to until a
+    // port is bound.
+    env.set("*in*", MValue::nil());
+    env.set("*out*", MValue::nil());
+    env.set("*err*", MValue::nil());
+    env.set("*print-level*", MValue::nil());
+    env.set("*print-length*", MValue::nil());
+    env.set("*host-language*", MValue::string("Rust"));
+    env.set("*file*", MValue::nil());
+    set_env(env, "eval", meval, "[form]", "Evaluates the form.");
+    set(env, "read-all", read_forms, "[s]", "Reads every form in s into a list.");
+    set_env(env, "doc", docs::doc, "[f]", "Prints the name, arglists and docstring of f.");
+    set_env(env, "find-doc", docs::find_doc, "[pattern]",
+        "Prints the documentation of everything whose name or docstring matches a regex or string.");
+    set(env, "arglists", docs::arglists, "[f]", "Returns the list of parameter vectors f takes, or nil.");
+}
+
+fn terminal(env: &Env) {
+    set_env(env, "prn", prn, "[& xs]", "Prints the values readably, separated by spaces, and a newline.");
+    set_env(env, "println", println, "[& xs]", "Prints the values as str would, separated by spaces, and a newline.");
+    set_env(env, "printf", printf, "[fmt & args]", "Prints the arguments formatted into the format string.");
+    set_env(env, "pprint", pprint::pprint, "[x] [x width]", "Pretty-prints x and a newline.");
+    set_env(env, "readline", ports::readline, "[prompt]",
+        "Prompts for a line on the terminal, with line editing, unless *in* is bound to another port.");
+    env.set("*in*", Port::stdin());
+    env.set("*out*", Port::stdout());
+    env.set("*err*", Port::stderr());
+}
+
+fn process(env: &Env) {
+    set(env, "sh", sh, "[cmd & args]",
+        "Runs a program to completion and returns {:exit code :out \"...\" :err \"...\"}. Takes :in, \
+         :dir, :env and :timeout options after the arguments.");
+    set(env, "process", crate::core::process, "[cmd & args]",
+        "Starts a program and returns {:pid n :in port :out port :err port}. Takes :dir and :env \
+         options after the arguments.");
+    set(env, "process-wait", process_wait, "[p] [p ms]",
+        "Waits for the process to exit and returns its exit code, or nil if it is still running after \
+         ms milliseconds.");
+    set(env, "process-kill", process_kill, "[p]", "Kills the process if it is still running.");
+    set(env, "getenv", getenv, "[name]", "Returns the value of an environment variable, or nil.");
+    set(env, "setenv", setenv, "[name value]",
+        "Sets an environment variable for this process and the programs it runs, or removes it when \
+         value is nil.");
+    set(env, "env-vars", env_vars, "[]", "Returns a map of every environment variable to its value.");
+    set(env, "exit", exit, "[] [status]", "Ends the program, with status 0 by default.");
+}
+
+fn fs_read(env: &Env, files: &Env) {
+    let set = |name: &str, f: FnExpr, arglists: &str, doc: &str|
+        env.set(name, docs::document(MValue::function(f, Some(files.clone())), name, arglists, doc));
+
+    set("slurp", slurp, "[path]", "Returns the contents of the file as a string.");
+    set("file-exists?", files::file_exists_q, "[path]", "Returns true if the file exists.");
+    set("directory?", files::directory_q, "[path]", "Returns true if path is a directory.");
+    set("list-dir", files::list_dir, "[path]", "Returns the sorted names of the entries in a directory.");
+    set("file-size", files::file_size, "[path]", "Returns the size of the file in bytes.");
+    set("file-mtime", files::file_mtime, "[path]",
+        "Returns when the file was last modified, in milliseconds since the epoch.");
+    set("glob", files::glob, "[pattern]",
+        "Returns the sorted paths matching a pattern, where * and ? match within a name and ** any \
+         number of directories.");
+    set("read-lines", files::read_lines, "[path]", "Returns a lazy sequence of the lines of a file.");
+    set("read-bytes", files::read_bytes, "[path] [path offset length]",
+        "Returns a vector of the bytes of a file, or of length of them from offset on.");
+    set("open-input-file", ports::open_input_file, "[path]", "Returns a port that reads from the file.");
+    set("source", docs::source, "[f]", "Prints the definition of f as it was written.");
+}
+
+fn fs_write(env: &Env, files: &Env) {
+    let set = |name: &str, f: FnExpr, arglists: &str, doc: &str|
+        env.set(name, docs::document(MValue::function(f, Some(files.clone())), name, arglists, doc));
+
+    set("spit", files::spit, "[path content & options]",
+        "Writes content, as str would print it, to the file, or adds it to the end with :append true.");
+    set("mkdir", files::mkdir, "[path]", "Creates a directory and any missing parents.");
+    set("delete-file", files::delete_file, "[path]", "Deletes a file or an empty directory.");
+    set("rename-file", files::rename_file, "[from to]", "Renames or moves a file.");
+    set("temp-file", files::temp_file, "[] [prefix]",
+        "Creates an empty file with a new name in the temporary directory and returns its path.");
+    set("open-output-file", ports::open_output_file, "[path & options]",
+        "Returns a port that writes to the file, after what it holds with :append true.");
+}
+
+const PRELUDE: &[&str] = &[
+    "(def! not \"Returns true if a is false or nil.\" (fn* (a) (if a false true)))",
+    "(defmacro! cond \"Evaluates the expression after the first test that is truthy.\" (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))",
+    "(def! *gensym-counter* (atom 0))",
+    "(def! gensym \"Returns a new symbol.\" (fn* [] (symbol (str \"G__\" (swap! *gensym-counter* (fn* [x] (+ 1 x)))))))",
+    "(defmacro! defn \"Defines a function, with an optional docstring before its parameters.\" (fn* (name & decl) (if (string? (first decl)) `(def! ~name ~(first decl) (fn* ~(nth decl 1) (do ~@(rest (rest decl))))) `(def! ~name (fn* ~(first decl) (do ~@(rest decl)))))))",
+    "(defmacro! with-out-str \"Returns what the body prints to *out* as a string.\" (fn* (& body) (let* (s (gensym)) `(let* (~s (open-output-string)) (do (binding (*out* ~s) ~@body) (get-output-string ~s))))))",
+    "(defmacro! or \"Returns the first truthy expression, or the last.\" (fn* (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) (let* (condvar (gensym)) `(let* (~condvar ~(first xs)) (if ~condvar ~condvar (or ~@(rest xs)))))))))",
+];
+
+/// `load-file` looks for relative paths that aren't found from the current
+/// directory, or that it may not read there, in the directories of
+/// `*load-path*`, and binds `*file*` to the
+/// path while the file is evaluated.
+const FS_READ_PRELUDE: &[&str] = &[
+    "(def! *load-path* [])",
+    "(def! load-file \"Evaluates every form in the file, looking for it in *load-path*.\" (fn* (f) (let* (exists? (fn* (p) (try* (file-exists? p) (catch* e false))) find (fn* (dirs) (if (empty? dirs) f (let* (p (str (first dirs) \"/\" f)) (if (exists? p) p (find (rest dirs)))))) p (if (exists? f) f (find *load-path*))) (binding (*file* p) (eval (cons 'do (cons nil (read-all (slurp p)))))))))",
+];
+
+fn evaluate_all(forms: &[&str], env: &Env) -> Result<()> {
+    for form in forms {
+        eval(read_form().parse(form.as_bytes())?, env)?;
+    }
+
+    Ok(())
+}
+
+/// A new environment with the builtins the sandbox allows.
+pub fn environment(sandbox: &Sandbox) -> Result<Env> {
+    let env = Env::new(None);
+
+    // The parts of the prelude written in Lisp need pure builtins.
+    let prelude = sandbox.prelude && sandbox.allows(Capability::Pure);
+
+    if sandbox.allows(Capability::Pure) {
+        pure(&env);
+    }
+
+    if prelude {
+        evaluate_all(PRELUDE, &env)?;
+    }
+
+    if sandbox.allows(Capability::Terminal) {
+        terminal(&env);
+    }
+
+    if sandbox.allows(Capability::Process) {
+        process(&env);
+    }
+
+    if sandbox.allows(Capability::Time) {
+        set(&env, "time-ms", time_ms, "[]", "Returns the milliseconds since the epoch.");
+    }
+
+    // File builtins see the rest of the environment, for `*out*`, but
+    // their own allowed paths.
+    let files = Env::new(Some(env.clone()));
+    let allowed = sandbox.allowed_paths.as_ref().map(|paths| {
+        MValue::list(paths.iter()
+            .map(|p| p.canonicalize().unwrap_or_else(|_| p.clone()))
+            .map(|p| MValue::string(p.display()))
+            .collect())
+    });
+    files.set("allowed-paths", allowed.unwrap_or_else(MValue::nil));
+
+    if sandbox.allows(Capability::FsRead) {
+        fs_read(&env, &files);
+
+        if prelude {
+            evaluate_all(FS_READ_PRELUDE, &env)?;
+        }
+    }
+
+    if sandbox.allows(Capability::FsWrite) {
+        fs_write(&env, &files);
+    }
+
+    Ok(env)
+}
+
+#[test]
+fn test_sandbox() {
+    let run = |code: &str, env: &Env| eval(read_form().parse(code.as_bytes()).unwrap(), env);
+
+    let env = environment(&Sandbox::pure()).unwrap();
+    assert_eq!(run("(or nil (+ 1 2))", &env).unwrap(), MValue::integer(3));
+    assert!(matches!(run("(slurp \"Cargo.toml\")", &env), Err(Error::PermissionDenied(_))));
+    assert!(matches!(run("(undefined)", &env), Err(Error::NoSymbolFound(_))));
+
+    let dir = std::env::temp_dir().join(format!("mal-sandbox-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("inside")).unwrap();
+    std::fs::write(dir.join("inside/a.mal"), "(+ 1 2)").unwrap();
+    std::fs::write(dir.join("b.mal"), "(+ 3 4)").unwrap();
+
+    let sandbox = Sandbox {
+        capabilities: vec![Capability::Pure, Capability::FsRead],
+        allowed_paths: Some(vec![dir.join("inside")]),
+        prelude: true,
+    };
+    let env = environment(&sandbox).unwrap();
+    let load = |file: &str| run(&format!("(load-file \"{}\")", dir.join(file).display()), &env);
+
+    assert_eq!(load("inside/a.mal").unwrap(), MValue::integer(3));
+    assert!(matches!(load("inside/../b.mal"), Err(Error::PermissionDenied(_))));
+
+    run(&format!("(def! *load-path* [\"{}\"])", dir.join("inside").display()), &env).unwrap();
+    assert_eq!(run("(load-file \"a.mal\")", &env).unwrap(), MValue::integer(3));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,692 @@
+//! Native sequence functions. Every collection argument may be a list,
+//! vector, set, map (seen as `[key value]` vectors), string, lazy sequence
+//! or `nil`, and every function argument may be a builtin or a lambda.
+//!
+//! `map`, `filter`, `remove`, `take`, `drop`, `take-while`, `drop-while`,
+//! `interleave`, `mapcat`, `range`, `repeat`, `iterate` and `cycle` return
+//! lazy sequences; the others realize their whole input. Called without a
+//! collection, `map`, `filter`, `remove`, `take`, `drop`, `take-while`,
+//! `drop-while`, `distinct` and `mapcat` return transducers instead.
+
+use indexmap::IndexMap;
+
+use crate::core::assert_min_args;
+use crate::env::Env;
+use crate::eval::handle_function;
+use crate::sorted::{call_comparator, compare_values};
+use crate::transducers;
+use crate::types::*;
+
+use std::cmp::Ordering;
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+pub(crate) fn to_seq(value: &MValue) -> Result<Vec<MValue>> {
+    match *value.0 {
+        MalVal::Nil => Ok(vec![]),
+        MalVal::HashMap(ref hm, _) => hm.iter()
+            .map(|(k, v)| Ok(MValue::vector(vec![MValue::reconstruct(k)?, v.clone()])))
+            .collect(),
+        MalVal::SortedMap(ref sorted, _) => Ok(sorted.iter()
+            .map(|(k, v)| MValue::vector(vec![k.clone(), v.clone()]))
+            .collect()),
+        _ => value.cast_to_list(),
+    }
+}
+
+/// A position in a sequence. Eager collections are walked by index so
+/// that stepping through them doesn't copy their tail each time.
+#[derive(Clone)]
+pub(crate) enum Cursor {
+    Items(Rc<Vec<MValue>>, usize),
+    Lazy(MValue),
+}
+
+impl Cursor {
+    pub(crate) fn new(value: &MValue) -> Result<Cursor> {
+        if value.is_lazy_seq() {
+            Ok(Cursor::Lazy(value.clone()))
+        } else {
+            Ok(Cursor::Items(Rc::new(to_seq(value)?), 0))
+        }
+    }
+
+    pub(crate) fn next(&self) -> Result<Option<(MValue, Cursor)>> {
+        match self {
+            Cursor::Items(items, i) => Ok(items.get(*i)
+                .map(|v| (v.clone(), Cursor::Items(items.clone(), i + 1)))),
+            Cursor::Lazy(value) => match value.realize()? {
+                Some((first, rest)) => Ok(Some((first, Cursor::new(&rest)?))),
+                None => Ok(None),
+            },
+        }
+    }
+
+    fn into_value(self) -> MValue {
+        match self {
+            Cursor::Items(items, i) => MValue::list(items[i..].to_vec()),
+            Cursor::Lazy(value) => value,
+        }
+    }
+}
+
+/// Builds a lazy sequence that calls `step` on `state` to produce each
+/// element and the state for the rest.
+pub(crate) fn unfold<S, F>(state: S, step: Rc<F>) -> MValue
+    where S: Clone + 'static, F: Fn(S) -> Result<Option<(MValue, S)>> + 'static
+{
+    MValue::lazy_seq(move || match step(state.clone())? {
+        Some((value, next)) => Ok(MValue::lazy_cons(value, unfold(next, step.clone()))),
+        None => Ok(MValue::nil()),
+    })
+}
+
+pub(crate) fn count_arg(value: &MValue) -> Result<usize> {
+    let n = value.cast_to_int()?;
+    usize::try_from(n).map_err(|_| Error::EvalError(format!("Count {} is negative", n)))
+}
+
+/// Stable sort that stops at the first error the comparison raises.
+fn sort_values<F>(values: &mut [MValue], mut cmp: F) -> Result<()>
+    where F: FnMut(&MValue, &MValue) -> Result<Ordering>
+{
+    let mut error = None;
+
+    values.sort_by(|a, b| {
+        if error.is_some() {
+            return Ordering::Equal;
+        }
+
+        cmp(a, b).unwrap_or_else(|e| {
+            error = Some(e);
+            Ordering::Equal
+        })
+    });
+
+    error.map_or(Ok(()), Err)
+}
+
+/// `(map f coll & colls)` calls `f` with one element of each collection
+/// and stops at the shortest.
+pub fn map(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if args.len() == 1 {
+        return Ok(transducers::map(&args[0]));
+    }
+
+    let f = args[0].clone();
+    let cursors = args[1..].iter().map(Cursor::new).collect::<Result<Vec<_>>>()?;
+
+    Ok(unfold(cursors, Rc::new(move |cursors: Vec<Cursor>| {
+        let mut values = vec![];
+        let mut rests = vec![];
+
+        for c in cursors {
+            match c.next()? {
+                Some((value, rest)) => {
+                    values.push(value);
+                    rests.push(rest);
+                },
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some((handle_function(&f, values)?, rests)))
+    })))
+}
+
+/// `(reduce f coll)` or `(reduce f init coll)`; `f` is called with no
+/// arguments when `coll` is empty and there is no `init`, and the reduction
+/// stops early when `f` returns a `reduced` value.
+pub fn reduce(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let f = &args[0];
+    let mut cursor = Cursor::new(&args[args.len() - 1])?;
+
+    let mut acc = if args.len() > 2 {
+        args[1].clone()
+    } else {
+        match cursor.next()? {
+            Some((v, rest)) => {
+                cursor = rest;
+                v
+            },
+            None => return handle_function(f, vec![]),
+        }
+    };
+
+    while let Some((v, rest)) = cursor.next()? {
+        acc = handle_function(f, vec![acc, v])?;
+
+        if acc.is_reduced() {
+            break;
+        }
+        cursor = rest;
+    }
+
+    Ok(acc.unreduced())
+}
+
+fn keep(args: &[MValue], wanted: bool) -> Result<MValue> {
+    let pred = args[0].clone();
+
+    Ok(unfold(Cursor::new(&args[1])?, Rc::new(move |mut cursor: Cursor| {
+        while let Some((v, rest)) = cursor.next()? {
+            if handle_function(&pred, vec![v.clone()])?.is_truthy() == wanted {
+                return Ok(Some((v, rest)));
+            }
+            cursor = rest;
+        }
+
+        Ok(None)
+    })))
+}
+
+/// `(filter pred coll)` keeps the elements for which `pred` is truthy.
+pub fn filter(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if args.len() == 1 {
+        return Ok(transducers::filter(&args[0]));
+    }
+    keep(&args, true)
+}
+
+/// `(remove pred coll)` drops the elements for which `pred` is truthy.
+pub fn remove(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if args.len() == 1 {
+        return Ok(transducers::remove(&args[0]));
+    }
+    keep(&args, false)
+}
+
+/// `(some pred coll)` returns the first truthy `(pred x)`, or `nil`.
+pub fn some(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let mut cursor = Cursor::new(&args[1])?;
+
+    while let Some((v, rest)) = cursor.next()? {
+        let r = handle_function(&args[0], vec![v])?;
+        if r.is_truthy() {
+            return Ok(r);
+        }
+        cursor = rest;
+    }
+
+    Ok(MValue::nil())
+}
+
+/// `(every? pred coll)` is true when `pred` holds for every element.
+pub fn every_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let mut cursor = Cursor::new(&args[1])?;
+
+    while let Some((v, rest)) = cursor.next()? {
+        if !handle_function(&args[0], vec![v])?.is_truthy() {
+            return Ok(MValue::bool(false));
+        }
+        cursor = rest;
+    }
+
+    Ok(MValue::bool(true))
+}
+
+/// `(take n coll)`
+pub fn take(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if args.len() == 1 {
+        return transducers::take(&args[0]);
+    }
+
+    let n = count_arg(&args[0])?;
+
+    Ok(unfold((n, Cursor::new(&args[1])?), Rc::new(|(n, cursor): (usize, Cursor)| {
+        if n == 0 {
+            return Ok(None);
+        }
+
+        Ok(cursor.next()?.map(|(v, rest)| (v, (n - 1, rest))))
+    })))
+}
+
+/// Lazily skips elements of `coll` while `skip`, given the index and the
+/// element, returns true.
+fn skip_while<F>(coll: &MValue, skip: F) -> Result<MValue>
+    where F: Fn(usize, &MValue) -> Result<bool> + 'static
+{
+    let cursor = Cursor::new(coll)?;
+
+    Ok(MValue::lazy_seq(move || {
+        let mut cursor = cursor.clone();
+
+        for i in 0.. {
+            match cursor.next()? {
+                Some((v, rest)) if skip(i, &v)? => cursor = rest,
+                Some(_) => return Ok(cursor.into_value()),
+                None => return Ok(MValue::nil()),
+            }
+        }
+
+        unreachable!("the loop only ends by returning")
+    }))
+}
+
+/// `(drop n coll)`
+pub fn drop(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if args.len() == 1 {
+        return transducers::drop(&args[0]);
+    }
+
+    let n = count_arg(&args[0])?;
+    skip_while(&args[1], move |i, _| Ok(i < n))
+}
+
+/// `(take-while pred coll)`
+pub fn take_while(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if args.len() == 1 {
+        return Ok(transducers::take_while(&args[0]));
+    }
+
+    let pred = args[0].clone();
+
+    Ok(unfold(Cursor::new(&args[1])?, Rc::new(move |cursor: Cursor| {
+        match cursor.next()? {
+            Some((v, rest)) if handle_function(&pred, vec![v.clone()])?.is_truthy() =>
+                Ok(Some((v, rest))),
+            _ => Ok(None),
+        }
+    })))
+}
+
+/// `(drop-while pred coll)`
+pub fn drop_while(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if args.len() == 1 {
+        return Ok(transducers::drop_while(&args[0]));
+    }
+
+    let pred = args[0].clone();
+    skip_while(&args[1], move |_, v| Ok(handle_function(&pred, vec![v.clone()])?.is_truthy()))
+}
+
+/// `(partition n coll)`, `(partition n step coll)` or
+/// `(partition n step pad coll)`. Without `pad` a short last group is
+/// dropped; with it the group is filled from `pad` and may stay short.
+pub fn partition(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let n = count_arg(&args[0])?;
+    let step = if args.len() > 2 { count_arg(&args[1])? } else { n };
+    let pad = if args.len() > 3 { Some(to_seq(&args[2])?) } else { None };
+    let values = to_seq(&args[args.len() - 1])?;
+
+    if n == 0 || step == 0 {
+        return Err(Error::EvalError("partition needs a positive size and step".to_string()));
+    }
+
+    let mut groups = vec![];
+    let mut start = 0;
+
+    while start < values.len() {
+        let mut group = values[start..values.len().min(start + n)].to_vec();
+
+        if group.len() < n {
+            if let Some(ref pad) = pad {
+                group.extend(pad.iter().take(n - group.len()).cloned());
+                groups.push(MValue::list(group));
+            }
+            break;
+        }
+
+        groups.push(MValue::list(group));
+        start += step;
+    }
+
+    Ok(MValue::list(groups))
+}
+
+/// `(group-by f coll)` maps each `(f x)` to a vector of the elements that
+/// produced it.
+pub fn group_by(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let mut groups: IndexMap<(String, String), Vec<MValue>> = IndexMap::new();
+
+    for v in to_seq(&args[1])? {
+        let key = handle_function(&args[0], vec![v.clone()])?.hash_key()?;
+        groups.entry(key).or_default().push(v);
+    }
+
+    Ok(MValue::from_hashmap(groups.into_iter()
+        .map(|(k, v)| (k, MValue::vector(v)))
+        .collect()))
+}
+
+/// `(frequencies coll)` maps each distinct element to how often it occurs.
+pub fn frequencies(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let mut counts: IndexMap<(String, String), i64> = IndexMap::new();
+
+    for v in to_seq(&args[0])? {
+        *counts.entry(v.hash_key()?).or_insert(0) += 1;
+    }
+
+    Ok(MValue::from_hashmap(counts.into_iter()
+        .map(|(k, n)| (k, MValue::integer(n)))
+        .collect()))
+}
+
+/// `(sort coll)` or `(sort comparator coll)`. The sort is stable.
+pub fn sort(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let mut values = to_seq(&args[args.len() - 1])?;
+
+    if args.len() > 1 {
+        sort_values(&mut values, |a, b| call_comparator(&args[0], a, b))?;
+    } else {
+        sort_values(&mut values, compare_values)?;
+    }
+
+    Ok(MValue::list(values))
+}
+
+/// `(sort-by keyfn coll)` or `(sort-by keyfn comparator coll)`; `keyfn` is
+/// called once per element.
+pub fn sort_by(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let mut keyed = to_seq(&args[args.len() - 1])?.into_iter()
+        .map(|v| Ok(MValue::list(vec![handle_function(&args[0], vec![v.clone()])?, v])))
+        .collect::<Result<Vec<_>>>()?;
+
+    let key = |pair: &MValue| pair.cast_to_list().map(|p| p[0].clone());
+
+    if args.len() > 2 {
+        sort_values(&mut keyed, |a, b| call_comparator(&args[1], &key(a)?, &key(b)?))?;
+    } else {
+        sort_values(&mut keyed, |a, b| compare_values(&key(a)?, &key(b)?))?;
+    }
+
+    keyed.iter()
+        .map(|pair| pair.cast_to_list().map(|p| p[1].clone()))
+        .collect::<Result<Vec<_>>>()
+        .map(MValue::list)
+}
+
+/// `(distinct coll)` drops repeated elements, keeping the first of each.
+pub fn distinct(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    if args.is_empty() {
+        return Ok(transducers::distinct());
+    }
+
+    let mut seen_keys = HashSet::new();
+    let mut result: Vec<MValue> = vec![];
+
+    for v in to_seq(&args[0])? {
+        let fresh = match v.hash_key() {
+            Ok(key) => seen_keys.insert(key),
+            Err(_) => !result.contains(&v),
+        };
+
+        if fresh {
+            result.push(v);
+        }
+    }
+
+    Ok(MValue::list(result))
+}
+
+/// `(interleave c1 c2 & colls)` takes one element of each collection in
+/// turn until the shortest runs out.
+pub fn interleave(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let cursors = args.iter().map(Cursor::new).collect::<Result<Vec<_>>>()?;
+
+    Ok(unfold((cursors, VecDeque::new()), Rc::new(
+        |(cursors, mut pending): (Vec<Cursor>, VecDeque<MValue>)| {
+            if cursors.is_empty() {
+                return Ok(None);
+            }
+
+            let mut cursors = cursors;
+            if pending.is_empty() {
+                let mut rests = vec![];
+
+                for c in cursors {
+                    match c.next()? {
+                        Some((v, rest)) => {
+                            pending.push_back(v);
+                            rests.push(rest);
+                        },
+                        None => return Ok(None),
+                    }
+                }
+                cursors = rests;
+            }
+
+            Ok(pending.pop_front().map(|v| (v, (cursors, pending))))
+        })))
+}
+
+/// Lazily concatenates the collections in the sequence `colls`.
+fn flatten(colls: &MValue) -> Result<MValue> {
+    Ok(unfold((None, Cursor::new(colls)?), Rc::new(
+        |(inner, mut outer): (Option<Cursor>, Cursor)| {
+            let mut inner = inner;
+
+            loop {
+                if let Some(ref c) = inner {
+                    if let Some((v, rest)) = c.next()? {
+                        return Ok(Some((v, (Some(rest), outer))));
+                    }
+                }
+
+                match outer.next()? {
+                    Some((coll, rest)) => {
+                        inner = Some(Cursor::new(&coll)?);
+                        outer = rest;
+                    },
+                    None => return Ok(None),
+                }
+            }
+        })))
+}
+
+/// `(concat & colls)` when one of `colls` is lazy.
+pub(crate) fn lazy_concat(args: Vec<MValue>) -> Result<MValue> {
+    flatten(&MValue::list(args))
+}
+
+/// `(mapcat f & colls)` concatenates the collections `map` produces.
+pub fn mapcat(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if args.len() == 1 {
+        return Ok(transducers::mapcat(&args[0]));
+    }
+
+    flatten(&map(args, env)?)
+}
+
+/// `(zipmap keys vals)`
+pub fn zipmap(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let mut map = IndexMap::new();
+    let (mut keys, mut vals) = (Cursor::new(&args[0])?, Cursor::new(&args[1])?);
+
+    while let (Some((k, k_rest)), Some((v, v_rest))) = (keys.next()?, vals.next()?) {
+        map.insert(k.hash_key()?, v);
+        keys = k_rest;
+        vals = v_rest;
+    }
+
+    Ok(MValue::from_hashmap(map))
+}
+
+/// `(range)`, `(range end)`, `(range start end)` or
+/// `(range start end step)`. Any float argument makes every element a float.
+pub fn range(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    let (start, end) = match args.len() {
+        0 => (MValue::integer(0), None),
+        1 => (MValue::integer(0), Some(args[0].clone())),
+        _ => (args[0].clone(), Some(args[1].clone())),
+    };
+    let step = args.get(2).cloned().unwrap_or_else(|| MValue::integer(1));
+
+    let is_float = |v: &MValue| matches!(*v.0, MalVal::Float(_));
+
+    if is_float(&start) || is_float(&step) || end.as_ref().is_some_and(is_float) {
+        let (start, step) = (start.cast_to_float()?, step.cast_to_float()?);
+        let end = end.map(|e| e.cast_to_float()).transpose()?;
+
+        if step == 0.0 || step.is_nan() {
+            return Err(Error::EvalError("range step can't be zero".to_string()));
+        }
+
+        return Ok(unfold(0.0, Rc::new(move |i: f64| {
+            let x = start + i * step;
+            match end {
+                Some(end) if (step > 0.0 && x >= end) || (step < 0.0 && x <= end) => Ok(None),
+                _ => Ok(Some((MValue::float(x), i + 1.0))),
+            }
+        })));
+    }
+
+    let (start, step) = (start.cast_to_int()?, step.cast_to_int()?);
+    let end = end.map(|e| e.cast_to_int()).transpose()?;
+
+    if step == 0 {
+        return Err(Error::EvalError("range step can't be zero".to_string()));
+    }
+
+    Ok(unfold(Some(start), Rc::new(move |x: Option<i64>| {
+        match (x, end) {
+            (Some(x), Some(end)) if (step > 0 && x >= end) || (step < 0 && x <= end) => Ok(None),
+            (Some(x), _) => Ok(Some((MValue::integer(x), x.checked_add(step)))),
+            (None, _) => Ok(None),
+        }
+    })))
+}
+
+/// `(repeat x)` or `(repeat n x)`
+pub fn repeat(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let n = match args.len() {
+        1 => None,
+        _ => Some(count_arg(&args[0])?),
+    };
+    let x = args[args.len() - 1].clone();
+
+    Ok(unfold(n, Rc::new(move |n: Option<usize>| match n {
+        Some(0) => Ok(None),
+        _ => Ok(Some((x.clone(), n.map(|n| n - 1)))),
+    })))
+}
+
+/// `(iterate f x)` is `x`, `(f x)`, `(f (f x))` and so on.
+pub fn iterate(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let f = args[0].clone();
+
+    // The state is the previous element, or `None` before the first one,
+    // so `f` only runs when the element it produces is realized.
+    let x = args[1].clone();
+    Ok(unfold(None, Rc::new(move |prev: Option<MValue>| {
+        let next = match prev {
+            Some(prev) => handle_function(&f, vec![prev])?,
+            None => x.clone(),
+        };
+
+        Ok(Some((next.clone(), Some(next))))
+    })))
+}
+
+/// `(cycle coll)` repeats the elements of `coll` forever.
+pub fn cycle(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let items = Rc::new(to_seq(&args[0])?);
+
+    Ok(unfold(0, Rc::new(move |i: usize| {
+        Ok(items.get(i % items.len().max(1)).map(|v| (v.clone(), i + 1)))
+    })))
+}
+
+/// `(doall coll)` realizes every lazy sequence in `coll` and returns it.
+pub fn doall(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    args[0].force()?;
+    Ok(args[0].clone())
+}
+
+#[test]
+fn test_sequences() {
+    let xs = MValue::vector(vec![3, 1, 2, 1].into_iter().map(MValue::integer).collect());
+    let ints = |v: Vec<i64>| MValue::list(v.into_iter().map(MValue::integer).collect());
+
+    assert_eq!(sort(vec![xs.clone()], None).unwrap(), ints(vec![1, 1, 2, 3]));
+    assert_eq!(distinct(vec![xs.clone()], None).unwrap(), ints(vec![3, 1, 2]));
+    assert_eq!(take(vec![MValue::integer(2), xs.clone()], None).unwrap(), ints(vec![3, 1]));
+    assert_eq!(range(vec![MValue::integer(5), MValue::integer(0), MValue::integer(-2)], None).unwrap(),
+               ints(vec![5, 3, 1]));
+
+    let naturals = range(vec![], None).unwrap();
+    let evens = filter(vec![MValue::function(|a, _| Ok(MValue::bool(a[0].cast_to_int()? % 2 == 0)), None),
+                            naturals], None).unwrap();
+    assert_eq!(take(vec![MValue::integer(3), evens], None).unwrap(), ints(vec![0, 2, 4]));
+    assert_eq!(partition(vec![MValue::integer(3), xs.clone()], None).unwrap(),
+               MValue::list(vec![ints(vec![3, 1, 2])]));
+    assert_eq!(sort(vec![MValue::vector(vec![MValue::string("a"), MValue::integer(1)])], None).unwrap(),
+               MValue::list(vec![MValue::integer(1), MValue::string("a")]));
+}
+
+#[test]
+fn test_lazy() {
+    use crate::eval::eval;
+    use crate::reader::read_form;
+    use crate::sandbox::{environment, Sandbox};
+
+    let env = environment(&Sandbox::pure()).unwrap();
+    let run = |code: &str| eval(read_form().parse(code.as_bytes()).unwrap(), &env).unwrap();
+    let calls = || run("@calls");
+    let ints = |v: Vec<i64>| MValue::list(v.into_iter().map(MValue::integer).collect());
+
+    run("(def! calls (atom 0))");
+    run("(def! count! (fn* (x) (do (swap! calls (fn* (n) (+ n 1))) x)))");
+
+    // Only what is taken from an infinite sequence is computed.
+    run("(def! xs (take 3 (map count! (iterate (fn* (x) (+ x 1)) 0))))");
+    assert_eq!(calls(), MValue::integer(0));
+    assert_eq!(run("(doall xs)"), ints(vec![0, 1, 2]));
+    assert_eq!(calls(), MValue::integer(3));
+
+    // Each of the four thunks, the last giving the empty end, runs once
+    // however the sequence is compared or printed.
+    run("(def! counting (fn* (i) (lazy-seq (count! (if (< i 3) (cons i (counting (+ i 1))) nil)))))");
+    run("(def! ys (counting 0))");
+    let ys = run("ys");
+    assert_eq!(calls(), MValue::integer(3));
+    assert_eq!(ys, ints(vec![0, 1, 2]));
+    assert_eq!(format!("{:?}", ys), "MValue((0 1 2), false)");
+    assert_eq!(run("(pr-str ys)"), MValue::string("(0 1 2)"));
+    assert_eq!(run("(= ys '(0 1 2))"), MValue::bool(true));
+    assert_eq!(calls(), MValue::integer(7));
+}
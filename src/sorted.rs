@@ -0,0 +1,563 @@
+//! Sorted maps and sets, and the ordering they use by default.
+//!
+//! The entries are kept in a persistent AVL tree: adding or removing a key
+//! copies only the path to it, so the old collection stays as it was while
+//! sharing the rest. Comparators given to `sorted-map-by` are Lisp functions
+//! that may fail, which a `BTreeMap` has no way to report.
+
+use crate::core::assert_min_args;
+use crate::env::Env;
+use crate::eval::handle_function;
+use crate::sequences::to_seq;
+use crate::types::*;
+
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+fn rank(value: &MalVal) -> u8 {
+    use MalVal::*;
+
+    match value {
+        Nil => 0,
+        Bool(_) => 1,
+        Int(_) | Float(_) => 2,
+        Str(_) => 3,
+        Keyword(_) => 4,
+        Sym(_) => 5,
+        List(_, _) | Vector(_, _) | LazySeq(_) => 6,
+        Set(_, _) | SortedSet(_, _) => 7,
+        HashMap(_, _) | SortedMap(_, _) => 8,
+        Regex(_) => 9,
+        Reduced(_) | Tagged(_, _) => 10,
+        Atom(_) | Port(_) => 11,
+        Fun(_, _, _) | Lambda(_, _) => 12,
+    }
+}
+
+fn compare_seqs(x: &[MValue], y: &[MValue]) -> Result<Ordering> {
+    if x.len() != y.len() {
+        return Ok(x.len().cmp(&y.len()));
+    }
+
+    for (x, y) in x.iter().zip(y) {
+        match compare_values(x, y)? {
+            Ordering::Equal => continue,
+            o => return Ok(o),
+        }
+    }
+
+    Ok(Ordering::Equal)
+}
+
+fn sorted_elements(value: &MValue) -> Result<Vec<MValue>> {
+    let mut values = to_seq(value)?;
+    let mut error = None;
+
+    values.sort_by(|a, b| compare_values(a, b).unwrap_or_else(|e| {
+        error.get_or_insert(e);
+        Ordering::Equal
+    }));
+
+    error.map_or(Ok(values), Err)
+}
+
+/// A total ordering over values. Values of the same kind compare
+/// naturally: numbers by value, strings, keywords and symbols
+/// lexicographically, and sequences, sets and maps by size and then
+/// element-wise. Values of different kinds order by kind, with `nil` first.
+/// Only realizing a lazy sequence can fail.
+pub(crate) fn compare_values(a: &MValue, b: &MValue) -> Result<Ordering> {
+    use MalVal::*;
+
+    match (&*a.0, &*b.0) {
+        (x, y) if rank(x) != rank(y) => Ok(rank(x).cmp(&rank(y))),
+        (Bool(x), Bool(y)) => Ok(x.cmp(y)),
+        (Int(x), Int(y)) => Ok(x.cmp(y)),
+        (Int(_), _) | (Float(_), _) => Ok(a.cast_to_float()?.total_cmp(&b.cast_to_float()?)),
+        (Str(x), Str(y)) | (Keyword(x), Keyword(y)) | (Sym(x), Sym(y)) => Ok(x.cmp(y)),
+        (List(x, _), List(y, _)) | (Vector(x, _), Vector(y, _))
+        | (List(x, _), Vector(y, _)) | (Vector(x, _), List(y, _)) => compare_seqs(x, y),
+        (LazySeq(_), _) | (_, LazySeq(_)) => compare_seqs(&a.cast_to_list()?, &b.cast_to_list()?),
+        (Set(_, _), _) | (SortedSet(_, _), _) | (HashMap(_, _), _) | (SortedMap(_, _), _) =>
+            compare_seqs(&sorted_elements(a)?, &sorted_elements(b)?),
+        (Regex(x), Regex(y)) => Ok(x.as_str().cmp(y.as_str())),
+        (Reduced(x), Reduced(y)) => compare_values(x, y),
+        (Tagged(t, x), Tagged(u, y)) => Ok(t.cmp(u).then(compare_values(x, y)?)),
+        _ => Ok(Rc::as_ptr(&a.0).cmp(&Rc::as_ptr(&b.0))),
+    }
+}
+
+/// Turns what a user comparator returned into an ordering: a number is read
+/// by its sign, and a boolean as "less than" in the style of `<`.
+pub(crate) fn call_comparator(f: &MValue, a: &MValue, b: &MValue) -> Result<Ordering> {
+    let r = handle_function(f, vec![a.clone(), b.clone()])?;
+
+    match *r.0 {
+        MalVal::Int(n) => Ok(n.cmp(&0)),
+        MalVal::Float(n) => Ok(n.partial_cmp(&0.0).unwrap_or(Ordering::Equal)),
+        _ if r.is_truthy() => Ok(Ordering::Less),
+        _ if handle_function(f, vec![b.clone(), a.clone()])?.is_truthy() => Ok(Ordering::Greater),
+        _ => Ok(Ordering::Equal),
+    }
+}
+
+type Link = Option<Rc<Node>>;
+
+struct Node {
+    key: MValue,
+    value: MValue,
+    height: usize,
+    left: Link,
+    right: Link,
+}
+
+fn height(link: &Link) -> usize {
+    link.as_ref().map_or(0, |n| n.height)
+}
+
+fn node(key: MValue, value: MValue, left: Link, right: Link) -> Rc<Node> {
+    let height = 1 + height(&left).max(height(&right));
+    Rc::new(Node { key, value, height, left, right })
+}
+
+/// A node for the entry and subtrees, rotated back into balance when one
+/// side has grown two taller than the other.
+fn balance(key: MValue, value: MValue, left: Link, right: Link) -> Rc<Node> {
+    let (hl, hr) = (height(&left), height(&right));
+
+    if hl > hr + 1 {
+        let l = left.expect("a taller left subtree");
+
+        if height(&l.left) >= height(&l.right) {
+            return node(l.key.clone(), l.value.clone(), l.left.clone(),
+                Some(node(key, value, l.right.clone(), right)));
+        }
+
+        let lr = l.right.as_ref().expect("a taller inner subtree");
+        return node(lr.key.clone(), lr.value.clone(),
+            Some(node(l.key.clone(), l.value.clone(), l.left.clone(), lr.left.clone())),
+            Some(node(key, value, lr.right.clone(), right)));
+    }
+
+    if hr > hl + 1 {
+        let r = right.expect("a taller right subtree");
+
+        if height(&r.right) >= height(&r.left) {
+            return node(r.key.clone(), r.value.clone(),
+                Some(node(key, value, left, r.left.clone())), r.right.clone());
+        }
+
+        let rl = r.left.as_ref().expect("a taller inner subtree");
+        return node(rl.key.clone(), rl.value.clone(),
+            Some(node(key, value, left, rl.left.clone())),
+            Some(node(r.key.clone(), r.value.clone(), rl.right.clone(), r.right.clone())));
+    }
+
+    node(key, value, left, right)
+}
+
+/// Iterates over the entries in order from a stack of the nodes still to
+/// visit, each of which comes before everything in its right subtree.
+pub struct Iter<'a> {
+    stack: Vec<&'a Node>,
+}
+
+impl<'a> Iter<'a> {
+    fn push_left(&mut self, mut link: Option<&'a Node>) {
+        while let Some(n) = link {
+            self.stack.push(n);
+            link = n.left.as_deref();
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a MValue, &'a MValue);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.stack.pop()?;
+        self.push_left(n.right.as_deref());
+        Some((&n.key, &n.value))
+    }
+}
+
+/// The entries of a sorted map or set; a set stores each key as its own
+/// value.
+#[derive(Clone)]
+pub struct Sorted {
+    root: Link,
+    len: usize,
+    comparator: Option<MValue>,
+}
+
+impl Sorted {
+    pub fn new(comparator: Option<MValue>) -> Self {
+        Sorted { root: None, len: 0, comparator }
+    }
+
+    pub fn comparator(&self) -> Option<&MValue> {
+        self.comparator.as_ref()
+    }
+
+    pub fn compare(&self, a: &MValue, b: &MValue) -> Result<Ordering> {
+        match self.comparator {
+            Some(ref f) => call_comparator(f, a, b),
+            None => compare_values(a, b),
+        }
+    }
+
+    pub fn get(&self, key: &MValue) -> Result<Option<MValue>> {
+        let mut link = self.root.as_deref();
+
+        while let Some(n) = link {
+            link = match self.compare(key, &n.key)? {
+                Ordering::Less => n.left.as_deref(),
+                Ordering::Greater => n.right.as_deref(),
+                Ordering::Equal => return Ok(Some(n.value.clone())),
+            };
+        }
+
+        Ok(None)
+    }
+
+    /// Adds or replaces an entry. A failing comparator leaves the
+    /// collection as it was.
+    pub fn insert(&mut self, key: MValue, value: MValue) -> Result<()> {
+        let (root, added) = self.insert_at(&self.root, key, value)?;
+        self.root = Some(root);
+        self.len += added as usize;
+        Ok(())
+    }
+
+    fn insert_at(&self, link: &Link, key: MValue, value: MValue) -> Result<(Rc<Node>, bool)> {
+        let n = match link {
+            Some(n) => n,
+            None => return Ok((node(key, value, None, None), true)),
+        };
+
+        match self.compare(&key, &n.key)? {
+            Ordering::Less => {
+                let (left, added) = self.insert_at(&n.left, key, value)?;
+                Ok((balance(n.key.clone(), n.value.clone(), Some(left), n.right.clone()), added))
+            },
+            Ordering::Greater => {
+                let (right, added) = self.insert_at(&n.right, key, value)?;
+                Ok((balance(n.key.clone(), n.value.clone(), n.left.clone(), Some(right)), added))
+            },
+            Ordering::Equal => Ok((node(key, value, n.left.clone(), n.right.clone()), false)),
+        }
+    }
+
+    pub fn remove(&mut self, key: &MValue) -> Result<()> {
+        if let Some(root) = self.remove_at(&self.root, key)? {
+            self.root = root;
+            self.len -= 1;
+        }
+
+        Ok(())
+    }
+
+    /// The subtree without `key`, or `None` when it isn't there.
+    fn remove_at(&self, link: &Link, key: &MValue) -> Result<Option<Link>> {
+        let n = match link {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+
+        let removed = match self.compare(key, &n.key)? {
+            Ordering::Less => self.remove_at(&n.left, key)?
+                .map(|left| balance(n.key.clone(), n.value.clone(), left, n.right.clone())),
+            Ordering::Greater => self.remove_at(&n.right, key)?
+                .map(|right| balance(n.key.clone(), n.value.clone(), n.left.clone(), right)),
+            Ordering::Equal => return Ok(Some(match (&n.left, &n.right) {
+                (None, other) | (other, None) => other.clone(),
+                (Some(_), Some(right)) => {
+                    let (key, value, rest) = remove_first(right);
+                    Some(balance(key, value, n.left.clone(), rest))
+                },
+            })),
+        };
+
+        Ok(removed.map(Some))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        let mut iter = Iter { stack: vec![] };
+        iter.push_left(self.root.as_deref());
+        iter
+    }
+
+    /// The entries from the first whose key `at_or_after` holds for on,
+    /// which must be false up to some key and true from there.
+    fn seek(&self, at_or_after: &mut dyn FnMut(&MValue) -> Result<bool>) -> Result<Iter<'_>> {
+        let mut iter = Iter { stack: vec![] };
+        let mut link = self.root.as_deref();
+
+        while let Some(n) = link {
+            if at_or_after(&n.key)? {
+                iter.stack.push(n);
+                link = n.left.as_deref();
+            } else {
+                link = n.right.as_deref();
+            }
+        }
+
+        Ok(iter)
+    }
+
+    pub fn entries(&self) -> Vec<(MValue, MValue)> {
+        self.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    pub fn keys(&self) -> Vec<MValue> {
+        self.iter().map(|(k, _)| k.clone()).collect()
+    }
+
+    pub fn values(&self) -> Vec<MValue> {
+        self.iter().map(|(_, v)| v.clone()).collect()
+    }
+}
+
+/// The first entry of a subtree and the subtree without it.
+fn remove_first(n: &Rc<Node>) -> (MValue, MValue, Link) {
+    match n.left {
+        None => (n.key.clone(), n.value.clone(), n.right.clone()),
+        Some(ref left) => {
+            let (key, value, rest) = remove_first(left);
+            (key, value, Some(balance(n.key.clone(), n.value.clone(), rest, n.right.clone())))
+        },
+    }
+}
+
+fn sorted_map_of(comparator: Option<MValue>, args: &[MValue]) -> Result<MValue> {
+    if args.len() % 2 == 1 {
+        return Err(Error::EvalError("sorted-map takes an even number of arguments".to_string()));
+    }
+
+    let mut sorted = Sorted::new(comparator);
+
+    for pair in args.chunks(2) {
+        sorted.insert(pair[0].clone(), pair[1].clone())?;
+    }
+
+    Ok(MValue::sorted_map(sorted))
+}
+
+fn sorted_set_of(comparator: Option<MValue>, args: &[MValue]) -> Result<MValue> {
+    let mut sorted = Sorted::new(comparator);
+
+    for value in args {
+        sorted.insert(value.clone(), value.clone())?;
+    }
+
+    Ok(MValue::sorted_set(sorted))
+}
+
+/// `(sorted-map & kvs)`
+pub fn sorted_map(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    sorted_map_of(None, &args)
+}
+
+/// `(sorted-map-by comparator & kvs)`
+pub fn sorted_map_by(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    sorted_map_of(Some(args[0].clone()), &args[1..])
+}
+
+/// `(sorted-set & values)`
+pub fn sorted_set(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    sorted_set_of(None, &args)
+}
+
+/// `(sorted-set-by comparator & values)`
+pub fn sorted_set_by(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    sorted_set_of(Some(args[0].clone()), &args[1..])
+}
+
+/// `(compare x y)` is negative, zero or positive as `x` sorts before, with
+/// or after `y`.
+pub fn compare(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let n = match compare_values(&args[0], &args[1])? {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    };
+
+    Ok(MValue::integer(n))
+}
+
+fn ordering_index(o: Ordering) -> usize {
+    match o {
+        Ordering::Less => 0,
+        Ordering::Equal => 1,
+        Ordering::Greater => 2,
+    }
+}
+
+fn range_query(args: &[MValue], ascending: bool) -> Result<MValue> {
+    assert_min_args!(args, 3);
+
+    let (sorted, is_map) = match *args[0].0 {
+        MalVal::SortedMap(ref s, _) => (s, true),
+        MalVal::SortedSet(ref s, _) => (s, false),
+        _ => return Err(Error::EvalError(format!("{} is not a sorted collection", args[0]))),
+    };
+
+    // As in Clojure each test, such as `<` or `>=`, is called with the
+    // comparison of a key to the bound and zero. Asking each test about
+    // -1, 0 and 1 up front tells which comparisons it accepts.
+    let mut bounds = vec![];
+    for pair in args[1..].chunks(2).filter(|pair| pair.len() == 2) {
+        let mut accepts = [false; 3];
+        for (i, n) in [-1, 0, 1].iter().enumerate() {
+            accepts[i] = handle_function(&pair[0], vec![MValue::integer(*n), MValue::integer(0)])?.is_truthy();
+        }
+
+        let first = accepts.iter().position(|&a| a);
+        let last = accepts.iter().rposition(|&a| a);
+        match (first, last) {
+            (Some(first), Some(last)) => bounds.push((&pair[1], accepts, first, last)),
+            _ => return Ok(MValue::nil()),
+        }
+    }
+
+    // Keys only ever compare greater to a bound further on, so the range
+    // starts at the first key past every lower limit and ends before the
+    // first key past an upper one. Tests such as `not=` that accept
+    // comparisons on both sides of another are filtered within it.
+    let position = |k: &MValue, bound: &MValue| sorted.compare(k, bound).map(ordering_index);
+
+    let mut at_or_after = |k: &MValue| -> Result<bool> {
+        for (bound, _, first, _) in bounds.iter() {
+            if position(k, bound)? < *first {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    };
+
+    let mut result = vec![];
+
+    'entries: for (k, v) in sorted.seek(&mut at_or_after)? {
+        for (bound, accepts, _, last) in bounds.iter() {
+            let i = position(k, bound)?;
+
+            if i > *last {
+                break 'entries;
+            }
+            if !accepts[i] {
+                continue 'entries;
+            }
+        }
+
+        result.push(if is_map { MValue::vector(vec![k.clone(), v.clone()]) } else { k.clone() });
+    }
+
+    if !ascending {
+        result.reverse();
+    }
+
+    if result.is_empty() {
+        Ok(MValue::nil())
+    } else {
+        Ok(MValue::list(result))
+    }
+}
+
+/// `(subseq sc test key)` or `(subseq sc start-test start-key end-test
+/// end-key)` returns the entries whose keys pass the tests, in order.
+pub fn subseq(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    range_query(&args, true)
+}
+
+/// `(rsubseq ...)` is `subseq` in reverse order.
+pub fn rsubseq(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    range_query(&args, false)
+}
+
+#[test]
+fn test_sorted() {
+    let keys = vec![MValue::integer(3), MValue::string("b"), MValue::nil(), MValue::float(1.5)];
+    let set = sorted_set(keys, None).unwrap();
+
+    assert_eq!(set.pr_str(true), "#{nil 1.5 3 \"b\"}");
+
+    let gt = MValue::function(crate::core::gt, None);
+    assert_eq!(subseq(vec![set, gt, MValue::integer(2)], None).unwrap().pr_str(true), "(3 \"b\")");
+}
+
+#[test]
+fn test_sorted_tree() {
+    fn check(link: &Link) -> usize {
+        match link {
+            None => 0,
+            Some(n) => {
+                let (l, r) = (check(&n.left), check(&n.right));
+                assert!(l.max(r) - l.min(r) <= 1);
+                assert_eq!(n.height, 1 + l.max(r));
+                n.height
+            },
+        }
+    }
+
+    let mut sorted = Sorted::new(None);
+    for i in 0..1000 {
+        sorted.insert(MValue::integer((i * 7919) % 1000), MValue::integer(i)).unwrap();
+    }
+    let before = sorted.clone();
+    for i in (0..1000).filter(|i| i % 3 == 0) {
+        sorted.remove(&MValue::integer(i)).unwrap();
+    }
+
+    assert_eq!(before.len(), 1000);
+    // An AVL tree is at most about 1.44 times as tall as a perfect one.
+    assert!(check(&before.root) <= 14);
+    assert_eq!(before.keys(), (0..1000).map(MValue::integer).collect::<Vec<_>>());
+    assert_eq!(sorted.len(), 666);
+    assert!(check(&sorted.root) <= 14);
+    assert_eq!(sorted.keys(), (0..1000).filter(|i| i % 3 != 0).map(MValue::integer).collect::<Vec<_>>());
+    assert_eq!(sorted.get(&MValue::integer(919)).unwrap(), Some(MValue::integer(1)));
+    assert_eq!(sorted.get(&MValue::integer(3)).unwrap(), None);
+}
+
+#[test]
+fn test_sorted_queries() {
+    use crate::eval::eval;
+    use crate::reader::read_form;
+    use crate::sandbox::{environment, Sandbox};
+
+    let env = environment(&Sandbox::pure()).unwrap();
+    let run = |code: &str| eval(read_form().parse(code.as_bytes()).unwrap(), &env).map(|v| v.pr_str(true));
+
+    run("(def! sm (sorted-map-by > 1 :a 5 :e 3 :c 2 :b 4 :d))").unwrap();
+    assert_eq!(run("sm").unwrap(), "{5 :e 4 :d 3 :c 2 :b 1 :a}");
+    assert_eq!(run("(subseq sm > 2)").unwrap(), "([1 :a])");
+    assert_eq!(run("(rsubseq sm > 2)").unwrap(), "([1 :a])");
+    assert_eq!(run("(subseq sm <= 4)").unwrap(), "([5 :e] [4 :d])");
+
+    run("(def! ss (apply sorted-set (range 10)))").unwrap();
+    assert_eq!(run("(subseq ss >= 3 < 6)").unwrap(), "(3 4 5)");
+    assert_eq!(run("(rsubseq ss > 3 <= 6)").unwrap(), "(6 5 4)");
+    assert_eq!(run("(rsubseq ss < 2)").unwrap(), "(1 0)");
+    assert_eq!(run("(subseq ss (fn* (a b) (not (= a b))) 4 < 7)").unwrap(), "(0 1 2 3 5 6)");
+    assert_eq!(run("(subseq ss > 9)").unwrap(), "nil");
+    assert_eq!(run("(subseq ss < 3 > 6)").unwrap(), "nil");
+
+    // A comparator that fails leaves the collection as it was.
+    run("(def! unlucky (fn* (x) (if (= x 13) (throw \"unlucky\"))))").unwrap();
+    run("(def! picky (sorted-set-by (fn* (a b) (do (unlucky a) (unlucky b) (compare a b))) 1 2))").unwrap();
+    assert_eq!(run("(conj picky 13)").unwrap_err().to_string(), "Exception: unlucky");
+    assert_eq!(run("picky").unwrap(), "#{1 2}");
+    assert_eq!(run("(subseq picky > 13)").unwrap_err().to_string(), "Exception: unlucky");
+}
@@ -0,0 +1,275 @@
+//! Builtins of the `str` namespace. Positions and lengths count grapheme
+//! clusters, so `(str/subs "e\u{301}x" 1)` never splits the accented `e`.
+
+use regex::{NoExpand, Regex};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::core::{assert_min_args, match_result};
+use crate::env::Env;
+use crate::eval::handle_function;
+use crate::types::*;
+
+use std::convert::TryFrom;
+
+fn graphemes(s: &str) -> Vec<&str> {
+    s.graphemes(true).collect()
+}
+
+/// Number of graphemes in `s` that start before byte offset `byte`.
+fn grapheme_index(s: &str, byte: usize) -> usize {
+    s.grapheme_indices(true).take_while(|(i, _)| *i < byte).count()
+}
+
+fn index_arg(args: &[MValue], i: usize) -> Result<Option<usize>> {
+    match args.get(i) {
+        Some(v) if !v.is_nil() => {
+            let n = v.cast_to_int()?;
+            usize::try_from(n)
+                .map(Some)
+                .map_err(|_| Error::EvalError(format!("Index {} is negative", n)))
+        },
+        _ => Ok(None),
+    }
+}
+
+fn as_regex(value: &MValue) -> Result<Regex> {
+    if value.is_regex() {
+        value.cast_to_regex()
+    } else {
+        MValue::regex(&regex::escape(&value.cast_to_string()?))?.cast_to_regex()
+    }
+}
+
+/// `(str/split s separator)` or `(str/split s separator limit)`: splits on a
+/// string or regex, returning at most `limit` parts. An empty separator
+/// splits into graphemes.
+pub fn split(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let s = args[0].cast_to_string()?;
+    let limit = index_arg(&args, 2)?;
+
+    if !args[1].is_regex() && args[1].cast_to_string()?.is_empty() {
+        let parts = graphemes(&s);
+        let n = limit.unwrap_or(parts.len()).min(parts.len()).max(1);
+        let mut values = parts[..n - 1].iter().map(MValue::string).collect::<Vec<_>>();
+        values.push(MValue::string(parts[n - 1..].concat()));
+        return Ok(MValue::vector(values));
+    }
+
+    let regex = as_regex(&args[1])?;
+    let parts = match limit {
+        Some(limit) => regex.splitn(&s, limit).map(MValue::string).collect(),
+        None => regex.split(&s).map(MValue::string).collect(),
+    };
+
+    Ok(MValue::vector(parts))
+}
+
+/// `(str/join coll)` or `(str/join separator coll)`: concatenates the
+/// elements of `coll` as `str` would, with `separator` between them.
+pub fn join(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let (separator, coll) = match args.len() {
+        1 => (String::new(), &args[0]),
+        _ => (args[0].cast_to_string()?, &args[1]),
+    };
+
+    let parts = if coll.is_nil() { vec![] } else { coll.cast_to_list()? };
+    let parts = parts.iter().map(|v| v.pr_str(false)).collect::<Vec<_>>();
+
+    Ok(MValue::string(parts.join(&separator)))
+}
+
+/// `(str/trim s)`: removes Unicode whitespace from both ends.
+pub fn trim(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::string(args[0].cast_to_string()?.trim()))
+}
+
+/// `(str/triml s)`: removes Unicode whitespace from the start.
+pub fn triml(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::string(args[0].cast_to_string()?.trim_start()))
+}
+
+/// `(str/trimr s)`: removes Unicode whitespace from the end.
+pub fn trimr(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::string(args[0].cast_to_string()?.trim_end()))
+}
+
+/// `(str/upper-case s)`: full Unicode upper-casing, so "ß" becomes "SS".
+pub fn upper_case(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::string(args[0].cast_to_string()?.to_uppercase()))
+}
+
+/// `(str/lower-case s)`: full Unicode lower-casing.
+pub fn lower_case(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::string(args[0].cast_to_string()?.to_lowercase()))
+}
+
+/// `(str/starts-with? s prefix)`
+pub fn starts_with_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let s = args[0].cast_to_string()?;
+    Ok(MValue::bool(s.starts_with(&args[1].cast_to_string()?)))
+}
+
+/// `(str/ends-with? s suffix)`
+pub fn ends_with_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let s = args[0].cast_to_string()?;
+    Ok(MValue::bool(s.ends_with(&args[1].cast_to_string()?)))
+}
+
+/// `(str/includes? s substring)`
+pub fn includes_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let s = args[0].cast_to_string()?;
+    Ok(MValue::bool(s.contains(&args[1].cast_to_string()?)))
+}
+
+/// `(str/index-of s value)` or `(str/index-of s value from)`: the grapheme
+/// index of the first occurrence of `value` at or after `from`, or nil.
+pub fn index_of(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let s = args[0].cast_to_string()?;
+    let value = args[1].cast_to_string()?;
+    let from = index_arg(&args, 2)?.unwrap_or(0);
+
+    let start = s.grapheme_indices(true).nth(from).map(|(i, _)| i).unwrap_or_else(|| s.len());
+    let r = s[start..]
+        .find(&value)
+        .map(|i| MValue::integer(grapheme_index(&s, start + i) as i64))
+        .unwrap_or_else(MValue::nil);
+
+    Ok(r)
+}
+
+/// `(str/subs s start)` or `(str/subs s start end)`: the graphemes from
+/// `start` up to, but excluding, `end`.
+pub fn subs(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let s = args[0].cast_to_string()?;
+    let parts = graphemes(&s);
+    let start = index_arg(&args, 1)?.unwrap_or(0);
+    let end = index_arg(&args, 2)?.unwrap_or(parts.len());
+
+    if start > end || end > parts.len() {
+        return Err(Error::EvalError(format!(
+            "String index out of range: {}..{} of {}", start, end, parts.len())));
+    }
+
+    Ok(MValue::string(parts[start..end].concat()))
+}
+
+/// `(str/replace s match replacement)`: replaces every occurrence of a
+/// string or regex. The replacement is either a string, where `$1` and
+/// `${name}` refer to regex groups, or a function called with each match as
+/// `re-find` would return it.
+pub fn replace(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 3);
+
+    let s = args[0].cast_to_string()?;
+    let regex = as_regex(&args[1])?;
+    let replacement = &args[2];
+
+    if replacement.is_builtin() || replacement.is_lambda() {
+        let mut result = String::new();
+        let mut last = 0;
+
+        for caps in regex.captures_iter(&s) {
+            let m = caps.get(0).expect("group 0 is always present");
+            let value = handle_function(replacement, vec![match_result(&regex, &caps)])?;
+
+            result.push_str(&s[last..m.start()]);
+            result.push_str(&value.pr_str(false));
+            last = m.end();
+        }
+
+        result.push_str(&s[last..]);
+        return Ok(MValue::string(result));
+    }
+
+    let replacement = replacement.cast_to_string()?;
+
+    let r = if args[1].is_regex() {
+        regex.replace_all(&s, replacement.as_str())
+    } else {
+        regex.replace_all(&s, NoExpand(&replacement))
+    };
+
+    Ok(MValue::string(r))
+}
+
+/// `(str/split-lines s)`: splits on `\n` and `\r\n`.
+pub fn split_lines(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let s = args[0].cast_to_string()?;
+    Ok(MValue::vector(s.lines().map(MValue::string).collect()))
+}
+
+/// `(str/blank? s)`: true for nil and for strings of only whitespace.
+pub fn blank_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    if args[0].is_nil() {
+        return Ok(MValue::bool(true));
+    }
+
+    let s = args[0].cast_to_string()?;
+    Ok(MValue::bool(s.trim().is_empty()))
+}
+
+/// `(str/pad-left s width)` or `(str/pad-left s width pad)`: prepends `pad`
+/// (a space by default) until `s` is `width` graphemes long.
+pub fn pad_left(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let s = args[0].pr_str(false);
+    let width = index_arg(&args, 1)?.unwrap_or(0);
+    let pad = match args.get(2) {
+        Some(pad) => pad.pr_str(false),
+        None => " ".to_string(),
+    };
+
+    let pad_graphemes = graphemes(&pad);
+    if pad_graphemes.is_empty() {
+        return Err(Error::EvalError("Padding can't be empty".to_string()));
+    }
+
+    let missing = width.saturating_sub(graphemes(&s).len());
+    let padding = pad_graphemes.iter().cycle().take(missing).cloned().collect::<String>();
+
+    Ok(MValue::string(padding + &s))
+}
+
+/// `(str/reverse s)`: reverses the graphemes of `s`.
+pub fn reverse(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let s = args[0].cast_to_string()?;
+    Ok(MValue::string(s.graphemes(true).rev().collect::<String>()))
+}
+
+#[test]
+fn test_graphemes() {
+    let s = || MValue::string("ae\u{301}b");
+
+    assert_eq!(reverse(vec![s()], None).unwrap(), MValue::string("be\u{301}a"));
+    assert_eq!(subs(vec![s(), MValue::integer(1), MValue::integer(2)], None).unwrap(),
+               MValue::string("e\u{301}"));
+    assert_eq!(index_of(vec![s(), MValue::string("b")], None).unwrap(), MValue::integer(2));
+    assert_eq!(pad_left(vec![s(), MValue::integer(5), MValue::string("0")], None).unwrap(),
+               MValue::string("00ae\u{301}b"));
+}
@@ -0,0 +1,412 @@
+//! Transducers. A transducer is a builtin that takes a reducing function
+//! and returns another one; reducing functions are called with no
+//! arguments for an initial value, with the accumulator alone to complete,
+//! and with the accumulator and an input to step.
+//!
+//! The arguments of a transducer, such as the function given to `(map f)`,
+//! live in the environment of the builtin, and stateful transducers get a
+//! fresh `state` atom each time they are applied.
+
+use crate::core::{assert_min_args, conj};
+use crate::env::Env;
+use crate::eval::handle_function;
+use crate::sequences::{count_arg, unfold, Cursor};
+use crate::types::*;
+
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+fn bound(env: &Option<Env>, name: &str) -> Result<MValue> {
+    env.as_ref()
+        .and_then(|env| env.get(name))
+        .ok_or_else(|| Error::NoSymbolFound(name.to_string()))
+}
+
+fn closure(f: FnExpr, env: Env, bindings: Vec<(&str, MValue)>) -> MValue {
+    for (name, value) in bindings {
+        env.set(name, value);
+    }
+
+    MValue::function(f, Some(env))
+}
+
+/// A transducer whose reducing functions are `step` with the given
+/// arguments bound.
+fn transducer(step: FnExpr, bindings: Vec<(&str, MValue)>) -> MValue {
+    let mut bindings = bindings;
+    bindings.push(("step", MValue::function(step, None)));
+
+    closure(apply_transducer, Env::new(None), bindings)
+}
+
+fn apply_transducer(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let step = match *bound(&env, "step")?.0 {
+        MalVal::Fun(step, _, _) => step,
+        _ => return Err(Error::EvalError("Transducer has no step function".to_string())),
+    };
+
+    Ok(closure(step, Env::new(env), vec![
+        ("rf", args[0].clone()),
+        ("state", MValue::atom(MValue::nil())),
+    ]))
+}
+
+/// Handles the init and completion arities by passing them on to the
+/// wrapped reducing function, leaving the accumulator and input otherwise.
+fn arities(args: &[MValue], env: &Option<Env>) -> Result<std::result::Result<(MValue, MValue), MValue>> {
+    let rf = bound(env, "rf")?;
+
+    match args.len() {
+        0 => handle_function(&rf, vec![]).map(Err),
+        1 => handle_function(&rf, vec![args[0].clone()]).map(Err),
+        _ => Ok(Ok((args[0].clone(), args[1].clone()))),
+    }
+}
+
+fn call_rf(env: &Option<Env>, acc: MValue, x: MValue) -> Result<MValue> {
+    handle_function(&bound(env, "rf")?, vec![acc, x])
+}
+
+fn state(env: &Option<Env>) -> Result<MValue> {
+    bound(env, "state")?.atom_deref()
+}
+
+fn set_state(env: &Option<Env>, value: MValue) -> Result<MValue> {
+    bound(env, "state")?.atom_reset(value)
+}
+
+fn map_step(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let (acc, x) = match arities(&args, &env)? {
+        Ok(step) => step,
+        Err(done) => return Ok(done),
+    };
+
+    let x = handle_function(&bound(&env, "f")?, vec![x])?;
+    call_rf(&env, acc, x)
+}
+
+fn keep_step(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let (acc, x) = match arities(&args, &env)? {
+        Ok(step) => step,
+        Err(done) => return Ok(done),
+    };
+
+    let wanted = bound(&env, "wanted")?.cast_to_bool();
+
+    if handle_function(&bound(&env, "pred")?, vec![x.clone()])?.is_truthy() == wanted {
+        call_rf(&env, acc, x)
+    } else {
+        Ok(acc)
+    }
+}
+
+fn take_step(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let (acc, x) = match arities(&args, &env)? {
+        Ok(step) => step,
+        Err(done) => return Ok(done),
+    };
+
+    let n = bound(&env, "n")?.cast_to_int()?;
+    let taken = state(&env)?.cast_to_int().unwrap_or(0) + 1;
+    set_state(&env, MValue::integer(taken))?;
+
+    if taken > n {
+        return Ok(MValue::reduced(acc));
+    }
+
+    let acc = call_rf(&env, acc, x)?;
+
+    if taken == n && !acc.is_reduced() {
+        Ok(MValue::reduced(acc))
+    } else {
+        Ok(acc)
+    }
+}
+
+fn drop_step(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let (acc, x) = match arities(&args, &env)? {
+        Ok(step) => step,
+        Err(done) => return Ok(done),
+    };
+
+    let n = bound(&env, "n")?.cast_to_int()?;
+    let dropped = state(&env)?.cast_to_int().unwrap_or(0);
+
+    if dropped < n {
+        set_state(&env, MValue::integer(dropped + 1))?;
+        Ok(acc)
+    } else {
+        call_rf(&env, acc, x)
+    }
+}
+
+fn take_while_step(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let (acc, x) = match arities(&args, &env)? {
+        Ok(step) => step,
+        Err(done) => return Ok(done),
+    };
+
+    if handle_function(&bound(&env, "pred")?, vec![x.clone()])?.is_truthy() {
+        call_rf(&env, acc, x)
+    } else {
+        Ok(MValue::reduced(acc))
+    }
+}
+
+fn drop_while_step(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let (acc, x) = match arities(&args, &env)? {
+        Ok(step) => step,
+        Err(done) => return Ok(done),
+    };
+
+    // The state turns true once an input fails the predicate.
+    if !state(&env)?.is_truthy() {
+        if handle_function(&bound(&env, "pred")?, vec![x.clone()])?.is_truthy() {
+            return Ok(acc);
+        }
+        set_state(&env, MValue::bool(true))?;
+    }
+
+    call_rf(&env, acc, x)
+}
+
+fn distinct_step(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let (acc, x) = match arities(&args, &env)? {
+        Ok(step) => step,
+        Err(done) => return Ok(done),
+    };
+
+    let mut seen = match state(&env)? {
+        s if s.is_set() => s.cast_to_set()?,
+        _ => Default::default(),
+    };
+
+    if seen.insert(x.hash_key()?, x.clone()).is_some() {
+        return Ok(acc);
+    }
+
+    set_state(&env, MValue::from_set(seen))?;
+    call_rf(&env, acc, x)
+}
+
+/// Steps every element of `coll` into the reducing function, keeping a
+/// `reduced` result wrapped so the outer reduction stops too.
+fn step_all(env: &Option<Env>, acc: MValue, coll: &MValue) -> Result<MValue> {
+    let mut acc = acc;
+    let mut cursor = Cursor::new(coll)?;
+
+    while let Some((x, rest)) = cursor.next()? {
+        acc = call_rf(env, acc, x)?;
+
+        if acc.is_reduced() {
+            break;
+        }
+        cursor = rest;
+    }
+
+    Ok(acc)
+}
+
+fn cat_step(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    match arities(&args, &env)? {
+        Ok((acc, coll)) => step_all(&env, acc, &coll),
+        Err(done) => Ok(done),
+    }
+}
+
+fn mapcat_step(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    let (acc, x) = match arities(&args, &env)? {
+        Ok(step) => step,
+        Err(done) => return Ok(done),
+    };
+
+    let coll = handle_function(&bound(&env, "f")?, vec![x])?;
+    step_all(&env, acc, &coll)
+}
+
+pub fn map(f: &MValue) -> MValue {
+    transducer(map_step, vec![("f", f.clone())])
+}
+
+pub fn filter(pred: &MValue) -> MValue {
+    transducer(keep_step, vec![("pred", pred.clone()), ("wanted", MValue::bool(true))])
+}
+
+pub fn remove(pred: &MValue) -> MValue {
+    transducer(keep_step, vec![("pred", pred.clone()), ("wanted", MValue::bool(false))])
+}
+
+pub fn take(n: &MValue) -> Result<MValue> {
+    count_arg(n)?;
+    Ok(transducer(take_step, vec![("n", n.clone())]))
+}
+
+pub fn drop(n: &MValue) -> Result<MValue> {
+    count_arg(n)?;
+    Ok(transducer(drop_step, vec![("n", n.clone())]))
+}
+
+pub fn take_while(pred: &MValue) -> MValue {
+    transducer(take_while_step, vec![("pred", pred.clone())])
+}
+
+pub fn drop_while(pred: &MValue) -> MValue {
+    transducer(drop_while_step, vec![("pred", pred.clone())])
+}
+
+pub fn distinct() -> MValue {
+    transducer(distinct_step, vec![])
+}
+
+pub fn cat() -> MValue {
+    transducer(cat_step, vec![])
+}
+
+pub fn mapcat(f: &MValue) -> MValue {
+    transducer(mapcat_step, vec![("f", f.clone())])
+}
+
+fn reduce_with(rf: &MValue, init: MValue, coll: &MValue) -> Result<MValue> {
+    let mut acc = init;
+    let mut cursor = Cursor::new(coll)?;
+
+    while let Some((x, rest)) = cursor.next()? {
+        acc = handle_function(rf, vec![acc, x])?;
+
+        if acc.is_reduced() {
+            break;
+        }
+        cursor = rest;
+    }
+
+    handle_function(rf, vec![acc.unreduced()])
+}
+
+/// `(transduce xform f coll)` or `(transduce xform f init coll)`; without
+/// `init`, `(f)` gives the starting value.
+pub fn transduce(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 3);
+
+    let rf = handle_function(&args[0], vec![args[1].clone()])?;
+    let init = match args.len() {
+        3 => handle_function(&args[1], vec![])?,
+        _ => args[2].clone(),
+    };
+
+    reduce_with(&rf, init, &args[args.len() - 1])
+}
+
+/// `(into to coll)` or `(into to xform coll)` adds the inputs to `to` with
+/// `conj`.
+pub fn into(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 2);
+
+    let conj = MValue::function(conj, None);
+    let rf = match args.len() {
+        2 => conj,
+        _ => handle_function(&args[1], vec![conj])?,
+    };
+
+    reduce_with(&rf, args[0].clone(), &args[args.len() - 1])
+}
+
+fn buffer_step(args: Vec<MValue>, env: Option<Env>) -> Result<MValue> {
+    if args.len() >= 2 {
+        let buffer = bound(&env, "buffer")?;
+        let mut values = buffer.atom_deref()?.cast_to_list()?;
+        values.push(args[1].clone());
+        buffer.atom_reset(MValue::list(values))?;
+    }
+
+    Ok(args.first().cloned().unwrap_or_else(MValue::nil))
+}
+
+/// A lazy sequence of what `xform` makes of the elements of `coll`, realized
+/// as far as the consumer asks for.
+fn lazy_transduce(xform: &MValue, coll: &MValue) -> Result<MValue> {
+    let buffer = MValue::atom(MValue::list(vec![]));
+    let rf = handle_function(xform, vec![
+        closure(buffer_step, Env::new(None), vec![("buffer", buffer.clone())])])?;
+
+    let drain = move |pending: &mut VecDeque<MValue>| -> Result<()> {
+        pending.extend(buffer.atom_deref()?.cast_to_list()?);
+        buffer.atom_reset(MValue::list(vec![]))?;
+        Ok(())
+    };
+
+    Ok(unfold((Some(Cursor::new(coll)?), VecDeque::new()), Rc::new(
+        move |(mut cursor, mut pending): (Option<Cursor>, VecDeque<MValue>)| loop {
+            if let Some(v) = pending.pop_front() {
+                return Ok(Some((v, (cursor, pending))));
+            }
+
+            let c = match cursor {
+                Some(c) => c,
+                None => return Ok(None),
+            };
+
+            cursor = match c.next()? {
+                Some((x, rest)) => {
+                    let acc = handle_function(&rf, vec![MValue::nil(), x])?;
+                    Some(rest).filter(|_| !acc.is_reduced())
+                },
+                None => None,
+            };
+
+            if cursor.is_none() {
+                handle_function(&rf, vec![MValue::nil()])?;
+            }
+
+            drain(&mut pending)?;
+        })))
+}
+
+/// `(sequence coll)` or `(sequence xform coll)`
+pub fn sequence(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    match args.len() {
+        1 if args[0].is_lazy_seq() => Ok(args[0].clone()),
+        1 => Ok(MValue::list(crate::sequences::to_seq(&args[0])?)),
+        _ => lazy_transduce(&args[0], &args[1]),
+    }
+}
+
+/// `(eduction xform* coll)` applies the transducers in order, the first
+/// one outermost as with `comp`.
+pub fn eduction(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+
+    let (coll, xforms) = args.split_last().expect("there is at least one argument");
+    let xform = crate::core::comp(xforms.to_vec(), None)?;
+
+    lazy_transduce(&xform, coll)
+}
+
+/// `(reduced x)`
+pub fn reduced(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::reduced(args[0].clone()))
+}
+
+/// `(reduced? x)`
+pub fn reduced_q(args: Vec<MValue>, _env: Option<Env>) -> Result<MValue> {
+    assert_min_args!(&args, 1);
+    Ok(MValue::bool(args[0].is_reduced()))
+}
+
+#[test]
+fn test_transducers() {
+    let inc = MValue::function(|a, _| Ok(MValue::integer(a[0].cast_to_int()? + 1)), None);
+    let odd = MValue::function(|a, _| Ok(MValue::bool(a[0].cast_to_int()? % 2 == 1)), None);
+    let ints = |v: Vec<i64>| MValue::vector(v.into_iter().map(MValue::integer).collect());
+
+    let xform = crate::core::comp(vec![filter(&odd), map(&inc), take(&MValue::integer(2)).unwrap()], None)
+        .unwrap();
+    let naturals = crate::sequences::range(vec![], None).unwrap();
+
+    assert_eq!(into(vec![ints(vec![]), xform.clone(), naturals.clone()], None).unwrap(), ints(vec![2, 4]));
+    assert_eq!(sequence(vec![xform, naturals], None).unwrap(), ints(vec![2, 4]));
+}
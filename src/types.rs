@@ -1,15 +1,23 @@
 use MalVal::*;
 
-use std::collections::HashMap;
 use std::fmt::{self, Display, Debug};
+use std::convert::TryFrom;
 use std::cell::RefCell;
+use std::mem::size_of;
 use std::string::ToString;
 
 use std::rc::Rc;
 use crate::env::Env;
+use crate::limits;
+use crate::ports::Port;
+use crate::sorted::Sorted;
+use indexmap::IndexMap;
+use regex::Regex;
 
 pub type FnExpr = fn(Vec<MValue>, Option<Env>) -> Result<MValue>;
 
+pub type Thunk = Rc<dyn Fn() -> Result<MValue>>;
+
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -18,19 +26,140 @@ pub struct MValue(pub Rc<MalVal>, bool);
 #[derive(Clone)]
 pub enum MalVal {
     Int(i64),
+    Float(f64),
     Bool(bool),
     List(Vec<MValue>, MValue),
     Vector(Vec<MValue>, MValue),
-    HashMap(HashMap<(String, String), MValue>, MValue),
+    HashMap(IndexMap<(String, String), MValue>, MValue),
+    Set(IndexMap<(String, String), MValue>, MValue),
+    SortedMap(Sorted, MValue),
+    SortedSet(Sorted, MValue),
     Sym(String),
     Str(String),
     Keyword(String),
     Fun(FnExpr, Option<Env>, MValue),
     Atom(RefCell<MValue>),
+    Port(Port),
+    Regex(Regex),
     Lambda(MClosure, MValue),
+    LazySeq(Lazy),
+    Reduced(MValue),
+    /// An EDN tagged literal such as `#inst "..."`, kept with its tag so it
+    /// writes back the same way.
+    Tagged(String, MValue),
     Nil,
 }
 
+impl Drop for MValue {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.0) == 1 {
+            limits::release(self.0.footprint());
+        }
+    }
+}
+
+impl MalVal {
+    /// Roughly how many bytes a value takes up, not counting the values it
+    /// holds, which account for themselves.
+    fn footprint(&self) -> usize {
+        let payload = match self {
+            Str(s) | Sym(s) | Keyword(s) | Tagged(s, _) => s.len(),
+            List(l, _) | Vector(l, _) => l.len() * size_of::<MValue>(),
+            HashMap(m, _) | Set(m, _) => m.keys()
+                .map(|(k, kind)| k.len() + kind.len() + size_of::<MValue>())
+                .sum(),
+            SortedMap(s, _) | SortedSet(s, _) => s.len() * 2 * size_of::<MValue>(),
+            _ => 0,
+        };
+
+        size_of::<MalVal>() + payload
+    }
+}
+
+/// A lazy sequence is realized one step at a time: the thunk runs at most
+/// once and its result is cached as the first element and the rest.
+#[derive(Clone)]
+pub enum LazyState {
+    Pending(Thunk),
+    Realizing,
+    Realized(Option<(MValue, MValue)>),
+}
+
+#[derive(Clone)]
+pub struct Lazy(RefCell<LazyState>);
+
+impl Lazy {
+    fn replace(&self, state: LazyState) -> LazyState {
+        std::mem::replace(&mut *self.0.borrow_mut(), state)
+    }
+
+    /// Calls `f` with the first element and the rest once realized; what a
+    /// pending thunk holds on to can't be seen.
+    pub(crate) fn trace(&self, f: &mut dyn FnMut(&MValue)) {
+        if let LazyState::Realized(Some((ref first, ref rest))) = *self.0.borrow() {
+            f(first);
+            f(rest);
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        self.replace(LazyState::Realized(None));
+    }
+
+    /// Realizes the first step in this cell, as `MValue::realize` does for
+    /// the value holding it, for when there is only the `MalVal` at hand.
+    fn realize(&self) -> Result<Option<(MValue, MValue)>> {
+        match self.replace(LazyState::Realizing) {
+            LazyState::Realized(step) => {
+                self.replace(LazyState::Realized(step.clone()));
+                Ok(step)
+            },
+            LazyState::Realizing => Err(Error::EvalError(
+                "Lazy sequence depends on its own value".to_string())),
+            LazyState::Pending(thunk) => {
+                match limits::step().and_then(|_| thunk()).and_then(|next| next.realize()) {
+                    Ok(step) => {
+                        self.replace(LazyState::Realized(step.clone()));
+                        Ok(step)
+                    },
+                    Err(e) => {
+                        self.replace(LazyState::Pending(thunk));
+                        Err(e)
+                    },
+                }
+            },
+        }
+    }
+
+    /// A sequence of the same elements that shares what this cell caches,
+    /// rather than a copy that would run the thunks again.
+    fn shared(&self) -> Result<MValue> {
+        let step = self.realize()?;
+        Ok(MValue::new(MalVal::LazySeq(Lazy(RefCell::new(LazyState::Realized(step)))), false))
+    }
+}
+
+impl Drop for Lazy {
+    // Dropping a long realized chain one cell at a time keeps the recursion
+    // of the default drop from overflowing the stack.
+    fn drop(&mut self) {
+        let mut rest = match self.replace(LazyState::Realizing) {
+            LazyState::Realized(Some((_, rest))) => rest,
+            _ => return,
+        };
+
+        while Rc::strong_count(&rest.0) == 1 {
+            rest = match *rest.0 {
+                LazySeq(ref lazy) => match lazy.replace(LazyState::Realizing) {
+                    LazyState::Realized(Some((_, rest))) => rest,
+                    _ => return,
+                },
+                _ => return,
+            };
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MClosure {
     env: Env,
@@ -47,6 +176,27 @@ impl MClosure {
         }
     }
 
+    pub(crate) fn env(&self) -> &Env {
+        &self.env
+    }
+
+    pub(crate) fn body(&self) -> &MValue {
+        &self.body
+    }
+
+    pub fn parameters(&self) -> &[String] {
+        &self.parameters
+    }
+
+    /// The `fn*` form the closure was made from.
+    pub fn source(&self) -> MValue {
+        MValue::list(vec![
+            MValue::symbol("fn*"),
+            MValue::list(self.parameters.iter().map(MValue::symbol).collect()),
+            self.body.clone(),
+        ])
+    }
+
     pub fn apply(&self, exprs: Vec<MValue>) -> Result<(MValue, Env)> {
         let copy = self.clone();
         let env = Env::with_binds(Some(copy.env), copy.parameters, exprs)?;
@@ -56,9 +206,15 @@ impl MClosure {
 }
 
 impl MValue {
+    fn new(value: MalVal, is_macro: bool) -> MValue {
+        limits::allocate(value.footprint());
+        MValue(Rc::new(value), is_macro)
+    }
+
     pub fn meta(&self) -> Result<MValue> {
         match *self.0 {
-            List(_, ref v) | Vector(_, ref v) | HashMap(_, ref v)
+            List(_, ref v) | Vector(_, ref v) | HashMap(_, ref v) | Set(_, ref v)
+                | SortedMap(_, ref v) | SortedSet(_, ref v)
                 | Fun(_,_, ref v) | Lambda(_, ref v) => Ok(v.clone()),
             _ => Err(Error::EvalError(format!("{} has no metadata", self))),
         }
@@ -69,85 +225,151 @@ impl MValue {
             List(ref v, _) => List(v.clone(), meta),
             Vector(ref v, _) => Vector(v.clone(), meta), 
             HashMap(ref v, _) => HashMap(v.clone(), meta),
+            Set(ref v, _) => Set(v.clone(), meta),
+            SortedMap(ref v, _) => SortedMap(v.clone(), meta),
+            SortedSet(ref v, _) => SortedSet(v.clone(), meta),
             Fun(f, ref env, _) => Fun(f, env.clone(), meta),
             Lambda(ref v, _) => Lambda(v.clone(), meta),
             _ => return Err(Error::EvalError(format!("{} can't hold metadata", self))),
         };
 
-        Ok(MValue(Rc::new(r), self.1))
+        Ok(MValue::new(r, self.1))
     }
 
     pub fn enum_key(&self) -> String {
         match *self.0 {
             Int(_) => "Int".to_string(),
+            Float(_) => "Float".to_string(),
             Bool(_) => "Bool".to_string(),
             Sym(_) => "Symbol".to_string(),
             Keyword(_) => "Keyword".to_string(),
             Atom(_) => "Atom".to_string(),
+            Port(_) => "Port".to_string(),
+            Regex(_) => "Regex".to_string(),
             Str(_) => "String".to_string(),
             Nil => "Nil".to_string(),
             List(_,_) => "List".to_string(),
             Vector(_,_) => "Vector".to_string(),
             HashMap(_,_) => "Hashmap".to_string(),
+            Set(_,_) => "Set".to_string(),
+            SortedMap(_,_) => "SortedMap".to_string(),
+            SortedSet(_,_) => "SortedSet".to_string(),
+            LazySeq(_) => "LazySeq".to_string(),
+            Reduced(_) => "Reduced".to_string(),
+            Tagged(_, _) => "Tagged".to_string(),
             Fun(_,_,_) | Lambda(_,_) => "Function".to_string(),
         }
     }
 
     pub fn integer(value: i64) -> MValue {
-        MValue(Rc::new(MalVal::Int(value)), false)
+        MValue::new(MalVal::Int(value), false)
+    }
+
+    pub fn float(value: f64) -> MValue {
+        MValue::new(MalVal::Float(value), false)
     }
 
     pub fn bool(value: bool) -> MValue {
-        MValue(Rc::new(MalVal::Bool(value)), false)
+        MValue::new(MalVal::Bool(value), false)
     }
 
     pub fn list(value: Vec<MValue>) -> MValue {
-        MValue(Rc::new(MalVal::List(value, MValue::nil())), false)
+        MValue::new(MalVal::List(value, MValue::nil()), false)
     }
 
     pub fn vector(value: Vec<MValue>) -> MValue {
-        MValue(Rc::new(MalVal::Vector(value, MValue::nil())), false)
+        MValue::new(MalVal::Vector(value, MValue::nil()), false)
     }
 
-    pub fn from_hashmap(hm: HashMap<(String, String), MValue>) -> MValue {
-        MValue(Rc::new(MalVal::HashMap(hm, MValue::nil())), false)
+    pub fn from_hashmap(hm: IndexMap<(String, String), MValue>) -> MValue {
+        MValue::new(MalVal::HashMap(hm, MValue::nil()), false)
     }
 
     pub fn hashmap(values: &mut Vec<MValue>) -> MValue {
-        let v = MValue(Rc::new(MalVal::HashMap(HashMap::new(), MValue::nil())), false);
+        let v = MValue::new(MalVal::HashMap(IndexMap::new(), MValue::nil()), false);
         v.hassoc(values).unwrap()
     }
 
+    pub fn from_set(set: IndexMap<(String, String), MValue>) -> MValue {
+        MValue::new(MalVal::Set(set, MValue::nil()), false)
+    }
+
+    pub fn set(values: Vec<MValue>) -> Result<MValue> {
+        let set = values.into_iter()
+            .map(|v| v.hash_key().map(|k| (k, v)))
+            .collect::<Result<_>>()?;
+
+        Ok(MValue::from_set(set))
+    }
+
+    pub fn sorted_map(sorted: Sorted) -> MValue {
+        MValue::new(MalVal::SortedMap(sorted, MValue::nil()), false)
+    }
+
+    pub fn sorted_set(sorted: Sorted) -> MValue {
+        MValue::new(MalVal::SortedSet(sorted, MValue::nil()), false)
+    }
+
     pub fn symbol<T: ToString>(value: T) -> MValue {
-        MValue(Rc::new(MalVal::Sym(value.to_string())), false)
+        MValue::new(MalVal::Sym(value.to_string()), false)
     }
 
     pub fn string<T: ToString>(value: T) -> MValue {
-        MValue(Rc::new(MalVal::Str(value.to_string())), false)
+        MValue::new(MalVal::Str(value.to_string()), false)
     }
 
     pub fn keyword<T: ToString>(value: T) -> MValue {
-        MValue(Rc::new(MalVal::Keyword(value.to_string())), false)
+        MValue::new(MalVal::Keyword(value.to_string()), false)
     }
 
     pub fn atom(value: MValue) -> MValue {
-        MValue(Rc::new(MalVal::Atom(RefCell::new(value))), false)
+        MValue::new(MalVal::Atom(RefCell::new(value)), false)
+    }
+
+    pub fn port(value: Port) -> MValue {
+        MValue::new(MalVal::Port(value), false)
+    }
+
+    pub fn regex(pattern: &str) -> Result<MValue> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| Error::EvalError(format!("Invalid regex: {}", e)))?;
+
+        Ok(MValue::new(MalVal::Regex(regex), false))
     }
 
     pub fn function(value: FnExpr, env: Option<Env>) -> MValue {
-        MValue(Rc::new(MalVal::Fun(value, env, MValue::nil())), false)
+        MValue::new(MalVal::Fun(value, env, MValue::nil()), false)
     }
 
     pub fn lambda(env: Env, parameters: Vec<String>, body: MValue) -> MValue {
-        MValue(Rc::new(MalVal::Lambda(MClosure {
+        MValue::new(MalVal::Lambda(MClosure {
             env,
             parameters,
             body,
-        }, MValue::nil())), false)
+        }, MValue::nil()), false)
+    }
+
+    pub fn lazy_seq<F: Fn() -> Result<MValue> + 'static>(thunk: F) -> MValue {
+        MValue::new(MalVal::LazySeq(Lazy(RefCell::new(LazyState::Pending(Rc::new(thunk))))), false)
+    }
+
+    /// An already realized lazy sequence, so `rest` can stay unrealized.
+    pub fn lazy_cons(first: MValue, rest: MValue) -> MValue {
+        MValue::new(MalVal::LazySeq(Lazy(RefCell::new(LazyState::Realized(Some((first, rest)))))), false)
+    }
+
+    /// Wraps a value to tell `reduce` and `transduce` to stop early.
+    pub fn reduced(value: MValue) -> MValue {
+        MValue::new(MalVal::Reduced(value), false)
+    }
+
+    /// The tagged literal `#tag value`.
+    pub fn tagged<T: ToString>(tag: T, value: MValue) -> MValue {
+        MValue::new(MalVal::Tagged(tag.to_string(), value), false)
     }
 
     pub fn nil() -> MValue {
-        MValue(Rc::new(MalVal::Nil), false)
+        MValue::new(MalVal::Nil, false)
     }
 
     pub fn is_lambda(&self) -> bool {
@@ -178,6 +400,34 @@ impl MValue {
         }
     }
 
+    pub fn is_sorted_map(&self) -> bool {
+        matches!(*self.0, MalVal::SortedMap(_,_))
+    }
+
+    pub fn is_sorted_set(&self) -> bool {
+        matches!(*self.0, MalVal::SortedSet(_,_))
+    }
+
+    pub fn is_lazy_seq(&self) -> bool {
+        matches!(*self.0, MalVal::LazySeq(_))
+    }
+
+    pub fn is_reduced(&self) -> bool {
+        matches!(*self.0, MalVal::Reduced(_))
+    }
+
+    /// The value inside a `reduced` wrapper, or the value itself.
+    pub fn unreduced(&self) -> MValue {
+        match *self.0 {
+            Reduced(ref v) => v.clone(),
+            _ => self.clone(),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        matches!(*self.0, MalVal::Set(_,_))
+    }
+
     pub fn is_vector(&self) -> bool {
         match *self.0 {
             MalVal::Vector(_,_) => true,
@@ -226,7 +476,7 @@ impl MValue {
 
     pub fn is_number(&self) -> bool {
         match *self.0 {
-            MalVal::Int(_) => true,
+            MalVal::Int(_) | MalVal::Float(_) => true,
             _ => false,
         }
     }
@@ -252,6 +502,10 @@ impl MValue {
         }
     }
 
+    pub fn is_regex(&self) -> bool {
+        matches!(*self.0, MalVal::Regex(_))
+    }
+
     pub fn set_macro(&mut self) {
         self.1 = true;
     }
@@ -263,6 +517,14 @@ impl MValue {
         }
     }
 
+    pub fn cast_to_float(&self) -> Result<f64> {
+        match *self.0 {
+            MalVal::Int(x) => Ok(x as f64),
+            MalVal::Float(x) => Ok(x),
+            _ => Err(Error::EvalError(format!("{} is not a number", self))),
+        }
+    }
+
     pub fn atom_deref(&self) -> Result<MValue> {
         match *self.0 {
             MalVal::Atom(ref x) => Ok(x.borrow().clone()),
@@ -280,6 +542,13 @@ impl MValue {
         }
     }
 
+    pub fn cast_to_regex(&self) -> Result<Regex> {
+        match *self.0 {
+            Regex(ref r) => Ok(r.clone()),
+            _ => Err(Error::EvalError(format!("{} is not a regex", self))),
+        }
+    }
+
     pub fn cast_to_lambda(&self) -> Result<MClosure> {
         match *self.0 {
             Lambda(ref closure, _) => Ok(closure.clone()),
@@ -301,11 +570,33 @@ impl MValue {
         }
     }
 
+    /// Everything but `nil` and `false` counts as true, as in `if`.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(*self.0, Bool(false) | Nil)
+    }
+
+    pub fn hash_key(&self) -> Result<(String, String)> {
+        match *self.0 {
+            Sym(ref x) | Keyword(ref x) | Str(ref x) => Ok((x.clone(), self.enum_key())),
+            Int(_) | Float(_) | Bool(_) | Nil => Ok((self.pr_str(false), self.enum_key())),
+            _ => Err(Error::EvalError(format!("{} can't be used as a key", self))),
+        }
+    }
+
     pub fn reconstruct(value: &(String, String)) -> Result<MValue> {
         match value {
             (v, key) if key == "Symbol" => Ok(MValue::symbol(v)),
             (v, key) if key == "Keyword" => Ok(MValue::keyword(v)),
             (v, key) if key == "String" => Ok(MValue::string(v)),
+            (v, key) if key == "Int" => Ok(MValue::integer(v.parse()?)),
+            (v, key) if key == "Float" => Ok(MValue::float(match v.as_str() {
+                "##Inf" => f64::INFINITY,
+                "##-Inf" => f64::NEG_INFINITY,
+                "##NaN" => f64::NAN,
+                v => v.parse()?,
+            })),
+            (v, key) if key == "Bool" => Ok(MValue::bool(v == "true")),
+            (_, key) if key == "Nil" => Ok(MValue::nil()),
             x => Err(Error::EvalError(format!("Can't reconstruct {:?}", x))),
         }
     }
@@ -313,78 +604,329 @@ impl MValue {
     pub fn hassoc(&self, list: &mut Vec<MValue>) -> Result<MValue> {
         let mut hm = self.clone().cast_to_hashmap()?;
 
-        while !list.is_empty() {
-            let v = list.pop().ok_or_else(|| Error::ParseError(
-                    "Could not extract value for hashmap".to_string()))?;
+        if list.len() % 2 == 1 {
+            return Err(Error::ParseError(
+                    "Could not extract key for hashmap".to_string()));
+        }
 
-            match list.pop() {
-                Some(ref k) if k.is_symbol() || k.is_string() || k.is_keyword() =>
-                    hm.insert((k.cast_to_string()?, k.enum_key()), v),
-                r => return Err(Error::ParseError(
-                        format!("Could not extract key for hashmap: {:?}", r))),
-            };
+        for pair in std::mem::take(list).chunks(2) {
+            hm.insert(pair[0].hash_key()?, pair[1].clone());
         }
 
-        Ok(
-            MValue(
-                Rc::new(
-                    MalVal::HashMap(hm, MValue::nil())
-                ),
-                false
-            )
-        )
+        Ok(MValue::new(MalVal::HashMap(hm, MValue::nil()), false))
     }
 
     pub fn cast_to_list(&self) -> Result<Vec<MValue>> {
         match *self.0 {
             List(ref x, _) | Vector(ref x, _) => Ok(x.to_vec()),
+            Set(ref x, _) => Ok(x.values().cloned().collect()),
+            SortedSet(ref x, _) => Ok(x.keys()),
             Str(ref s) => Ok(s.chars().map(MValue::string).collect()),
+            LazySeq(_) => {
+                let mut values = vec![];
+                let mut step = self.realize()?;
+
+                while let Some((first, rest)) = step {
+                    values.push(first);
+                    step = rest.realize()?;
+                }
+
+                Ok(values)
+            },
             _ => Err(Error::EvalError(format!("{} is not a list", self))),
         }
     }
 
-    pub fn cast_to_hashmap(&self) -> Result<HashMap<(String, String), MValue>> {
+    /// Splits a sequence into its first element and the rest, running the
+    /// thunks of lazy sequences as needed. Thunks that return another lazy
+    /// sequence are followed in a loop rather than by recursion.
+    pub fn realize(&self) -> Result<Option<(MValue, MValue)>> {
+        let mut chain: Vec<(MValue, Thunk)> = vec![];
+        let mut current = self.clone();
+
+        let step = loop {
+            let state = match *current.0 {
+                LazySeq(ref lazy) => lazy.replace(LazyState::Realizing),
+                _ => break split_sequence(&current),
+            };
+
+            match state {
+                LazyState::Realized(step) => {
+                    current.set_lazy_state(LazyState::Realized(step.clone()));
+                    break Ok(step);
+                },
+                LazyState::Realizing => break Err(Error::EvalError(
+                    "Lazy sequence depends on its own value".to_string())),
+                LazyState::Pending(thunk) => match limits::step().and_then(|_| thunk()) {
+                    Ok(next) => {
+                        chain.push((current, thunk));
+                        current = next;
+                    },
+                    Err(e) => {
+                        chain.push((current, thunk));
+                        break Err(e);
+                    },
+                },
+            }
+        };
+
+        match step {
+            Ok(step) => {
+                for (cell, _) in chain {
+                    cell.set_lazy_state(LazyState::Realized(step.clone()));
+                }
+                Ok(step)
+            },
+            Err(e) => {
+                for (cell, thunk) in chain {
+                    cell.set_lazy_state(LazyState::Pending(thunk));
+                }
+                Err(e)
+            },
+        }
+    }
+
+    fn set_lazy_state(&self, state: LazyState) {
+        if let LazySeq(ref lazy) = *self.0 {
+            lazy.replace(state);
+        }
+    }
+
+    /// Realizes every lazy sequence reachable from this value.
+    pub fn force(&self) -> Result<()> {
+        match *self.0 {
+            List(ref l, _) | Vector(ref l, _) => l.iter().try_for_each(MValue::force),
+            HashMap(ref m, _) | Set(ref m, _) => m.values().try_for_each(MValue::force),
+            LazySeq(_) => self.cast_to_list()?.iter().try_for_each(MValue::force),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn cast_to_hashmap(&self) -> Result<IndexMap<(String, String), MValue>> {
         match *self.0 {
             MalVal::HashMap(ref x, _) => Ok(x.clone()),
             _ => Err(Error::EvalError(format!("{} is not a hashmap", self))),
         }
     }
 
-    pub fn pr_str(&self, readably: bool) -> String {
+    pub fn cast_to_set(&self) -> Result<IndexMap<(String, String), MValue>> {
         match *self.0 {
+            MalVal::Set(ref x, _) => Ok(x.clone()),
+            _ => Err(Error::EvalError(format!("{} is not a set", self))),
+        }
+    }
+
+    pub fn pr_str(&self, readably: bool) -> String {
+        Printer::new(readably, PrintLimits::default()).print(self)
+    }
+
+    /// Like `pr_str`, but elides what lies beyond `limits`.
+    pub fn pr_str_limited(&self, readably: bool, limits: PrintLimits) -> String {
+        Printer::new(readably, limits).print(self)
+    }
+}
+
+/// How much of a value to print, as set by `*print-level*` and
+/// `*print-length*`: collections nested deeper than `level` print as `#`,
+/// and those with more than `length` items end in `...`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrintLimits {
+    pub level: Option<usize>,
+    pub length: Option<usize>,
+}
+
+impl PrintLimits {
+    /// Reads the limits from `*print-level*` and `*print-length*` in `env`;
+    /// anything but a non-negative integer means no limit.
+    pub fn from_env(env: &Env) -> Self {
+        let limit = |name: &str| env.get(name)
+            .and_then(|v| match *v.0 {
+                Int(n) => usize::try_from(n).ok(),
+                _ => None,
+            });
+
+        PrintLimits { level: limit("*print-level*"), length: limit("*print-length*") }
+    }
+}
+
+/// Values are immutable except for atoms, so a structure can only refer to
+/// itself through one. The printer keeps the atoms it is inside of and
+/// prints a reference back to any of them as `#<cycle>`.
+struct Printer {
+    readably: bool,
+    limits: PrintLimits,
+    depth: usize,
+    atoms: Vec<*const RefCell<MValue>>,
+}
+
+impl Printer {
+    fn new(readably: bool, limits: PrintLimits) -> Self {
+        Printer { readably, limits, depth: 0, atoms: vec![] }
+    }
+
+    fn print(&mut self, value: &MValue) -> String {
+        match *value.0 {
             Int(ref k) => k.to_string(),
+            Float(ref k) => format_float(*k),
             Bool(ref b) => b.to_string(),
             Sym(ref s) => s.to_string(),
             Keyword(ref s) => format!(":{}", s),
-            Atom(ref v) => format!("(atom {})", v.borrow()),
+            Atom(ref v) => self.atom(v),
+            Port(ref p) => format!("#<port {}>", p.name()),
+            // The reader unescapes only `\"` in a regex literal, so that is
+            // all there is to escape.
+            Regex(ref r) => format!("#\"{}\"", r.as_str().replace('"', "\\\"")),
             Str(ref s) => {
-                if readably {
+                if self.readably {
                     format!("\"{}\"", escape_str(s))
                 } else {
                     s.to_string()
                 }
             },
             Nil => "nil".to_string(),
-            List(ref l, _) => print_sequence(&l, "(", ")", readably),
-            Vector(ref l, _) => print_sequence(&l, "[", "]", readably),
+            List(ref l, _) => self.sequence(l, "(", ")"),
+            Vector(ref l, _) => self.sequence(l, "[", "]"),
             HashMap(ref l, _) => {
                 let l = l.iter()
-                    .flat_map(|(k, v)| vec![MValue::reconstruct(k).unwrap(), v.clone()])
-                    .collect::<Vec<MValue>>();
-                print_sequence(&l, "{", "}", readably)
+                    .map(|(k, v)| MValue::reconstruct(k).map(|k| (k, v.clone())))
+                    .collect::<Result<Vec<(MValue, MValue)>>>();
+
+                match l {
+                    Ok(l) => self.entries(&l),
+                    Err(error) => format!("#<{}>", error),
+                }
             },
+            Set(ref s, _) => {
+                let l = s.values().cloned().collect::<Vec<MValue>>();
+                self.sequence(&l, "#{", "}")
+            },
+            SortedMap(ref s, _) => self.entries(&s.entries()),
+            SortedSet(ref s, _) => self.sequence(&s.keys(), "#{", "}"),
+            Reduced(ref v) => format!("#<reduced {}>", self.print(v)),
+            Tagged(ref tag, ref v) => format!("#{} {}", tag, self.print(v)),
+            LazySeq(_) => self.lazy_seq(value),
             Fun(_,_,_) | Lambda(_,_) => "#<function>".to_string(),
         }
     }
+
+    fn atom(&mut self, cell: &RefCell<MValue>) -> String {
+        let ptr = cell as *const RefCell<MValue>;
+
+        if self.atoms.contains(&ptr) {
+            return "#<cycle>".to_string();
+        }
+
+        self.atoms.push(ptr);
+        let printed = format!("(atom {})", self.print(&cell.borrow()));
+        self.atoms.pop();
+
+        printed
+    }
+
+    /// Prints the items of a collection between its delimiters, or `#` when
+    /// the collection is nested too deeply.
+    fn nested<F>(&mut self, start: &str, end: &str, items: F) -> String
+        where F: FnOnce(&mut Self) -> Vec<String>
+    {
+        if self.limits.level.is_some_and(|level| self.depth >= level) {
+            return "#".to_string();
+        }
+
+        self.depth += 1;
+        let items = items(self);
+        self.depth -= 1;
+
+        format!("{}{}{}", start, items.join(" "), end)
+    }
+
+    fn sequence(&mut self, seq: &[MValue], start: &str, end: &str) -> String {
+        let length = self.limits.length;
+
+        self.nested(start, end, |printer| {
+            let mut items: Vec<String> = seq.iter()
+                .take(length.unwrap_or(usize::MAX))
+                .map(|v| printer.print(v))
+                .collect();
+
+            if length.is_some_and(|length| seq.len() > length) {
+                items.push("...".to_string());
+            }
+
+            items
+        })
+    }
+
+    fn entries(&mut self, entries: &[(MValue, MValue)]) -> String {
+        let length = self.limits.length;
+
+        self.nested("{", "}", |printer| {
+            let mut items: Vec<String> = entries.iter()
+                .take(length.unwrap_or(usize::MAX))
+                .flat_map(|(k, v)| vec![printer.print(k), printer.print(v)])
+                .collect();
+
+            if length.is_some_and(|length| entries.len() > length) {
+                items.push("...".to_string());
+            }
+
+            items
+        })
+    }
+
+    /// With a length limit only that much of a lazy sequence is realized,
+    /// so infinite ones can be printed too.
+    fn lazy_seq(&mut self, value: &MValue) -> String {
+        let length = match self.limits.length {
+            Some(length) => length,
+            None => return match value.cast_to_list() {
+                Ok(l) => self.sequence(&l, "(", ")"),
+                Err(e) => format!("#<lazy-seq {}>", e),
+            },
+        };
+
+        let mut seq = vec![];
+        let mut rest = value.clone();
+
+        while seq.len() <= length {
+            match rest.realize() {
+                Ok(Some((first, next))) => {
+                    seq.push(first);
+                    rest = next;
+                },
+                Ok(None) => break,
+                Err(e) => return format!("#<lazy-seq {}>", e),
+            }
+        }
+
+        self.sequence(&seq, "(", ")")
+    }
 }
 
-fn print_sequence(seq: &[MValue], start: &str, end: &str, readably: bool) -> String {
-    let seq: Vec<String> = seq
-        .iter()
-        .map(|v| v.pr_str(readably))
-        .collect();
+fn split_sequence(value: &MValue) -> Result<Option<(MValue, MValue)>> {
+    if value.is_nil() {
+        return Ok(None);
+    }
+
+    let mut l = value.cast_to_list()?;
 
-    format!("{}{}{}", start, seq.join(" "), end)
+    if l.is_empty() {
+        return Ok(None);
+    }
+
+    let rest = l.split_off(1);
+    Ok(Some((l.remove(0), MValue::list(rest))))
+}
+
+pub fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        "##NaN".to_string()
+    } else if f.is_infinite() && f > 0.0 {
+        "##Inf".to_string()
+    } else if f.is_infinite() {
+        "##-Inf".to_string()
+    } else {
+        format!("{:?}", f)
+    }
 }
 
 fn escape_str(s: &str) -> String {
@@ -411,10 +953,56 @@ pub enum Error {
     EvalError(String),
     ArgsError,
     NoSymbolFound(String),
-    IoError(String),
+    IoError(IoFailure),
+    ResourceLimit(String),
+    Interrupted(String),
+    OutOfFuel,
+    PermissionDenied(String),
+    /// Raised by `exit` to end the program with a status. `try*` doesn't
+    /// catch it, so it unwinds to whatever runs the program.
+    Exit(i32),
+}
+
+/// A failed IO operation, and the path it was on when there was one.
+#[derive(Debug)]
+pub struct IoFailure {
+    pub path: Option<String>,
+    pub kind: std::io::ErrorKind,
+    pub message: String,
+}
+
+impl IoFailure {
+    /// What `catch*` gets: a map with the `:path`, the `:kind` of failure as
+    /// a keyword like `:not-found`, and the `:message`.
+    fn to_value(&self) -> MValue {
+        let mut kind = String::new();
+
+        for c in format!("{:?}", self.kind).chars() {
+            if c.is_uppercase() && !kind.is_empty() {
+                kind.push('-');
+            }
+            kind.extend(c.to_lowercase());
+        }
+
+        MValue::hashmap(&mut vec![
+            MValue::keyword("type"), MValue::keyword("io-error"),
+            MValue::keyword("kind"), MValue::keyword(kind),
+            MValue::keyword("path"), self.path.as_ref().map_or_else(MValue::nil, MValue::string),
+            MValue::keyword("message"), MValue::string(&self.message),
+        ])
+    }
 }
 
 impl Error {
+    /// An IO error on `path`.
+    pub fn io<P: AsRef<std::path::Path>>(path: P, error: std::io::Error) -> Error {
+        Error::IoError(IoFailure {
+            path: Some(path.as_ref().display().to_string()),
+            kind: error.kind(),
+            message: error.to_string(),
+        })
+    }
+
     pub fn catch(&self) -> MValue {
         match self {
             Error::Throw(ref v) => v.clone(),
@@ -422,7 +1010,12 @@ impl Error {
             Error::EvalError(_) => MValue::string(self),
             Error::ArgsError => MValue::string(self),
             Error::NoSymbolFound(_) => MValue::string(self),
-            Error::IoError(_) => MValue::string(self),
+            Error::IoError(ref failure) => failure.to_value(),
+            Error::ResourceLimit(_) => MValue::string(self),
+            Error::Interrupted(_) => MValue::string(self),
+            Error::OutOfFuel => MValue::string(self),
+            Error::PermissionDenied(_) => MValue::string(self),
+            Error::Exit(_) => MValue::string(self),
         }
     }
 }
@@ -435,7 +1028,14 @@ impl Display for Error {
             Error::EvalError(s) => write!(f, "Eval error: {}", s),
             Error::ArgsError => write!(f, "Args error"),
             Error::NoSymbolFound(s) => write!(f, "\'{}\' not found", s),
-            Error::IoError(s) => write!(f, "IO Error: {}", s),
+            Error::IoError(IoFailure { path: Some(path), message, .. }) =>
+                write!(f, "IO Error: {}: {}", path, message),
+            Error::IoError(failure) => write!(f, "IO Error: {}", failure.message),
+            Error::ResourceLimit(s) => write!(f, "Resource limit: {}", s),
+            Error::Interrupted(s) => write!(f, "Interrupted: {}", s),
+            Error::OutOfFuel => write!(f, "Out of fuel"),
+            Error::PermissionDenied(s) => write!(f, "Permission denied: {}", s),
+            Error::Exit(status) => write!(f, "Exit with status {}", status),
         }
     }
 }
@@ -446,6 +1046,7 @@ impl PartialEq for MalVal {
       (Nil, Nil) => true,
       (Bool(ref x), Bool(ref y)) => x == y,
       (Int(ref x), Int(ref y)) => x == y,
+      (Float(ref x), Float(ref y)) => x == y,
       (Str(ref x), Str(ref y)) => x == y,
       (Keyword(ref x), Keyword(ref y)) => x == y,
       (Sym(ref x), Sym(ref y)) => x == y,
@@ -454,6 +1055,33 @@ impl PartialEq for MalVal {
       (List(ref x, _), Vector(ref y, _)) |
       (Vector(ref x, _), List(ref y, _)) => x == y,
       (HashMap(ref x, _), HashMap(ref y, _)) => x == y,
+      (Set(ref x, _), Set(ref y, _)) => x == y,
+      (Regex(ref x), Regex(ref y)) => x.as_str() == y.as_str(),
+      (Tagged(ref t, ref x), Tagged(ref u, ref y)) => t == u && x == y,
+      // Atoms are equal only to themselves, which also keeps comparing
+      // structures that refer to themselves through one from looping.
+      (Atom(ref x), Atom(ref y)) => std::ptr::eq(x, y),
+      (Port(ref x), Port(ref y)) => x.same(y),
+      (SortedMap(ref x, _), SortedMap(ref y, _)) | (SortedSet(ref x, _), SortedSet(ref y, _)) =>
+          x.len() == y.len() && x.iter().eq(y.iter()),
+      (SortedMap(ref x, _), HashMap(ref y, _)) | (HashMap(ref y, _), SortedMap(ref x, _))
+      | (SortedSet(ref x, _), Set(ref y, _)) | (Set(ref y, _), SortedSet(ref x, _)) => {
+          x.len() == y.len() && x.iter()
+              .all(|(k, v)| k.hash_key().ok().and_then(|k| y.get(&k)) == Some(v))
+      },
+      (LazySeq(_), List(_, _)) | (LazySeq(_), Vector(_, _)) | (LazySeq(_), LazySeq(_))
+      | (List(_, _), LazySeq(_)) | (Vector(_, _), LazySeq(_)) => {
+          let as_list = |v: &MalVal| match v {
+              List(ref l, _) | Vector(ref l, _) => Ok(l.clone()),
+              LazySeq(ref lazy) => lazy.shared()?.cast_to_list(),
+              _ => Err(Error::EvalError("not a sequence".to_string())),
+          };
+
+          match (as_list(self), as_list(other)) {
+              (Ok(x), Ok(y)) => x == y,
+              _ => false,
+          }
+      },
       _ => false,
     }
   }
@@ -463,21 +1091,25 @@ impl Debug for MalVal {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Int(ref k) => write!(f, "{:?}", k),
+            Float(ref k) => write!(f, "{:?}", k),
             Bool(ref b) => write!(f, "{:?}", b),
             Sym(ref s) => write!(f, "{:?}", s),
             Keyword(ref s) => write!(f, "{:?}", s),
-            Atom(ref v) => write!(f, "(atom {:?})", v),
+            Atom(ref v) => write!(f, "{}", Printer::new(true, PrintLimits::default()).atom(v)),
+            Port(ref p) => write!(f, "#<port {}>", p.name()),
+            Regex(ref r) => write!(f, "#\"{}\"", r.as_str().replace('"', "\\\"")),
             Str(ref s) => write!(f, "{:?}", s),
             Nil => write!(f, "nil"),
-            List(ref l, _) => write!(f, "{}", print_sequence(&l, "(", ")", true)),
-            Vector(ref l, _) => write!(f, "{}", print_sequence(&l, "[", "]", true)),
-            HashMap(ref l, _) => {
-                let l = l.iter()
-                    .flat_map(|(k, v)| vec![MValue::reconstruct(k).unwrap(), v.clone()])
-                    .collect::<Vec<MValue>>();
-                write!(f, "{}", print_sequence(&l, "{", "}", true))
-            },
+            Reduced(ref v) => write!(f, "#<reduced {:?}>", v),
+            Tagged(ref tag, ref v) => write!(f, "#{} {:?}", tag, v),
             Fun(_,_,_) | Lambda(_,_) => write!(f, "#<function>"),
+            LazySeq(ref lazy) => match lazy.shared() {
+                Ok(seq) => write!(f, "{}", seq.pr_str(true)),
+                Err(e) => write!(f, "#<lazy-seq {}>", e),
+            },
+            List(_, _) | Vector(_, _) | HashMap(_, _) | Set(_, _)
+            | SortedMap(_, _) | SortedSet(_, _) =>
+                write!(f, "{}", MValue::new(self.clone(), false).pr_str(true)),
         }
     }
 }
@@ -490,6 +1122,18 @@ impl From<pom::Error> for Error {
 
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Error {
+        Error::IoError(IoFailure { path: None, kind: error.kind(), message: error.to_string() })
+    }
+}
+
+impl From<std::num::ParseIntError> for Error {
+    fn from(error: std::num::ParseIntError) -> Error {
+        Error::ParseError(error.to_string())
+    }
+}
+
+impl From<std::num::ParseFloatError> for Error {
+    fn from(error: std::num::ParseFloatError) -> Error {
         Error::ParseError(error.to_string())
     }
 }
@@ -499,3 +1143,67 @@ impl From<std::num::TryFromIntError> for Error {
         Error::EvalError(error.to_string())
     }
 }
+
+#[test]
+fn test_print_cycles() {
+    let a = MValue::atom(MValue::nil());
+    a.atom_reset(MValue::hashmap(&mut vec![MValue::keyword("self"), a.clone()])).unwrap();
+
+    assert_eq!(a.pr_str(true), "(atom {:self #<cycle>})");
+    assert_eq!(format!("{:?}", a), "MValue((atom {:self #<cycle>}), false)");
+    assert_eq!(a, a.clone());
+    assert_ne!(a, MValue::atom(MValue::nil()));
+
+    let nested = MValue::vector(vec![MValue::integer(1), MValue::vector(vec![MValue::integer(2)]), a]);
+    let limits = PrintLimits { level: Some(1), length: Some(2) };
+    assert_eq!(nested.pr_str_limited(true, limits), "[1 # ...]");
+}
+
+#[test]
+fn test_regex() {
+    use crate::core::{re_find, re_groups, re_matches, re_seq};
+    use crate::reader::read_form;
+
+    let regex = MValue::regex(r#"a"b\d"#).unwrap();
+    let printed = regex.pr_str(true);
+    assert_eq!(printed, r#"#"a\"b\d""#);
+    assert_eq!(read_form().parse(printed.as_bytes()).unwrap(), regex);
+
+    let call = |f: FnExpr, pattern: &str, s: &str| f(vec![MValue::regex(pattern).unwrap(), MValue::string(s)], None).unwrap();
+    let strings = |v: &[&str]| v.iter().map(MValue::string).collect::<Vec<_>>();
+
+    assert_eq!(call(re_find, r"\d+", "ab 12 34"), MValue::string("12"));
+    assert_eq!(call(re_find, r"(\w)(\d)?", "x"), MValue::vector(vec![MValue::string("x"), MValue::string("x"), MValue::nil()]));
+    assert_eq!(call(re_find, r"\d", "abc"), MValue::nil());
+    assert_eq!(call(re_matches, r"\d+", "12"), MValue::string("12"));
+    assert_eq!(call(re_matches, r"\d+", "12a"), MValue::nil());
+    assert_eq!(call(re_seq, r"\d", "1a2b3"), MValue::list(strings(&["1", "2", "3"])));
+    assert_eq!(call(re_seq, r"\d", "abc"), MValue::nil());
+    assert_eq!(call(re_groups, r"(a)(b)?", "ac"), MValue::vector(vec![MValue::string("a"), MValue::string("a"), MValue::nil()]));
+
+    let named = call(re_find, r"(?P<year>\d{4})-(?P<month>\d\d)", "on 2024-05");
+    assert_eq!(named, MValue::hashmap(&mut vec![
+        MValue::integer(0), MValue::string("2024-05"),
+        MValue::keyword("year"), MValue::string("2024"),
+        MValue::keyword("month"), MValue::string("05"),
+    ]));
+}
+
+#[test]
+fn test_float_keys() {
+    let map = MValue::hashmap(&mut vec![
+        MValue::float(f64::NAN), MValue::integer(1),
+        MValue::float(f64::INFINITY), MValue::integer(2),
+        MValue::float(f64::NEG_INFINITY), MValue::integer(3),
+        MValue::float(0.5), MValue::integer(4),
+    ]);
+
+    assert_eq!(map.pr_str(true), "{##NaN 1 ##Inf 2 ##-Inf 3 0.5 4}");
+
+    let keys = map.cast_to_hashmap().unwrap().keys()
+        .map(MValue::reconstruct)
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert!(matches!(*keys[0].0, Float(x) if x.is_nan()));
+    assert_eq!(keys[1..], [MValue::float(f64::INFINITY), MValue::float(f64::NEG_INFINITY), MValue::float(0.5)]);
+}